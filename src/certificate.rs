@@ -1,5 +1,5 @@
 use error::Error;
-use identity::{Address, Identity, Secret, Signature};
+use identity::{Address, Identity, Secret, Signature, Successions};
 use prost::Message;
 use proto;
 use std::collections::HashMap;
@@ -96,7 +96,7 @@ impl CertificateRequest {
         self
     }
 
-    pub fn sign(self, signer: &Secret, serial: u64) -> SignedCertificate {
+    pub fn sign(self, signer: &Secret, serial: u64) -> Result<SignedCertificate, Error> {
         let crt = Certificate {
             last_valid_epoch: self.last_valid_epoch,
             identity: self.identity,
@@ -111,11 +111,11 @@ impl CertificateRequest {
         crt.encode(&mut b).unwrap();
         c.extend(b);
 
-        let sig = signer.sign(b"sign carrier certificate", &c);
+        let sig = signer.sign(b"sign carrier certificate", &c)?;
         let sig = sig.as_bytes();
         assert_eq!(sig.len(), 64);
         c.extend_from_slice(sig);
-        c
+        Ok(c)
     }
 }
 
@@ -146,6 +146,7 @@ pub struct Authenticator {
     shadow: Address,
     grants: HashMap<Identity, HashSet<String>>,
     door:   Identity,
+    successions: Successions,
 }
 
 impl Authenticator {
@@ -154,9 +155,49 @@ impl Authenticator {
             shadow,
             door,
             grants: HashMap::new(),
+            successions: Successions::default(),
         }
     }
 
+    /// grants and certs for a retired identity apply to its successor instead
+    pub fn successions(&mut self, successions: Successions) {
+        self.successions = successions;
+    }
+
+    /// everything granted to identity and the identities it succeeded.
+    /// nothing for an identity that was itself succeeded
+    fn grant(&self, identity: &Identity) -> Option<HashSet<&String>> {
+        if self.successions.is_retired(identity) {
+            return None;
+        }
+        let mut r = HashSet::new();
+        let mut found = false;
+        for identity in self.successions.lineage(identity) {
+            if let Some(g) = self.grants.get(&identity) {
+                found = true;
+                r.extend(g.iter());
+            }
+        }
+        if found {
+            Some(r)
+        } else {
+            None
+        }
+    }
+
+    /// certs naming the door before it was rotated still name the door
+    fn is_door(&self, target: &[u8]) -> bool {
+        match Identity::from_bytes(target) {
+            Ok(target) => self.successions.current(&target) == self.door,
+            Err(_) => false,
+        }
+    }
+
+    /// a cert for identity is good for cur when cur is identity or succeeded it
+    fn certifies(&self, identity: &Identity, cur: &Identity) -> bool {
+        identity == cur || self.successions.lineage(cur).contains(identity)
+    }
+
     pub fn allow(&mut self, grantee: Identity, resources: Vec<String>) {
         let g = self.grants.entry(grantee).or_insert(HashSet::new());
         for resource in resources {
@@ -166,12 +207,15 @@ impl Authenticator {
 
     /// this is an optimization to reject early, not a full authorization check!
     pub fn reject_early(&self, requester: &Identity, chain: &CertificateChain) -> Result<(), Error> {
+        if self.successions.is_retired(requester) {
+            return Err(Error::AccessDenied);
+        }
         let mut chain               = chain.into_iter();
         let mut allow_delegation    = true;
         let mut cur                 = requester.clone();
 
         loop {
-            if let Some(_grant) = self.grants.get(&cur) {
+            if let Some(_grant) = self.grant(&cur) {
                 return Ok(());
             }
 
@@ -186,7 +230,7 @@ impl Authenticator {
             let certified_identity = Identity::from_bytes(&cert.identity)?;
             let authority = Identity::from_bytes(&cert.authority)?;
 
-            if certified_identity != cur {
+            if !self.certifies(&certified_identity, &cur) {
                 return Err(Error::from(Error::BrokenChain));
             }
 
@@ -199,7 +243,7 @@ impl Authenticator {
                         allow_delegation = true;
                     }
                     Some(proto::claim::Claim::One(ref a)) => {
-                        if a.target.as_slice() == self.door.as_bytes() || a.target == b"*"
+                        if self.is_door(&a.target) || a.target == b"*"
                         {
                             nextaccess = true;
                         }
@@ -219,22 +263,26 @@ impl Authenticator {
             }
 
             // first cert doesnt need delegation
-            if certified_identity == *requester {
+            if self.certifies(&certified_identity, requester) {
                 allow_delegation = true;
             }
 
-            cur = authority;
+            // certs signed before the authority was rotated speak for its successor
+            cur = self.successions.current(&authority);
         }
     }
 
     pub fn check(&self, requester: &Identity, resource: &String, chain: &CertificateChain) -> Result<(), Error> {
+        if self.successions.is_retired(requester) {
+            return Err(Error::AccessDenied);
+        }
         let mut chain = chain.into_iter();
         let mut allow_delegation = true;
         let mut cur = requester.clone();
 
         loop {
-            if let Some(grant) = self.grants.get(&cur) {
-                if grant.contains(resource) || grant.contains("*") {
+            if let Some(grant) = self.grant(&cur) {
+                if grant.contains(resource) || grant.contains(&String::from("*")) {
                     return Ok(());
                 }
             }
@@ -250,7 +298,7 @@ impl Authenticator {
             let certified_identity = Identity::from_bytes(&cert.identity)?;
             let authority = Identity::from_bytes(&cert.authority)?;
 
-            if certified_identity != cur {
+            if !self.certifies(&certified_identity, &cur) {
                 return Err(Error::from(Error::BrokenChain));
             }
 
@@ -263,7 +311,7 @@ impl Authenticator {
                         allow_delegation = true;
                     }
                     Some(proto::claim::Claim::One(ref a)) => {
-                        if (a.target.as_slice() == self.door.as_bytes() || a.target == b"*") &&
+                        if (self.is_door(&a.target) || a.target == b"*") &&
                             (a.resources.contains(resource) || a.resources.contains(&String::from("*")))
                         {
                             nextaccess = true;
@@ -285,11 +333,12 @@ impl Authenticator {
             }
 
             // first cert doesnt need delegation
-            if certified_identity == *requester {
+            if self.certifies(&certified_identity, requester) {
                 allow_delegation = true;
             }
 
-            cur = authority;
+            // certs signed before the authority was rotated speak for its successor
+            cur = self.successions.current(&authority);
         }
     }
 }
@@ -301,7 +350,7 @@ pub fn basic() {
 
     let cert = CertificateRequest::new(32, identity1.identity());
 
-    let signed_good = cert.sign(&identity2, 1);
+    let signed_good = cert.sign(&identity2, 1).unwrap();
     let mut signed_bad = signed_good.clone();
     let len = signed_bad.len();
     if signed_bad[len - 1] == 0x00 {
@@ -327,7 +376,7 @@ pub fn delegated_access() {
 
     let cert = CertificateRequest::new(32, trustee.identity())
         .one(door.identity(), &["open", "close"])
-        .sign(&allowed, 3);
+        .sign(&allowed, 3).unwrap();
 
     auth.check(&allowed.identity(), &"open".to_string(), &vec![])
         .unwrap();
@@ -376,16 +425,16 @@ pub fn chain() {
         .one(door.identity(), &["open"])
         .one(door.identity(), &["close", "peek"])
         .allow_delegation()
-        .sign(&allowed, 3);
+        .sign(&allowed, 3).unwrap();
 
     let cert2 = CertificateRequest::new(32, trustee2.identity())
         .one(door.identity(), &["open"])
-        .sign(&trustee1, 3);
+        .sign(&trustee1, 3).unwrap();
 
     let cert3 = CertificateRequest::new(32, trustee3.identity())
         .one(door.identity(), &["open"])
         .one(door.identity(), &["close"])
-        .sign(&trustee2, 3);
+        .sign(&trustee2, 3).unwrap();
 
     // T1 can open and peek
     auth.check(&trustee1.identity(), &"open".to_string(), &vec![cert1.clone()])
@@ -458,7 +507,7 @@ pub fn resource_wildcard() {
 
     let cert = CertificateRequest::new(32, trustee.identity())
         .one(door.identity(), &["*"])
-        .sign(&allowed, 3);
+        .sign(&allowed, 3).unwrap();
 
     auth.check(&allowed.identity(), &"open".to_string(), &vec![])
         .unwrap();
@@ -500,7 +549,7 @@ pub fn by_shadow() {
 
     let cert = CertificateRequest::new(32, trustee.identity())
         .all(shadow.clone(), &["open"])
-        .sign(&allowed, 3);
+        .sign(&allowed, 3).unwrap();
 
     auth.check(&allowed.identity(), &"open".to_string(), &vec![])
         .unwrap();
@@ -531,7 +580,7 @@ pub fn by_shadow() {
 
     let cert = CertificateRequest::new(32, trustee.identity())
         .all(unrelated_shadow.clone(), &["open"])
-        .sign(&allowed, 3);
+        .sign(&allowed, 3).unwrap();
 
     assert!(
         auth.check(&trustee.identity(), &"open".to_string(), &vec![cert.clone()])
@@ -554,7 +603,7 @@ pub fn unrelated_wildcard() {
 
     let cert = CertificateRequest::new(32, trustee.identity())
         .one(Secret::gen().identity(), &["*"])
-        .sign(&allowed, 3);
+        .sign(&allowed, 3).unwrap();
 
     auth.check(&allowed.identity(), &"open".to_string(), &vec![])
         .unwrap();
@@ -589,3 +638,50 @@ pub fn unrelated_wildcard() {
             .is_err()
     );
 }
+
+#[test]
+pub fn succession() {
+    use identity::Succession;
+
+    let shadow = Secret::gen().address();
+    let door = Secret::gen();
+    let old = Secret::gen();
+    let new = Secret::gen();
+    let trustee = Secret::gen();
+
+    let mut auth = Authenticator::new(door.identity(), shadow);
+    auth.allow(old.identity(), vec!["open".to_string()]);
+
+    // issued by the old identity before it was rotated
+    let cert = CertificateRequest::new(32, trustee.identity())
+        .one(door.identity(), &["open"])
+        .sign(&old, 3).unwrap();
+
+    auth.check(&old.identity(), &"open".to_string(), &vec![]).unwrap();
+    assert!(auth.check(&new.identity(), &"open".to_string(), &vec![]).is_err());
+
+    let mut successions = Successions::default();
+    successions.add(Succession::sign(&old, new.identity(), 1).unwrap()).unwrap();
+    auth.successions(successions);
+
+    auth.check(&new.identity(), &"open".to_string(), &vec![]).unwrap();
+    auth.reject_early(&new.identity(), &vec![]).unwrap();
+    assert!(auth.check(&old.identity(), &"open".to_string(), &vec![]).is_err());
+    assert!(auth.reject_early(&old.identity(), &vec![]).is_err());
+    auth.reject_early(&trustee.identity(), &vec![cert.clone()]).unwrap();
+    auth.check(&trustee.identity(), &"open".to_string(), &vec![cert.clone()]).unwrap();
+    assert!(auth.check(&trustee.identity(), &"close".to_string(), &vec![cert]).is_err());
+    assert!(auth.check(&new.identity(), &"close".to_string(), &vec![]).is_err());
+
+    // the door rotated too, certs naming the old door still count
+    let door2 = Secret::gen();
+    let mut successions = Successions::default();
+    successions.add(Succession::sign(&door, door2.identity(), 2).unwrap()).unwrap();
+    let mut auth = Authenticator::new(door2.identity(), Secret::gen().address());
+    auth.allow(old.identity(), vec!["open".to_string()]);
+    auth.successions(successions);
+    let cert = CertificateRequest::new(32, trustee.identity())
+        .one(door.identity(), &["open"])
+        .sign(&old, 4).unwrap();
+    auth.check(&trustee.identity(), &"open".to_string(), &vec![cert]).unwrap();
+}
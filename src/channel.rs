@@ -1,6 +1,8 @@
+use congestion;
 use error::Error;
 use noise;
 use packet::{EncryptedPacket, Frame};
+use pmtu;
 use rand;
 use recovery;
 use replay;
@@ -24,17 +26,98 @@ extern "C" {
 
 use stream;
 
-//TODO: use mtu?
 //careful, this must be bigger than the maximum mtu used in the entire network
 //not the same number as used in shell/sft/etc..
-pub const MAX_PACKET_SIZE: usize = 1500;
+//the actual packet size per channel is discovered by pmtu, between the configured floor and ceiling
+pub const MAX_PACKET_SIZE: usize = 9000;
+
+/// default ip mtu ceiling for path mtu discovery, a jumbo frame.
+/// a plain ethernet mtu leaves no room above the floor, so anything less would never probe
+pub const DEFAULT_MTU_CEILING: usize = 9000;
 const DEFAULT_IDLE_TIMER: u64 = 30000;
 
+/// stream messages and datagrams must be smaller than this, to fit a minimum size packet
+pub const MAX_MESSAGE_SIZE: usize = 1200;
+
+/// received datagrams that the application hasn't picked up yet.
+/// older ones are dropped first, since stale data is worse than lost data
+const MAX_DATAGRAM_QUEUE: usize = 100;
+
+/// pacing sends a full congestion window over this fraction of one smoothed rtt
+const PACING_GAIN: f64 = 1.25;
+
+/// path responses kept around until the endpoint checks them.
+/// during discovery every candidate path has a challenge outstanding
+const MAX_PATH_RESPONSES: usize = 16;
+
+/// replace the transport keys after this many packets in either direction
+pub const DEFAULT_REKEY_PACKETS: u64 = 1 << 24;
+
+/// or after this many seconds, whichever comes first
+pub const DEFAULT_REKEY_INTERVAL: u64 = 3600;
+
 pub struct Config {
     pub timeout: Option<u16>,
     pub sleeping: bool,
 }
 
+/// local settings applied to every channel of an endpoint
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub congestion: congestion::Algorithm,
+    pub pacing: bool,
+    /// packet size
+    pub mtu_floor: usize,
+    /// ip mtu
+    pub mtu_ceiling: usize,
+    pub rekey_packets: u64,
+    /// in seconds
+    pub rekey_interval: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            congestion: congestion::Algorithm::default(),
+            pacing: false,
+            mtu_floor: pmtu::MIN_PACKET_SIZE,
+            mtu_ceiling: DEFAULT_MTU_CEILING,
+            rekey_packets: DEFAULT_REKEY_PACKETS,
+            rekey_interval: DEFAULT_REKEY_INTERVAL,
+        }
+    }
+}
+
+/// bytes of stream payload, not counting headers or framing
+#[derive(Clone, Debug, Default)]
+pub struct StreamStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// snapshot of a channels transport state. times are in milliseconds
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub smoothed_rtt: u64,
+    pub rttvar: f64,
+    pub min_rtt: u64,
+    pub congestion_window: u64,
+    pub bytes_in_flight: usize,
+    /// waiting to be sent, acks not counted
+    pub bytes_queued: usize,
+    pub mtu: usize,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub packets_lost: u64,
+    pub packets_retransmitted: u64,
+    pub replay_drops: u64,
+    /// how many times the keys were replaced
+    pub key_epoch: u32,
+    /// wire version negotiated with the peer
+    pub version: u8,
+    pub streams: HashMap<u32, StreamStats>,
+}
+
 pub struct Channel {
     pub debug_id: String,
     noise: noise::Transport,
@@ -43,11 +126,18 @@ pub struct Channel {
     replay: replay::AntiReplay,
     recovery: recovery::QuicRecovery,
     streams: HashMap<u32, stream::OrderedStream>,
+    datagrams: VecDeque<Vec<u8>>,
+    path_responses: VecDeque<u64>,
     gone: bool,
 
     //outgoing
     counters: HashMap<u32, u64>,
     outqueue: VecDeque<Frame>,
+    pacing: bool,
+    next_send: f64,
+    /// bytes of tail loss and rto probes that may go past the congestion window
+    probe_bytes: usize,
+    pmtu: pmtu::Pmtu,
 
     sleeping: bool,
     idle_time: u64,
@@ -55,6 +145,18 @@ pub struct Channel {
     last_seen: u64,
     idle_count: u16,
 
+    //rekeying, only done by the initiator
+    rekey_packets: u64,
+    rekey_interval: u64,
+    rekeyed_at: u64,
+    packets_at_rekey: u64,
+
+    //statistics
+    packets_sent: u64,
+    packets_received: u64,
+    replay_drops: u64,
+    stream_stats: HashMap<u32, StreamStats>,
+
     #[cfg(not(target_arch = "wasm32"))]
     basetime: Instant,
 }
@@ -64,23 +166,38 @@ pub enum ChannelProgress {
     SendPacket(Vec<u8>),
     ReceiveHeader(u32, Vec<u8>),
     ReceiveStream(u32, Vec<u8>),
+    ReceiveDatagram(Vec<u8>),
     Close(u32),
     Disconnect,
 }
 
 impl Channel {
     pub fn new<S: Into<String>>(noise: noise::Transport, debug_id: S) -> Self {
+        Self::with_settings(noise, debug_id, Settings::default())
+    }
+
+    pub fn with_settings<S: Into<String>>(
+        noise: noise::Transport,
+        debug_id: S,
+        settings: Settings,
+    ) -> Self {
         Channel {
             debug_id: debug_id.into(),
             noise: noise,
 
             replay: replay::AntiReplay::new(),
-            recovery: recovery::QuicRecovery::new(),
+            recovery: recovery::QuicRecovery::with_congestion(settings.congestion),
             streams: HashMap::new(),
+            datagrams: VecDeque::new(),
+            path_responses: VecDeque::new(),
             gone: false,
 
             counters: HashMap::new(),
             outqueue: VecDeque::new(),
+            pacing: settings.pacing,
+            next_send: 0.0,
+            probe_bytes: 0,
+            pmtu: pmtu::Pmtu::new(settings.mtu_floor, settings.mtu_ceiling),
 
             sleeping: false,
             idle_time: DEFAULT_IDLE_TIMER,
@@ -88,6 +205,16 @@ impl Channel {
             last_seen: 0,
             idle_count: 0,
 
+            rekey_packets: settings.rekey_packets,
+            rekey_interval: settings.rekey_interval * 1000,
+            rekeyed_at: 0,
+            packets_at_rekey: 0,
+
+            packets_sent: 0,
+            packets_received: 0,
+            replay_drops: 0,
+            stream_stats: HashMap::new(),
+
             #[cfg(not(all(target_arch = "wasm32")))]
             basetime: Instant::now(),
         }
@@ -97,6 +224,11 @@ impl Channel {
         self.recovery.bytes_in_flight()
     }
 
+    /// bytes of frames queued but not sent yet, not counting acks
+    pub fn bytes_queued(&self) -> usize {
+        self.outqueue.iter().filter(|frame| !frame.is_ack()).map(|frame| frame.len()).sum()
+    }
+
     pub fn window(&self) -> usize {
         self.recovery.window()
     }
@@ -105,6 +237,36 @@ impl Channel {
         self.noise.is_initiator()
     }
 
+    /// largest packet this channel currently sends
+    pub fn mtu(&self) -> usize {
+        self.pmtu.current()
+    }
+
+    /// the path this channel sends on is ipv6 or not. limits the packet size
+    pub fn set_ipv6(&mut self, ipv6: bool) {
+        self.pmtu.set_ipv6(ipv6);
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            smoothed_rtt: self.recovery.smoothed_rtt,
+            rttvar: self.recovery.rttvar,
+            min_rtt: self.recovery.min_rtt(),
+            congestion_window: self.recovery.congestion_window(),
+            bytes_in_flight: self.recovery.bytes_in_flight(),
+            bytes_queued: self.bytes_queued(),
+            mtu: self.pmtu.current(),
+            packets_sent: self.packets_sent,
+            packets_received: self.packets_received,
+            packets_lost: self.recovery.packets_lost(),
+            packets_retransmitted: self.recovery.packets_retransmitted(),
+            replay_drops: self.replay_drops,
+            key_epoch: self.noise.epoch(),
+            version: self.noise.version(),
+            streams: self.stream_stats.clone(),
+        }
+    }
+
     fn now(&self) -> u64 {
         #[cfg(not(target_arch = "wasm32"))]
         {
@@ -133,15 +295,17 @@ impl Channel {
         let counter = pkt.counter;
 
         if !self.replay.within_window(counter) {
+            self.replay_drops += 1;
             return Err(Error::AntiReplay.into());
         }
 
         let pkt = self.noise.recv(pkt)?;
-        let frames = Frame::decode(pkt.as_slice())?;
+        let frames = Frame::decode(self.noise.version(), pkt.as_slice())?;
 
         // packet authenticated from here
 
         self.replay.update_window(counter);
+        self.packets_received += 1;
 
         let mut ackonly = true;
         for frame in frames {
@@ -190,6 +354,7 @@ impl Channel {
                     self.gone = true;
                 }
                 Frame::Ack { delay, acked } => {
+                    self.pmtu.on_ack(&acked);
                     let loss = self.recovery.on_ack_received(delay, acked.clone(), now);
 
                     trace!(
@@ -218,6 +383,30 @@ impl Channel {
                         .or_insert(stream::OrderedStream::new());
                     ordered.push(Frame::Close { stream, order })?;
                 }
+                Frame::Datagram { payload } => {
+                    trace!("[{}] received datagram with {} bytes", self.debug_id, payload.len());
+                    if self.datagrams.len() >= MAX_DATAGRAM_QUEUE {
+                        debug!("[{}] datagram queue full, dropping oldest", self.debug_id);
+                        self.datagrams.pop_front();
+                    }
+                    self.datagrams.push_back(payload);
+                }
+                Frame::PathChallenge { data } => {
+                    trace!("[{}] received path challenge", self.debug_id);
+                    self.outqueue.push_back(Frame::PathResponse { data });
+                }
+                Frame::PathResponse { data } => {
+                    trace!("[{}] received path response", self.debug_id);
+                    if self.path_responses.len() >= MAX_PATH_RESPONSES {
+                        self.path_responses.pop_front();
+                    }
+                    self.path_responses.push_back(data);
+                }
+                Frame::Rekey { epoch, seed } => {
+                    if self.noise.rekey_accept(epoch, &seed)? {
+                        debug!("[{}] peer offered new keys. now in epoch {}", self.debug_id, epoch);
+                    }
+                }
                 Frame::Config { timeout, sleeping } => {
                     if let Some(seconds) = timeout {
                         debug!("peer set timeout to {} seconds", seconds);
@@ -258,7 +447,7 @@ impl Channel {
                 );
 
                 for frame in lost {
-                    if !frame.is_ack() && !frame.is_ping() {
+                    if frame.is_retransmittable() {
                         self.outqueue.push_back(frame);
                     }
                 }
@@ -273,9 +462,7 @@ impl Channel {
                         .collect::<Vec<String>>()
                         .join(",")
                 );
-                for frame in re {
-                    self.outqueue.push_back(frame);
-                }
+                self.retransmit(re, false);
             }
             recovery::LossDetection::RetransmissionTimeout(re) => {
                 trace!(
@@ -285,9 +472,10 @@ impl Channel {
                     self.recovery.bytes_in_flight(),
                     self.now(),
                 );
-                for frame in re {
-                    self.outqueue.push_front(frame);
-                }
+                // full size packets might be silently dropped on this path
+                let now = self.now();
+                self.pmtu.on_black_hole(now);
+                self.retransmit(re, true);
             }
             recovery::LossDetection::Unrecoverable => {
                 warn!("[{}] connection is unrecoverable", self.debug_id);
@@ -296,6 +484,28 @@ impl Channel {
         }
     }
 
+    /// queue frames from a probe for retransmission, past the congestion window.
+    /// if nothing in the probe is retransmittable we send a ping instead
+    fn retransmit(&mut self, re: Vec<Frame>, front: bool) {
+        let probing = !re.is_empty();
+        let re: Vec<Frame> = re
+            .into_iter()
+            .filter(|frame| frame.is_retransmittable())
+            .collect();
+        if probing && re.is_empty() {
+            self.outqueue.push_back(Frame::Ping);
+            return;
+        }
+        for frame in re {
+            self.probe_bytes += frame.len();
+            if front {
+                self.outqueue.push_front(frame);
+            } else {
+                self.outqueue.push_back(frame);
+            }
+        }
+    }
+
     /// progress the channel and return something that happened
     /// this needs to be polled until it returns Later or Disconnectd
     pub fn progress(&mut self) -> Result<ChannelProgress, Error> {
@@ -356,6 +566,13 @@ impl Channel {
                 self.last_seen + self.idle_time
             };
 
+            if let Some(deadline) = self.pmtu.deadline(self.recovery.smoothed_rtt) {
+                if deadline < self.deadline {
+                    trace!("deadline from pmtu");
+                    self.deadline = deadline;
+                }
+            }
+
             if self.deadline <= now {
                 error!(
                     "[{}] upcoming deadline {} already expired at {}",
@@ -365,31 +582,60 @@ impl Channel {
             }
         }
 
-        // send out packets
-        if !self.outqueue.is_empty() {
+        let paced = self.pacing && self.next_send > now as f64;
+
+        self.maybe_rekey(now);
+
+        // probe for a bigger path mtu
+        if !paced {
+            if let Some(size) = self.pmtu.poll(now, self.recovery.smoothed_rtt) {
+                let mut pkt = Vec::new();
+                Frame::Ping.encode(self.noise.version(), &mut pkt)?;
+                pkt.resize(pmtu::probe_payload_len(size), 0x00);
+
+                let pkt = self.noise.send(&pkt)?;
+                trace!("[{}] sending pmtu probe {} with {} bytes", self.debug_id, pkt.counter, size);
+                self.pmtu.on_probe_sent(size, pkt.counter, now);
+                self.packets_sent += 1;
+
+                // probes are not tracked by recovery. losing one is not congestion
+                let pkt = pkt.encode();
+                assert_eq!(pkt.len(), size);
+                return Ok(ChannelProgress::SendPacket(pkt));
+            }
+        }
+
+        // send out packets. acks and pings are never held back by the pacer or the congestion window
+        let mut window = if paced {
+            0
+        } else {
+            self.recovery.window() + self.probe_bytes
+        };
+        if self.next_frame(window).is_some() {
+            let mtu = self.pmtu.current();
             let mut frames = Vec::new();
             let mut pkt = Vec::new();
             loop {
-                let more = match self.outqueue.front() {
+                let (i, more) = match self.next_frame(window) {
                     None => break,
-                    Some(v) => v.len(),
+                    Some(i) => (i, self.outqueue[i].len()),
                 };
 
-                let overhead = 36;
-                let morelen = pkt.len() + overhead + more;
-                let morelen = morelen + (256 - (morelen % 256));
-
-                if morelen >= MAX_PACKET_SIZE {
+                if pmtu::packet_size(pkt.len() + more) > mtu {
                     break;
                 }
-                let mut frame = self.outqueue.pop_front().unwrap();
+                let mut frame = self.outqueue.remove(i).unwrap();
+                if !frame.is_ack() {
+                    window -= more.min(window);
+                    self.probe_bytes -= more.min(self.probe_bytes);
+                }
                 if let Frame::Ack { acked, delay } = frame {
                     frame = Frame::Ack {
                         acked,
                         delay: now - delay,
                     };
                 }
-                frame.encode(&mut pkt)?;
+                frame.encode(self.noise.version(), &mut pkt)?;
                 frames.push(frame);
             }
             assert_ne!(
@@ -415,9 +661,18 @@ impl Channel {
             );
 
             self.recovery.on_packet_sent(pkt.counter, frames, now);
+            self.packets_sent += 1;
 
             let pkt = pkt.encode();
-            assert!(pkt.len() < MAX_PACKET_SIZE);
+            assert!(pkt.len() <= mtu);
+            if self.pacing && !frames.iter().all(|frame| frame.is_ack()) {
+                let interval = pacing_interval(
+                    pkt.len(),
+                    self.recovery.smoothed_rtt,
+                    self.recovery.congestion_window(),
+                );
+                self.next_send = self.next_send.max(now as f64) + interval;
+            }
             return Ok(ChannelProgress::SendPacket(pkt));
         }
 
@@ -436,11 +691,14 @@ impl Channel {
                     Frame::Stream {
                         stream, payload, ..
                     } => {
+                        self.stream_stats.entry(stream).or_default().bytes_received +=
+                            payload.len() as u64;
                         return Ok(ChannelProgress::ReceiveStream(stream, payload));
                     }
                     Frame::Close { stream, .. } => {
                         trace!("LD1: stream {} closed", stream);
                         self.streams.remove(&stream);
+                        self.stream_stats.remove(&stream);
                         return Ok(ChannelProgress::Close(stream));
                     }
                     _ => unreachable!(),
@@ -448,13 +706,55 @@ impl Channel {
             }
         }
 
+        if let Some(payload) = self.datagrams.pop_front() {
+            return Ok(ChannelProgress::ReceiveDatagram(payload));
+        }
+
         if self.gone {
             return Ok(ChannelProgress::Disconnect);
         }
 
-        Ok(ChannelProgress::Later(Duration::from_millis(
-            self.deadline - now,
-        )))
+        let mut later = self.deadline - now;
+        if paced {
+            let next_send = (self.next_send.ceil() as u64).max(now + 1);
+            later = later.min(next_send - now);
+        }
+
+        Ok(ChannelProgress::Later(Duration::from_millis(later)))
+    }
+
+    /// index of the next frame that may go into a packet.
+    /// other frames go out in order, as long as they fit into window
+    fn next_frame(&self, mut window: usize) -> Option<usize> {
+        for (i, frame) in self.outqueue.iter().enumerate() {
+            if frame.is_ack() || frame.is_ping() || frame.len() <= window {
+                return Some(i);
+            }
+            // frames behind it wait too
+            window = 0;
+        }
+        None
+    }
+
+    /// offer new keys to the peer when the current ones were used long enough.
+    /// the offer is an ordinary frame, so it's retransmitted when lost
+    fn maybe_rekey(&mut self, now: u64) {
+        if !self.noise.is_initiator() || self.noise.rekey_pending() || self.noise.version() < 0x09 {
+            return;
+        }
+        let packets = self.packets_sent + self.packets_received - self.packets_at_rekey;
+        if packets < self.rekey_packets && now < self.rekeyed_at + self.rekey_interval {
+            return;
+        }
+        match self.noise.rekey_offer() {
+            Ok((epoch, seed)) => {
+                debug!("[{}] offering keys for epoch {} after {} packets", self.debug_id, epoch, packets);
+                self.outqueue.push_back(Frame::Rekey { epoch, seed });
+            }
+            Err(e) => warn!("[{}] cannot rekey: {}", self.debug_id, e),
+        }
+        self.rekeyed_at = now;
+        self.packets_at_rekey = self.packets_sent + self.packets_received;
     }
 
     /// queue a message
@@ -464,7 +764,8 @@ impl Channel {
         let order = *order;
 
         let msg = msg.into();
-        assert!(msg.len() < 1200, "message too big {}", msg.len());
+        assert!(msg.len() < MAX_MESSAGE_SIZE, "message too big {}", msg.len());
+        self.stream_stats.entry(stream).or_default().bytes_sent += msg.len() as u64;
         self.outqueue.push_back(Frame::Stream {
             stream: stream,
             order: order,
@@ -472,6 +773,18 @@ impl Channel {
         });
     }
 
+    /// queue an unreliable datagram. it is encrypted like everything else,
+    /// but never retransmitted when lost
+    pub fn datagram<M: Into<Vec<u8>>>(&mut self, msg: M) {
+        let msg = msg.into();
+        assert!(msg.len() < MAX_MESSAGE_SIZE, "message too big {}", msg.len());
+        if self.noise.version() < 0x09 {
+            warn!("[{}] peer does not support datagrams, dropping one", self.debug_id);
+            return;
+        }
+        self.outqueue.push_back(Frame::Datagram { payload: msg });
+    }
+
     /// open a new stream, given a header
     pub fn open<M: Into<Vec<u8>>>(&mut self, payload: M, are_we_initiator: bool) -> u32 {
         let payload = payload.into();
@@ -542,16 +855,45 @@ impl Channel {
     pub fn remove(&mut self, stream: u32) {
         self.streams.remove(&stream);
         self.counters.remove(&stream);
+        self.stream_stats.remove(&stream);
     }
 
     /// create a disconnect packet
     pub fn disconnect(&mut self) -> Result<Vec<u8>, Error> {
         let mut pkt = Vec::new();
-        Frame::Disconnect.encode(&mut pkt)?;
+        Frame::Disconnect.encode(self.noise.version(), &mut pkt)?;
         let pkt = self.noise.send(&pkt)?;
+        self.packets_sent += 1;
         Ok(pkt.encode())
     }
 
+    /// whether the peer can answer path challenges
+    pub fn validates_paths(&self) -> bool {
+        self.noise.version() >= 0x09
+    }
+
+    /// create a packet challenging the peer to echo data.
+    /// it is sent directly to the address under validation rather than queued,
+    /// and not tracked by recovery. the endpoint retries on its own timer
+    pub fn path_challenge(&mut self, data: u64) -> Result<Vec<u8>, Error> {
+        let mut pkt = Vec::new();
+        Frame::PathChallenge { data }.encode(self.noise.version(), &mut pkt)?;
+        let pkt = self.noise.send(&pkt)?;
+        self.packets_sent += 1;
+        Ok(pkt.encode())
+    }
+
+    /// true if the peer answered the challenge with data
+    pub fn path_validated(&mut self, data: u64) -> bool {
+        match self.path_responses.iter().position(|v| *v == data) {
+            Some(i) => {
+                self.path_responses.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
     /// send probe packets
     pub fn probe(&mut self) {
         self.outqueue.push_back(Frame::Ping);
@@ -570,3 +912,270 @@ impl Channel {
         self.outqueue.push_back(fr);
     }
 }
+
+/// time in ms that sending a packet of this size occupies,
+/// so that a full congestion window is spread over one rtt
+fn pacing_interval(bytes: usize, smoothed_rtt: u64, congestion_window: u64) -> f64 {
+    if smoothed_rtt == 0 || congestion_window == 0 {
+        // no rtt sample yet, nothing to pace against
+        return 0.0;
+    }
+    bytes as f64 * smoothed_rtt as f64 / (congestion_window as f64 * PACING_GAIN)
+}
+
+#[test]
+fn pacing() {
+    assert_eq!(pacing_interval(1280, 0, 14600), 0.0);
+
+    // 2G: 10 packets in a 500ms rtt, one every 40ms
+    let interval = pacing_interval(1280, 500, 12800);
+    assert_eq!(interval.round(), 40.0);
+
+    // a bigger window means shorter gaps
+    assert!(pacing_interval(1280, 500, 25600) < interval);
+}
+
+#[cfg(test)]
+fn connected_pair(settings: Settings) -> (Channel, Channel) {
+    use identity::Secret;
+
+    let (secret_i, secret_r) = (Secret::gen(), Secret::gen());
+    let (mut i, pkt) = noise::initiate(None, &secret_i, 1).unwrap();
+    let (r, _, _) = noise::respond(None, pkt).unwrap();
+    let (r, pkt) = r.send_response(7, &secret_r).unwrap();
+    i.recv_response(pkt).unwrap();
+    let i = i.into_transport().unwrap();
+
+    (
+        Channel::with_settings(i, "i", settings),
+        Channel::with_settings(r, "r", settings),
+    )
+}
+
+/// drain everything a channel wants to send and everything it received
+#[cfg(test)]
+fn drain(chan: &mut Channel, wire: &mut Vec<Vec<u8>>, received: &mut Vec<Vec<u8>>) {
+    loop {
+        match chan.progress().unwrap() {
+            ChannelProgress::Later(_) => return,
+            ChannelProgress::SendPacket(pkt) => wire.push(pkt),
+            ChannelProgress::ReceiveStream(_, m) => received.push(m),
+            _ => (),
+        }
+    }
+}
+
+#[test]
+fn rekey_under_reordering() {
+    let settings = Settings {
+        pacing: false,
+        rekey_packets: 8,
+        ..Settings::default()
+    };
+    let (mut i, mut r) = connected_pair(settings);
+    let si = i.open(Vec::new(), true);
+    let sr = r.open(Vec::new(), false);
+
+    let (mut to_r, mut to_i) = (Vec::new(), Vec::new());
+    let (mut got_i, mut got_r) = (Vec::new(), Vec::new());
+    let mut replays = 0;
+    for n in 0..450u32 {
+        if n < 400 {
+            i.stream(si, format!("i{}", n));
+            r.stream(sr, format!("r{}", n));
+        }
+        drain(&mut i, &mut to_r, &mut got_i);
+        drain(&mut r, &mut to_i, &mut got_r);
+
+        // the link swaps neighbours and duplicates every fifth packet
+        for wire in &mut [&mut to_r, &mut to_i] {
+            for k in (1..wire.len()).step_by(2) {
+                wire.swap(k - 1, k);
+            }
+            let dups: Vec<Vec<u8>> = wire.iter().step_by(5).cloned().collect();
+            wire.extend(dups);
+        }
+
+        for pkt in to_r.drain(..) {
+            match r.recv(EncryptedPacket::decode(&pkt).unwrap()) {
+                Ok(()) => (),
+                Err(Error::AntiReplay) => replays += 1,
+                Err(e) => panic!("responder failed: {}", e),
+            }
+        }
+        for pkt in to_i.drain(..) {
+            match i.recv(EncryptedPacket::decode(&pkt).unwrap()) {
+                Ok(()) => (),
+                Err(Error::AntiReplay) => replays += 1,
+                Err(e) => panic!("initiator failed: {}", e),
+            }
+        }
+    }
+
+    assert!(i.noise.epoch() > 20, "only {} rekeys", i.noise.epoch());
+    assert!(r.noise.epoch() >= i.noise.epoch());
+    assert!(replays > 0);
+    assert_eq!(i.stats().key_epoch, i.noise.epoch());
+    assert_eq!(i.stats().version, 0x09);
+
+    let expect = |p: &str| (0..400).map(|n| format!("{}{}", p, n).into_bytes()).collect::<Vec<_>>();
+    assert_eq!(got_r, expect("i"));
+    assert_eq!(got_i, expect("r"));
+}
+
+#[test]
+fn version_0x08_frames() {
+    let settings = Settings {
+        pacing: false,
+        ..Settings::default()
+    };
+    let (mut i, mut r) = connected_pair(settings);
+    i.noise.downgrade(0x08);
+    r.noise.downgrade(0x08);
+    assert!(!i.validates_paths());
+    assert!(i.path_challenge(1).is_err());
+
+    i.datagram(b"lost".to_vec());
+    let stream = i.open(Vec::new(), true);
+    i.stream(stream, "hello");
+
+    let (mut wire, mut got) = (Vec::new(), Vec::new());
+    drain(&mut i, &mut wire, &mut Vec::new());
+    assert!(!wire.is_empty());
+
+    // a 0x08 peer rejects packets with frames it doesn't know
+    for pkt in wire.drain(..) {
+        r.recv(EncryptedPacket::decode(&pkt).unwrap()).unwrap();
+    }
+    drain(&mut r, &mut wire, &mut got);
+    assert_eq!(got, vec![b"hello".to_vec()]);
+}
+
+#[test]
+fn acks_bypass_pacing() {
+    let settings = Settings {
+        pacing: true,
+        ..Settings::default()
+    };
+    let (mut i, mut r) = connected_pair(settings);
+    let si = i.open(Vec::new(), true);
+    let sr = r.open(Vec::new(), false);
+
+    r.stream(sr, "ping");
+    let (mut wire, mut got) = (Vec::new(), Vec::new());
+    drain(&mut r, &mut wire, &mut Vec::new());
+    assert!(!wire.is_empty());
+
+    // the pacer holds back everything else
+    i.next_send = i.now() as f64 + 10_000.0;
+    i.stream(si, "pong");
+    for pkt in wire.drain(..) {
+        i.recv(EncryptedPacket::decode(&pkt).unwrap()).unwrap();
+    }
+    drain(&mut i, &mut wire, &mut got);
+    assert_eq!(got, vec![b"ping".to_vec()]);
+    assert!(!wire.is_empty());
+    assert!(i.outqueue.iter().all(|frame| !frame.is_ack()));
+    assert!(i.bytes_queued() > 0);
+}
+
+/// move the clock of chan forward
+#[cfg(test)]
+fn advance(chan: &mut Channel, ms: u64) {
+    chan.basetime -= Duration::from_millis(ms);
+}
+
+/// messages delivered in 10 seconds over a 100ms rtt link that loses every tenth packet,
+/// and the congestion window at the end
+#[cfg(test)]
+fn lossy_transfer(congestion: congestion::Algorithm) -> (usize, u64) {
+    let settings = Settings {
+        congestion,
+        pacing: false,
+        ..Settings::default()
+    };
+    let (mut i, mut r) = connected_pair(settings);
+    let si = i.open(Vec::new(), true);
+    r.open(Vec::new(), false);
+
+    let (mut to_r, mut to_i) = (VecDeque::new(), VecDeque::new());
+    let mut wire = Vec::new();
+    let (mut sent, mut received) = (0, 0);
+    for now in (0..10_000u64).step_by(5) {
+        while i.bytes_queued() < 50_000 {
+            i.stream(si, vec![0; 1000]);
+        }
+        drain(&mut i, &mut wire, &mut Vec::new());
+        for pkt in wire.drain(..) {
+            sent += 1;
+            if sent % 10 != 0 {
+                to_r.push_back((now + 50, pkt));
+            }
+        }
+        while to_r.front().map_or(false, |&(at, _)| at <= now) {
+            let (_, pkt) = to_r.pop_front().unwrap();
+            r.recv(EncryptedPacket::decode(&pkt).unwrap()).unwrap();
+        }
+
+        let mut got = Vec::new();
+        drain(&mut r, &mut wire, &mut got);
+        received += got.len();
+        for pkt in wire.drain(..) {
+            to_i.push_back((now + 50, pkt));
+        }
+        while to_i.front().map_or(false, |&(at, _)| at <= now) {
+            let (_, pkt) = to_i.pop_front().unwrap();
+            i.recv(EncryptedPacket::decode(&pkt).unwrap()).unwrap();
+        }
+
+        advance(&mut i, 5);
+        advance(&mut r, 5);
+    }
+    (received, i.stats().congestion_window)
+}
+
+#[test]
+fn congestion_control_on_a_lossy_link() {
+    let newreno = lossy_transfer(congestion::Algorithm::NewReno);
+    let cubic = lossy_transfer(congestion::Algorithm::Cubic);
+    let bbr = lossy_transfer(congestion::Algorithm::Bbr);
+    assert!(
+        newreno != cubic && cubic != bbr && newreno != bbr,
+        "newreno {:?}, cubic {:?}, bbr {:?}",
+        newreno,
+        cubic,
+        bbr
+    );
+    // bbr doesn't back off on loss alone
+    assert!(bbr.0 > newreno.0, "bbr {:?}, newreno {:?}", bbr, newreno);
+}
+
+#[test]
+fn dead_peer() {
+    let settings = Settings {
+        pacing: false,
+        ..Settings::default()
+    };
+    let (mut i, _r) = connected_pair(settings);
+    let si = i.open(Vec::new(), true);
+
+    // the application keeps writing and nothing ever comes back.
+    // the window closes, so new packets don't keep pushing the loss alarm out
+    for _ in 0..3000 {
+        while i.bytes_queued() < 10_000 {
+            i.stream(si, vec![0; 1000]);
+        }
+        loop {
+            match i.progress().unwrap() {
+                ChannelProgress::Later(_) => break,
+                ChannelProgress::Disconnect => {
+                    assert!(i.stats().packets_retransmitted > 0);
+                    return;
+                }
+                _ => (),
+            }
+        }
+        advance(&mut i, 10);
+    }
+    panic!("still sending to a dead peer after 30 seconds");
+}
@@ -0,0 +1,634 @@
+//! std::future and tokio adapters.
+//!
+//! the endpoint keeps running on osaka, in a thread of its own.
+//! AsyncEndpoint talks to that thread over channels, the same way a Handle does,
+//! so everything here can be used from any executor.
+//!
+//! the endpoint in the thread only makes outgoing connections.
+//! incomming connect requests, and streams opened by peers, are rejected.
+//!
+//! writes through AsyncWrite wait while the endpoint thread holds MAX_QUEUED bytes of the stream.
+//! the thread passes them on to the channel as long as the channel has less than that queued.
+
+use channel::MAX_MESSAGE_SIZE;
+use endpoint::{Endpoint, EndpointBuilder, Event, Stream};
+use error::Error;
+use futures::channel::{mpsc, oneshot};
+use futures::task::{Context, Poll};
+use futures::Stream as FuturesStream;
+use handle::{Handle, Notice};
+use headers::Headers;
+use identity::Identity;
+use mio_extras::channel as mio_channel;
+use osaka::{osaka, Future as OsakaFuture, FutureResult};
+use packet::RoutingKey;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use std::thread;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// bytes per stream frame written through AsyncWrite, the same chunk size Stream::message uses
+const WRITE_CHUNK: usize = 600;
+
+/// bytes written to an AsyncStream that the endpoint thread holds,
+/// and bytes a channel may have queued before the thread holds more
+const MAX_QUEUED: usize = 64 * 1024;
+
+enum Request {
+    Connect {
+        target: Identity,
+        reply:  oneshot::Sender<Result<RoutingKey, Error>>,
+    },
+    Open {
+        route:   RoutingKey,
+        headers: Headers,
+        reply:   oneshot::Sender<Result<(u32, mpsc::UnboundedReceiver<Vec<u8>>, Arc<Credit>), Error>>,
+    },
+    Write {
+        route:   RoutingKey,
+        stream:  u32,
+        payload: Vec<u8>,
+    },
+    /// after everything written so far
+    Close {
+        route:   RoutingKey,
+        stream:  u32,
+    },
+}
+
+/// bytes an AsyncStream wrote that the endpoint thread hasn't passed on to the channel yet
+#[derive(Default)]
+struct Credit {
+    queued: AtomicUsize,
+    waker:  Mutex<Option<Waker>>,
+    /// the endpoint thread forgot the stream
+    gone:   AtomicBool,
+}
+
+impl Credit {
+    /// true when len more bytes may be written now. otherwise the writer is woken on release
+    fn take(&self, len: usize, cx: &mut Context) -> bool {
+        if self.queued.load(Ordering::SeqCst) >= MAX_QUEUED {
+            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            // released in between
+            if self.queued.load(Ordering::SeqCst) >= MAX_QUEUED && !self.gone.load(Ordering::SeqCst) {
+                return false;
+            }
+        }
+        self.queued.fetch_add(len, Ordering::SeqCst);
+        true
+    }
+
+    /// true when nothing is held anymore. otherwise the writer is woken on release
+    fn drained(&self, cx: &mut Context) -> bool {
+        if self.queued.load(Ordering::SeqCst) == 0 {
+            return true;
+        }
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        self.queued.load(Ordering::SeqCst) == 0 || self.gone.load(Ordering::SeqCst)
+    }
+
+    fn release(&self, len: usize) {
+        self.queued.fetch_sub(len, Ordering::SeqCst);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// frames of an AsyncStream on the endpoint thread
+struct Writer {
+    pending: VecDeque<Vec<u8>>,
+    credit:  Arc<Credit>,
+    closing: bool,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.credit.gone.store(true, Ordering::SeqCst);
+        self.credit.wake();
+    }
+}
+
+type Requests = Arc<Mutex<mio_channel::Sender<Request>>>;
+
+pub struct AsyncEndpoint {
+    handle:   Handle,
+    requests: Requests,
+    notices:  mpsc::UnboundedReceiver<Notice>,
+}
+
+impl AsyncEndpoint {
+    /// connect to a broker in a new thread.
+    /// the thread ends when the AsyncEndpoint is dropped
+    pub fn spawn(builder: EndpointBuilder) -> Spawning {
+        let (ready, ready_rx) = oneshot::channel();
+        let (requests, requests_rx) = mio_channel::channel();
+        let (notices, notices_rx) = mpsc::unbounded();
+
+        thread::spawn(move || {
+            let poll = osaka::Poll::new();
+            if let Err(e) = drive(poll, builder, ready, requests_rx, notices).run() {
+                warn!("async endpoint: {}", e);
+            }
+        });
+
+        Spawning {
+            ready:   ready_rx,
+            parts:   Some((requests, notices_rx)),
+        }
+    }
+
+    /// a handle to the endpoint for threads that don't use futures
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    fn request(&self, req: Request) -> Result<(), Error> {
+        request(&self.requests, req)
+    }
+
+    /// connect to target through the broker, resolving to the route of the new channel
+    pub fn connect(&self, target: Identity) -> Reply<RoutingKey> {
+        let (reply, rx) = oneshot::channel();
+        let early = self.request(Request::Connect { target, reply }).err();
+        Reply { rx, early }
+    }
+
+    /// open a stream on the channel to route
+    pub fn open(&self, route: RoutingKey, headers: Headers) -> Opening {
+        let (reply, rx) = oneshot::channel();
+        let early = self.request(Request::Open { route, headers, reply }).err();
+        Opening {
+            requests: self.requests.clone(),
+            route,
+            rx: Reply { rx, early },
+        }
+    }
+}
+
+/// everything that happens on the endpoint, until the endpoint thread ends
+impl FuturesStream for AsyncEndpoint {
+    type Item = Notice;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Notice>> {
+        Pin::new(&mut self.notices).poll_next(cx)
+    }
+}
+
+pub struct Spawning {
+    ready: oneshot::Receiver<Result<Handle, Error>>,
+    parts: Option<(mio_channel::Sender<Request>, mpsc::UnboundedReceiver<Notice>)>,
+}
+
+impl Future for Spawning {
+    type Output = Result<AsyncEndpoint, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let handle = match Pin::new(&mut self.ready).poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(oneshot::Canceled)) => return Poll::Ready(Err(Error::EndpointGone)),
+            Poll::Ready(Ok(Err(e))) => return Poll::Ready(Err(e)),
+            Poll::Ready(Ok(Ok(handle))) => handle,
+        };
+        let (requests, notices) = self.parts.take().expect("Spawning polled after completion");
+        Poll::Ready(Ok(AsyncEndpoint {
+            handle,
+            requests: Arc::new(Mutex::new(requests)),
+            notices,
+        }))
+    }
+}
+
+/// the answer of the endpoint thread to a request
+pub struct Reply<T> {
+    rx:    oneshot::Receiver<Result<T, Error>>,
+    early: Option<Error>,
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(e) = self.early.take() {
+            return Poll::Ready(Err(e));
+        }
+        match Pin::new(&mut self.rx).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(oneshot::Canceled)) => Poll::Ready(Err(Error::EndpointGone)),
+            Poll::Ready(Ok(r)) => Poll::Ready(r),
+        }
+    }
+}
+
+fn request(requests: &Requests, req: Request) -> Result<(), Error> {
+    requests
+        .lock()
+        .map_err(|_| Error::EndpointGone)?
+        .send(req)
+        .map_err(|_| Error::EndpointGone)
+}
+
+pub struct Opening {
+    requests: Requests,
+    route:    RoutingKey,
+    rx:       Reply<(u32, mpsc::UnboundedReceiver<Vec<u8>>, Arc<Credit>)>,
+}
+
+impl Future for Opening {
+    type Output = Result<AsyncStream, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let (stream, incomming, credit) = match Pin::new(&mut self.rx).poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Ready(Ok(v)) => v,
+        };
+        Poll::Ready(Ok(AsyncStream {
+            requests: self.requests.clone(),
+            route: self.route,
+            stream,
+            incomming,
+            credit,
+            readbuf: Vec::new(),
+        }))
+    }
+}
+
+/// a stream on the endpoint thread.
+///
+/// as a futures Stream it yields whole frames, starting with the peer's headers.
+/// AsyncRead and AsyncWrite treat it as a byte stream instead, so the frame boundaries are lost.
+/// don't mix the two for reading.
+pub struct AsyncStream {
+    requests:  Requests,
+    route:     RoutingKey,
+    stream:    u32,
+    incomming: mpsc::UnboundedReceiver<Vec<u8>>,
+    credit:    Arc<Credit>,
+    /// the rest of a frame that didn't fit into the last read
+    readbuf:   Vec<u8>,
+}
+
+impl AsyncStream {
+    pub fn route(&self) -> RoutingKey {
+        self.route
+    }
+
+    pub fn id(&self) -> u32 {
+        self.stream
+    }
+
+    /// send one frame, without waiting for credit
+    pub fn send<M: Into<Vec<u8>>>(&self, m: M) -> Result<(), Error> {
+        let payload = m.into();
+        if payload.len() >= MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooBig { len: payload.len() });
+        }
+        self.credit.queued.fetch_add(payload.len(), Ordering::SeqCst);
+        self.write(payload)
+    }
+
+    fn write(&self, payload: Vec<u8>) -> Result<(), Error> {
+        request(&self.requests, Request::Write {
+            route: self.route,
+            stream: self.stream,
+            payload,
+        })
+    }
+
+    fn close(&self) {
+        request(&self.requests, Request::Close {
+            route: self.route,
+            stream: self.stream,
+        })
+        .ok();
+    }
+}
+
+impl Drop for AsyncStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl FuturesStream for AsyncStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Vec<u8>>> {
+        Pin::new(&mut self.incomming).poll_next(cx)
+    }
+}
+
+impl AsyncRead for AsyncStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.readbuf.is_empty() {
+            match Pin::new(&mut self.incomming).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                // closed
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Some(frame)) => self.readbuf = frame,
+            }
+        }
+        let len = buf.len().min(self.readbuf.len());
+        buf[..len].copy_from_slice(&self.readbuf[..len]);
+        self.readbuf.drain(..len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for AsyncStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.credit.gone.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream closed")));
+        }
+        let len = buf.len().min(WRITE_CHUNK);
+        if !self.credit.take(len, cx) {
+            return Poll::Pending;
+        }
+        match self.write(buf[..len].to_vec()) {
+            Ok(()) => Poll::Ready(Ok(len)),
+            Err(e) => Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))),
+        }
+    }
+
+    /// ready once the endpoint thread passed everything on to the channel
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.credit.gone.load(Ordering::SeqCst) || self.credit.drained(cx) {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        self.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// stream driver on the endpoint thread
+#[osaka]
+fn forward(_poll: osaka::Poll, mut stream: Stream, tx: mpsc::UnboundedSender<Vec<u8>>) {
+    loop {
+        let m = osaka::sync!(stream);
+        if tx.unbounded_send(m).is_err() {
+            debug!("stream {} was dropped by its AsyncStream", stream.id());
+            return;
+        }
+    }
+}
+
+/// pass held frames on to their channels, as long as the channels have room
+fn flush(ep: &mut Endpoint, writers: &mut HashMap<(RoutingKey, u32), Writer>) {
+    writers.retain(|&(route, stream), w| {
+        if !ep.is_open(route, stream) {
+            return false;
+        }
+        let mut queued = ep.stats(route).map(|s| s.channel.bytes_queued).unwrap_or(0);
+        while queued < MAX_QUEUED {
+            let m = match w.pending.pop_front() {
+                Some(m) => m,
+                None => break,
+            };
+            queued += m.len();
+            w.credit.release(m.len());
+            ep.stream(route, stream, m);
+        }
+        if w.closing && w.pending.is_empty() {
+            ep.close(route, stream);
+            return false;
+        }
+        true
+    });
+}
+
+/// run an endpoint until the AsyncEndpoint goes away
+#[osaka]
+fn drive(
+    poll: osaka::Poll,
+    builder: EndpointBuilder,
+    ready: oneshot::Sender<Result<Handle, Error>>,
+    requests: mio_channel::Receiver<Request>,
+    notices: mpsc::UnboundedSender<Notice>,
+) -> Result<(), Error> {
+    let mut ep = builder.connect(poll.clone());
+    let mut ep: Endpoint = match osaka::sync!(ep) {
+        Ok(v) => v,
+        Err(e) => {
+            ready.send(Err(e)).ok();
+            return Ok(());
+        }
+    };
+    if ready.send(Ok(ep.handle())).is_err() {
+        return Ok(());
+    }
+
+    let token = poll
+        .register(&requests, osaka::mio::Ready::readable(), osaka::mio::PollOpt::level())
+        .unwrap();
+    let mut connecting: HashMap<Identity, Vec<oneshot::Sender<Result<RoutingKey, Error>>>> = HashMap::new();
+    let mut writers: HashMap<(RoutingKey, u32), Writer> = HashMap::new();
+
+    loop {
+        loop {
+            match requests.try_recv() {
+                Ok(Request::Connect { target, reply }) => match ep.connect(target.clone()) {
+                    Ok(()) => connecting.entry(target).or_insert_with(Vec::new).push(reply),
+                    Err(e) => {
+                        reply.send(Err(e)).ok();
+                    }
+                },
+                Ok(Request::Open { route, headers, reply }) => {
+                    if ep.stats(route).is_none() {
+                        reply.send(Err(Error::NoRoute { route })).ok();
+                        continue;
+                    }
+                    let (tx, rx) = mpsc::unbounded();
+                    let mut id = None;
+                    ep.open(route, headers, |poll, stream| {
+                        id = Some(stream.id());
+                        forward(poll, stream, tx)
+                    });
+                    let credit = Arc::new(Credit::default());
+                    let id = id.unwrap();
+                    writers.insert((route, id), Writer {
+                        pending: VecDeque::new(),
+                        credit:  credit.clone(),
+                        closing: false,
+                    });
+                    reply.send(Ok((id, rx, credit))).ok();
+                }
+                Ok(Request::Write { route, stream, payload }) => match writers.get_mut(&(route, stream)) {
+                    Some(w) => w.pending.push_back(payload),
+                    None => debug!("dropping write to closed stream {} on route {}", stream, route),
+                },
+                Ok(Request::Close { route, stream }) => {
+                    if let Some(w) = writers.get_mut(&(route, stream)) {
+                        w.closing = true;
+                    }
+                }
+                Err(mio_channel::TryRecvError::Empty) => break,
+                Err(mio_channel::TryRecvError::Disconnected) => {
+                    debug!("async endpoint dropped, ending its thread");
+                    return Ok(());
+                }
+            }
+        }
+
+        flush(&mut ep, &mut writers);
+
+        match ep.poll() {
+            FutureResult::Done(Err(e)) => return Err(e),
+            FutureResult::Done(Ok(Event::IncommingConnect(q))) => {
+                debug!("rejecting connect from {}", q.identity);
+                ep.reject(q);
+            }
+            FutureResult::Done(Ok(Event::OutgoingConnect(q))) => {
+                let identity = q.identity.clone();
+                let r = ep.accept_outgoing(q, |_, _| None);
+                let mut replies = connecting.remove(&identity).unwrap_or_default().into_iter();
+                let route = match r {
+                    Ok(route) => {
+                        notices
+                            .unbounded_send(Notice::Connected {
+                                route,
+                                identity: identity.clone(),
+                            })
+                            .ok();
+                        if let Some(reply) = replies.next() {
+                            reply.send(Ok(route)).ok();
+                        }
+                        Some(route)
+                    }
+                    Err(e) => {
+                        if let Some(reply) = replies.next() {
+                            reply.send(Err(e)).ok();
+                        }
+                        None
+                    }
+                };
+                for reply in replies {
+                    reply
+                        .send(route.ok_or(Error::OutgoingConnectFailed {
+                            identity: identity.clone(),
+                            cr:       None,
+                        }))
+                        .ok();
+                }
+            }
+            FutureResult::Done(Ok(event)) => {
+                if let Some(notice) = Notice::from_event(&event) {
+                    notices.unbounded_send(notice).ok();
+                }
+            }
+            FutureResult::Again(mut again) => {
+                again.merge(poll.again(token.clone(), None));
+                yield again;
+            }
+        }
+    }
+}
+
+#[test]
+fn async_types_are_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<AsyncEndpoint>();
+    assert_send::<AsyncStream>();
+    assert_send::<Spawning>();
+}
+
+#[cfg(test)]
+struct Flag(AtomicBool);
+
+#[cfg(test)]
+impl futures::task::ArcWake for Flag {
+    fn wake_by_ref(flag: &Arc<Self>) {
+        flag.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn stream_io() {
+    let (requests, rx) = mio_channel::channel();
+    let (tx, incomming) = mpsc::unbounded();
+    let mut s = AsyncStream {
+        requests: Arc::new(Mutex::new(requests)),
+        route: 1,
+        stream: 3,
+        incomming,
+        credit: Arc::new(Credit::default()),
+        readbuf: Vec::new(),
+    };
+    let woken = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = futures::task::waker(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    // a frame is read over as many calls as it takes
+    tx.unbounded_send(b"hello".to_vec()).unwrap();
+    let mut buf = [0; 3];
+    match Pin::new(&mut s).poll_read(&mut cx, &mut buf) {
+        Poll::Ready(Ok(3)) => assert_eq!(&buf, b"hel"),
+        _ => panic!("expected 3 bytes"),
+    }
+    match Pin::new(&mut s).poll_read(&mut cx, &mut buf) {
+        Poll::Ready(Ok(2)) => assert_eq!(&buf[..2], b"lo"),
+        _ => panic!("expected the rest of the frame"),
+    }
+    assert!(Pin::new(&mut s).poll_read(&mut cx, &mut buf).is_pending());
+    drop(tx);
+    match Pin::new(&mut s).poll_read(&mut cx, &mut buf) {
+        Poll::Ready(Ok(0)) => (),
+        _ => panic!("expected the end of the stream"),
+    }
+
+    // writes wait once the endpoint thread holds too much
+    let chunk = vec![0; WRITE_CHUNK + 1];
+    let mut written = 0;
+    while let Poll::Ready(r) = Pin::new(&mut s).poll_write(&mut cx, &chunk) {
+        written += r.unwrap();
+    }
+    assert!(written >= MAX_QUEUED && written < MAX_QUEUED + WRITE_CHUNK);
+    assert!(Pin::new(&mut s).poll_flush(&mut cx).is_pending());
+    assert!(!woken.0.load(Ordering::SeqCst));
+
+    // the endpoint thread passes them on
+    let mut received = 0;
+    while let Ok(Request::Write { route: 1, stream: 3, payload }) = rx.try_recv() {
+        assert_eq!(payload.len(), WRITE_CHUNK);
+        received += payload.len();
+        s.credit.release(payload.len());
+    }
+    assert_eq!(received, written);
+    assert!(woken.0.load(Ordering::SeqCst));
+    match Pin::new(&mut s).poll_flush(&mut cx) {
+        Poll::Ready(Ok(())) => (),
+        _ => panic!("expected everything to be flushed"),
+    }
+
+    // the stream is closed once the endpoint thread forgets it
+    drop(Writer {
+        pending: VecDeque::new(),
+        credit:  s.credit.clone(),
+        closing: false,
+    });
+    match Pin::new(&mut s).poll_write(&mut cx, &chunk) {
+        Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::BrokenPipe => (),
+        _ => panic!("expected a broken pipe"),
+    }
+
+    drop(s);
+    match rx.try_recv() {
+        Ok(Request::Close { route: 1, stream: 3 }) => (),
+        _ => panic!("expected the stream to be closed"),
+    }
+}
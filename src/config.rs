@@ -1,127 +1,392 @@
 use dirs;
 use error::Error;
 use identity;
-use rand::thread_rng;
-use rand::RngCore;
+use keystore;
 use std::env;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Seek;
-use std::io::SeekFrom;
-use std::io::{Read, Write};
+use std::fs::{read_dir, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer};
 use toml;
+use toml::Spanned;
 use certificate;
+use channel;
+use pmtu;
+use congestion;
+use paths;
 use std::mem;
-use std::collections::HashMap;
-use mtdparts::parse_mtd;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use reflect;
+use timestamps;
 
 #[derive(Deserialize)]
 pub struct AuthorizationToml {
-    identity:   String,
+    identity:   Located<String>,
     resource:   String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub struct PublisherConfigToml {
-    shadow: String,
+    shadow:         Option<Located<String>>,
+    disable:        Option<Vec<String>>,
+    close_revoked:  Option<bool>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
+pub struct PathPolicyToml {
+    relay:      Option<bool>,
+    internet:   Option<bool>,
+    reevaluate: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
 struct ConfigToml {
-    secret:         Option<String>,
+    secret:         Option<Located<String>>,
     keepalive:      Option<u16>,
+    congestion:     Option<Located<String>>,
+    pacing:         Option<bool>,
+    mtu_floor:      Option<usize>,
+    mtu_ceiling:    Option<usize>,
+    paths:          Option<PathPolicyToml>,
+    reflectors:     Option<Vec<String>>,
+    lan:            Option<bool>,
+    brokers:        Option<usize>,
     publish:        Option<PublisherConfigToml>,
     authorize:      Option<Vec<AuthorizationToml>>,
-    names:          Option<HashMap<String, String>>,
+    names:          Option<HashMap<String, Located<String>>>,
+    peers:          Option<HashMap<String, Located<String>>>,
+    clock_skew:     Option<u64>,
+    succession:     Option<Vec<Located<String>>>,
 }
 
-impl ConfigToml {
-    fn secret(&self) -> Result<identity::Secret, Error> {
-        if let Some(ref s) = self.secret {
-            if s.starts_with(":") {
-                let mut fu_brwcheck : String;
-                let mut s: Vec<&str> = s.split(":").collect();
-
-                if s.get(1) == Some(&"mtdname") {
-                    if let Some(name) = s.get(2).map(|v|v.to_string()) {
-                        let f = File::open("/proc/mtd").expect("open /proc/mtd");
-                        let names = parse_mtd(f).expect("parsing /proc/mtd");
-                        let dev = names.get(&name).expect(&format!("mtd partition {} not found", name));
-                        fu_brwcheck = format!("/dev/{}", dev);
-
-                        s[1] = "mtd";
-                        s[2] = &fu_brwcheck;
-                    }
-                }
+/// the system wide file, read before the user's
+pub const SYSTEM_FILE: &str = "/etc/carrier/carrier.toml";
 
-                if s.get(1) == Some(&"mtd") {
-                    if let Some(mtd) = s.get(2) {
-                        let offset = s.get(3).and_then(|v|v.parse().ok()).unwrap_or(40);
-                        let mut f = OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .open(mtd)
-                            .expect(&format!("cannot open {}", mtd));
-                        f.seek(SeekFrom::Start(offset))?;
-
-                        let mut b = [0u8; 32];
-                        f.read_exact(&mut b)?;
-
-                        if b == [0xff; 32] || b == [0x0; 32] {
-                            f.seek(SeekFrom::Start(offset))?;
-                            thread_rng().try_fill_bytes(&mut b).unwrap();
-                            f.write(&b)?;
-                        }
-                        return Ok(identity::Secret::from_array(b));
-                    }
-                }
+/// drop-ins read after SYSTEM_FILE, by name
+pub const SYSTEM_DROPINS: &str = "/etc/carrier/conf.d";
 
-                return Err(Error::NoSecrets);
+/// environment variables overriding a key, with the table the key is in
+const OVERRIDES: &[(&str, Option<&str>, &str)] = &[
+    ("CARRIER_SECRET",           None,            "secret"),
+    ("CARRIER_KEEPALIVE",        None,            "keepalive"),
+    ("CARRIER_CONGESTION",       None,            "congestion"),
+    ("CARRIER_PACING",           None,            "pacing"),
+    ("CARRIER_MTU_FLOOR",        None,            "mtu_floor"),
+    ("CARRIER_MTU_CEILING",      None,            "mtu_ceiling"),
+    ("CARRIER_LAN",              None,            "lan"),
+    ("CARRIER_BROKERS",          None,            "brokers"),
+    ("CARRIER_CLOCK_SKEW",       None,            "clock_skew"),
+    ("CARRIER_PUBLISH_SHADOW",   Some("publish"), "shadow"),
+    ("CARRIER_PATHS_RELAY",      Some("paths"),   "relay"),
+    ("CARRIER_PATHS_INTERNET",   Some("paths"),   "internet"),
+    ("CARRIER_PATHS_REEVALUATE", Some("paths"),   "reevaluate"),
+];
+
+/// a value and where it was set, for problems to point at
+struct Located<T> {
+    value:  T,
+    layer:  usize,
+    start:  usize,
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Located<T> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let v = Spanned::<T>::deserialize(d)?;
+        Ok(Located {
+            start: v.start(),
+            layer: 0,
+            value: v.into_inner(),
+        })
+    }
+}
+
+/// one layer of the config
+enum Origin {
+    File { path: String, source: String },
+    Env { var: String, source: String },
+}
+
+impl Origin {
+    fn env(var: &str, table: Option<&str>, key: &str, value: &str) -> Origin {
+        // numbers and booleans as they are, anything else as a string
+        let literal = if value == "true" || value == "false" || value.parse::<i64>().is_ok() {
+            value.to_string()
+        } else {
+            let mut r = String::from("\"");
+            for c in value.chars() {
+                match c {
+                    '"' => r.push_str("\\\""),
+                    '\\' => r.push_str("\\\\"),
+                    c if c.is_control() => r.push_str(&format!("\\u{:04X}", c as u32)),
+                    c => r.push(c),
+                }
             }
+            r.push('"');
+            r
+        };
+        let source = match table {
+            Some(table) => format!("[{}]\n{} = {}\n", table, key, literal),
+            None => format!("{} = {}\n", key, literal),
+        };
+        Origin::Env { var: var.to_string(), source }
+    }
 
-            let s: identity::Secret = s.parse()?;
-            return Ok(s);
+    fn source(&self) -> &str {
+        match self {
+            Origin::File { source, .. } | Origin::Env { source, .. } => source,
         }
-        Err(Error::NoSecrets)
     }
 
-    fn publisher(&mut self, identity: identity::Identity) -> Result<Option<PublisherConfig>, Error> {
-        let publish = match &self.publish {
-            None => return Ok(None),
-            Some(v) => v,
+    fn problem<R: ToString>(&self, line: Option<usize>, reason: R) -> Error {
+        match self {
+            Origin::File { path, .. } => Error::Config {
+                file:   path.clone(),
+                line,
+                reason: reason.to_string(),
+            },
+            Origin::Env { var, .. } => Error::Config {
+                file:   format!("${}", var),
+                line:   None,
+                reason: reason.to_string(),
+            },
+        }
+    }
+}
+
+/// everything wrong with a config, so it can be fixed in one go
+struct Problems {
+    origins:    Vec<Origin>,
+    problems:   Vec<Error>,
+}
+
+impl Problems {
+    /// a problem with the value on the line where value starts
+    fn at<T, R: ToString>(&mut self, value: &Located<T>, reason: R) {
+        let e = {
+            let origin = &self.origins[value.layer];
+            let line = origin.source()[..value.start].matches('\n').count() + 1;
+            origin.problem(Some(line), reason)
         };
+        self.problems.push(e);
+    }
 
-        let shadow = publish.shadow.parse::<identity::Address>()?;
+    /// a problem with the config as a whole
+    fn add<R: ToString>(&mut self, reason: R) {
+        let files: Vec<String> = self
+            .origins
+            .iter()
+            .filter_map(|o| match o {
+                Origin::File { path, .. } => Some(path.clone()),
+                Origin::Env { .. } => None,
+            })
+            .collect();
+        self.problems.push(Error::Config {
+            file:   files.join(", "),
+            line:   None,
+            reason: reason.to_string(),
+        });
+    }
 
-        let mut auth = certificate::Authenticator::new(identity, shadow.clone());
-        if let Some(authorize) = mem::replace(&mut self.authorize, None) {
-            for i in authorize {
-                match i.identity.parse() {
-                    Ok(identity) => {
-                        auth.allow(identity, vec![i.resource]);
-                    },
-                    Err(e) => {
-                        warn!("in config: {}", e);
-                    }
+    fn parse<T: FromStr<Err = Error>>(&mut self, what: &str, value: &Located<String>) -> Option<T> {
+        match value.value.parse() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.at(value, format!("{} '{}': {}", what, value.value, e));
+                None
+            }
+        }
+    }
+}
+
+fn replace<T>(a: &mut Option<T>, b: Option<T>) {
+    if b.is_some() {
+        *a = b;
+    }
+}
+
+fn append<T>(a: &mut Option<Vec<T>>, b: Option<Vec<T>>) {
+    if let Some(b) = b {
+        a.get_or_insert_with(Vec::new).extend(b);
+    }
+}
+
+fn extend<V>(a: &mut Option<HashMap<String, V>>, b: Option<HashMap<String, V>>) {
+    if let Some(b) = b {
+        a.get_or_insert_with(HashMap::new).extend(b);
+    }
+}
+
+impl ConfigToml {
+    /// other on top of self: values are replaced, lists appended to and tables merged
+    fn merge(&mut self, other: ConfigToml) {
+        replace(&mut self.secret, other.secret);
+        replace(&mut self.keepalive, other.keepalive);
+        replace(&mut self.congestion, other.congestion);
+        replace(&mut self.pacing, other.pacing);
+        replace(&mut self.mtu_floor, other.mtu_floor);
+        replace(&mut self.mtu_ceiling, other.mtu_ceiling);
+        if let Some(o) = other.paths {
+            let paths = self.paths.get_or_insert_with(PathPolicyToml::default);
+            replace(&mut paths.relay, o.relay);
+            replace(&mut paths.internet, o.internet);
+            replace(&mut paths.reevaluate, o.reevaluate);
+        }
+        append(&mut self.reflectors, other.reflectors);
+        replace(&mut self.lan, other.lan);
+        replace(&mut self.brokers, other.brokers);
+        if let Some(o) = other.publish {
+            let publish = self.publish.get_or_insert_with(PublisherConfigToml::default);
+            replace(&mut publish.shadow, o.shadow);
+            append(&mut publish.disable, o.disable);
+            replace(&mut publish.close_revoked, o.close_revoked);
+        }
+        append(&mut self.authorize, other.authorize);
+        extend(&mut self.names, other.names);
+        extend(&mut self.peers, other.peers);
+        replace(&mut self.clock_skew, other.clock_skew);
+        append(&mut self.succession, other.succession);
+    }
+
+    /// mark every located value as set in layer
+    fn locate(&mut self, layer: usize) {
+        fn at<T>(v: &mut Located<T>, layer: usize) {
+            v.layer = layer;
+        }
+        self.secret.iter_mut().for_each(|v| at(v, layer));
+        self.congestion.iter_mut().for_each(|v| at(v, layer));
+        self.publish.iter_mut().flat_map(|v| v.shadow.iter_mut()).for_each(|v| at(v, layer));
+        self.authorize.iter_mut().flat_map(|v| v.iter_mut()).for_each(|v| at(&mut v.identity, layer));
+        self.names.iter_mut().flat_map(|v| v.values_mut()).for_each(|v| at(v, layer));
+        self.peers.iter_mut().flat_map(|v| v.values_mut()).for_each(|v| at(v, layer));
+        self.succession.iter_mut().flat_map(|v| v.iter_mut()).for_each(|v| at(v, layer));
+    }
+
+    /// known is the config being reloaded. its secret is kept as long as the store didn't change,
+    /// without opening the store again, which may ask for a passphrase
+    fn secret(&self, p: &mut Problems, known: Option<&Config>) -> Option<identity::Secret> {
+        match (self.secret.as_ref(), known) {
+            (Some(s), Some(known)) => {
+                if known.secret_store.as_ref() == Some(&s.value) {
+                    Some(known.secret.clone())
+                } else {
+                    p.at(s, "secret: changed, which needs a restart");
+                    None
                 }
             }
+            // not through p.parse, the value may be the secret itself
+            (Some(s), None) => match keystore::open(&s.value).and_then(|mut store| store.load()) {
+                Ok(secret) => Some(secret),
+                Err(e) => {
+                    p.at(s, format!("secret: {}", e));
+                    None
+                }
+            },
+            (None, _) => {
+                p.add(Error::NoSecrets);
+                None
+            }
         }
+    }
 
+    /// identity is None when the secret is broken, which doesn't stop checking the rest
+    fn publisher(
+        &mut self,
+        p: &mut Problems,
+        identity: Option<identity::Identity>,
+        successions: &identity::Successions,
+    ) -> Option<PublisherConfig> {
+        let publish = match mem::replace(&mut self.publish, None) {
+            None => return None,
+            Some(v) => v,
+        };
+
+        let shadow = match publish.shadow {
+            Some(ref shadow) => p.parse::<identity::Address>("shadow", shadow),
+            None => {
+                p.add("[publish] has no shadow");
+                None
+            }
+        };
+        let mut grants = Vec::new();
+        for i in mem::replace(&mut self.authorize, None).unwrap_or_default() {
+            if let Some(identity) = p.parse::<identity::Identity>("authorize", &i.identity) {
+                grants.push((identity, i.resource));
+            }
+        }
 
-        Ok(Some(PublisherConfig{
+        let (identity, shadow) = match (identity, shadow) {
+            (Some(identity), Some(shadow)) => (identity, shadow),
+            _ => return None,
+        };
+        let mut auth = certificate::Authenticator::new(identity, shadow.clone());
+        auth.successions(successions.clone());
+        for (identity, resource) in grants {
+            auth.allow(identity, vec![resource]);
+        }
+
+        Some(PublisherConfig{
             shadow,
             auth,
-        }))
+            disabled:       publish.disable.unwrap_or_default().into_iter().collect(),
+            close_revoked:  publish.close_revoked.unwrap_or(false),
+        })
     }
 
-    fn names(&mut self) -> Result<HashMap<String, identity::Identity>, Error> {
+    fn names(&mut self, p: &mut Problems) -> HashMap<String, identity::Identity> {
         let mut r = HashMap::new();
         if let Some(names) = mem::replace(&mut self.names, None) {
             for (k,v) in names {
-                r.insert(k, v.parse()?);
+                if let Some(identity) = p.parse(&format!("name {}", k), &v) {
+                    r.insert(k, identity);
+                }
             }
         }
-        Ok(r)
+        r
+    }
+
+    /// verified succession statements, see `carrier identity rotate`
+    fn successions(&mut self, p: &mut Problems) -> identity::Successions {
+        let mut r = identity::Successions::default();
+        if let Some(statements) = mem::replace(&mut self.succession, None) {
+            for s in statements {
+                if let Some(succession) = p.parse("succession", &s) {
+                    if let Err(e) = r.add(succession) {
+                        p.at(&s, format!("succession: {}", e));
+                    }
+                }
+            }
+        }
+        r
+    }
+
+    /// static keys of peers, by identity or name
+    fn peers(
+        &mut self,
+        p: &mut Problems,
+        names: &HashMap<String, identity::Identity>,
+    ) -> HashMap<identity::Identity, identity::Address> {
+        let mut r = HashMap::new();
+        if let Some(peers) = mem::replace(&mut self.peers, None) {
+            for (k,v) in peers {
+                let identity = match names.get(&k) {
+                    Some(identity) => Some(identity.clone()),
+                    None => match k.parse() {
+                        Ok(identity) => Some(identity),
+                        Err(e) => {
+                            p.at(&v, format!("peer '{}' is neither a name nor an identity: {}", k, e));
+                            None
+                        }
+                    },
+                };
+                let address = p.parse(&format!("peer {}", k), &v);
+                if let (Some(identity), Some(address)) = (identity, address) {
+                    r.insert(identity, address);
+                }
+            }
+        }
+        r
     }
 }
 
@@ -135,48 +400,397 @@ pub struct Authorization {
 pub struct PublisherConfig {
     pub shadow: identity::Address,
     pub auth:   certificate::Authenticator,
+    /// resources not served, whoever asks
+    pub disabled:       HashSet<String>,
+    /// close open streams whose identity lost access when the config is reloaded
+    pub close_revoked:  bool,
 }
 
 #[derive(Clone)]
 pub struct Config {
     pub secret:         identity::Secret,
+    /// where the secret came from, see keystore::open
+    pub secret_store:   Option<String>,
     pub keepalive:      Option<u16>,
+    pub congestion:     congestion::Algorithm,
+    pub pacing:         bool,
+    pub mtu_floor:      usize,
+    pub mtu_ceiling:    usize,
+    pub paths:          paths::PathPolicy,
+    pub reflectors:     Vec<SocketAddr>,
+    /// announce published identities on the local network, and accept direct handshakes
+    pub lan:            bool,
+    /// how many brokers to stay connected to
+    pub brokers:        usize,
     pub publish:        Option<PublisherConfig>,
     pub names:          HashMap<String, identity::Identity>,
+    /// peers we know the static key of, which we handshake with mutually
+    pub peers:          HashMap<identity::Identity, identity::Address>,
+    /// how far off our clock a peer's handshake may be, in seconds. 0 disables the check
+    pub clock_skew:     u64,
+    /// identities that were replaced by new ones
+    pub successions:    identity::Successions,
+    /// the file load_from was given instead of the user's
+    pub user_file:      Option<PathBuf>,
+}
+
+/// the user's file: $CARRIER_CONFIG_FILE, or ~/.devguard/carrier.toml
+pub fn path() -> PathBuf {
+    match env::var("CARRIER_CONFIG_FILE") {
+        Ok(v) => v.into(),
+        Err(_) => dirs::home_dir()
+            .unwrap_or("/root/".into())
+            .join(".devguard/carrier.toml"),
+    }
+}
+
+/// the files a config is merged from, each overriding the ones before it:
+/// SYSTEM_FILE, the drop-ins in SYSTEM_DROPINS by name, then the user's file or user instead.
+/// the CARRIER_* variables in OVERRIDES go on top of all of them
+pub fn files(user: Option<&Path>) -> Vec<PathBuf> {
+    let mut r = Vec::new();
+    let system = PathBuf::from(SYSTEM_FILE);
+    if system.exists() {
+        r.push(system);
+    }
+    if let Ok(dir) = read_dir(SYSTEM_DROPINS) {
+        let mut dropins: Vec<PathBuf> = dir
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().map(|e| e == "toml").unwrap_or(false))
+            .collect();
+        dropins.sort();
+        r.extend(dropins);
+    }
+
+    // a file that was asked for, or the only one, has to be there
+    let explicit = user.is_some() || env::var("CARRIER_CONFIG_FILE").is_ok();
+    let user = user.map(PathBuf::from).unwrap_or_else(path);
+    if explicit || r.is_empty() || user.exists() {
+        r.push(user);
+    }
+    r
 }
 
 pub fn load() -> Result<Config, Error> {
-    let defaultfile = dirs::home_dir()
-        .unwrap_or("/root/".into())
-        .join(".devguard/carrier.toml");
-    let filename = env::var("CARRIER_CONFIG_FILE")
-        .map(|v| v.into())
-        .unwrap_or(defaultfile);
-
-    let mut buffer = String::default();
-    File::open(&filename)
-        .expect(&format!("cannot open config file {:?}", filename))
-        .read_to_string(&mut buffer)
-        .expect(&format!("cannot read config file {:?}", filename));
-    let mut config: ConfigToml =
-        toml::from_str(&buffer).expect(&format!("cannot open config file {:?}", filename));
-
-    let secret = config.secret()?;
+    load_from(None)
+}
+
+/// with user instead of the user's file, see files().
+/// fails with the first problem, the others are logged
+pub fn load_from(user: Option<&Path>) -> Result<Config, Error> {
+    load_with(user, None)
+}
+
+fn load_with(user: Option<&Path>, known: Option<&Config>) -> Result<Config, Error> {
+    let mut problems = match read(user, known) {
+        Ok(mut config) => {
+            config.user_file = user.map(PathBuf::from);
+            return Ok(config);
+        }
+        Err(problems) => problems,
+    };
+    for e in &problems[1..] {
+        error!("{}", e);
+    }
+    Err(problems.remove(0))
+}
+
+/// every problem with the config, the secret it points to included.
+/// empty when it is good to use
+pub fn check(user: Option<&Path>) -> Vec<Error> {
+    match read(user, None) {
+        Ok(_) => Vec::new(),
+        Err(problems) => problems,
+    }
+}
+
+fn read(user: Option<&Path>, known: Option<&Config>) -> Result<Config, Vec<Error>> {
+    let mut origins = Vec::new();
+    let mut problems = Vec::new();
+    for path in files(user) {
+        let name = path.to_string_lossy().into_owned();
+        let mut source = String::new();
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut source)) {
+            Ok(_) => origins.push(Origin::File { path: name, source }),
+            Err(e) => problems.push(Error::Config {
+                file:   name,
+                line:   None,
+                reason: e.to_string(),
+            }),
+        }
+    }
+    for (var, table, key) in OVERRIDES {
+        if let Ok(value) = env::var(var) {
+            origins.push(Origin::env(var, *table, key, &value));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    merge(origins, known)
+}
+
+fn merge(origins: Vec<Origin>, known: Option<&Config>) -> Result<Config, Vec<Error>> {
+    let mut config = ConfigToml::default();
+    let mut problems = Vec::new();
+    for (layer, origin) in origins.iter().enumerate() {
+        match toml::from_str::<ConfigToml>(origin.source()) {
+            Ok(mut v) => {
+                v.locate(layer);
+                config.merge(v);
+            }
+            Err(e) => {
+                let line = e.line_col().map(|(line, _)| line + 1);
+                let mut reason = e.to_string();
+                if let Some(line) = line {
+                    let suffix = format!(" at line {}", line);
+                    if reason.ends_with(&suffix) {
+                        let len = reason.len() - suffix.len();
+                        reason.truncate(len);
+                    }
+                }
+                problems.push(origin.problem(line, reason));
+            }
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    let mut p = Problems {
+        origins,
+        problems,
+    };
+
+    let secret = config.secret(&mut p, known);
+    let congestion = match config.congestion {
+        Some(ref v) => p.parse("congestion", v).unwrap_or_default(),
+        None => congestion::Algorithm::default(),
+    };
+    let mtu_floor = config.mtu_floor.unwrap_or(pmtu::MIN_PACKET_SIZE);
+    if mtu_floor < pmtu::MIN_PACKET_SIZE {
+        warn!("in config: mtu_floor {} is too small to fit a stream frame, using {}",
+              mtu_floor, pmtu::MIN_PACKET_SIZE);
+    }
+    let mtu_ceiling = config.mtu_ceiling.unwrap_or(channel::DEFAULT_MTU_CEILING);
+    if mtu_ceiling > channel::MAX_PACKET_SIZE {
+        warn!("in config: mtu_ceiling {} is bigger than the maximum packet size {}",
+              mtu_ceiling, channel::MAX_PACKET_SIZE);
+    }
+    let mut path_policy = paths::PathPolicy::default();
+    if let Some(ref paths) = config.paths {
+        path_policy.relay = paths.relay.unwrap_or(path_policy.relay);
+        path_policy.internet = paths.internet.unwrap_or(path_policy.internet);
+        path_policy.reevaluate = paths.reevaluate.unwrap_or(path_policy.reevaluate);
+    }
+
+    let mut reflectors = Vec::new();
+    for r in config.reflectors.take().unwrap_or_default() {
+        // port is optional
+        let parsed = r
+            .parse::<SocketAddr>()
+            .or_else(|_| format!("{}:{}", r, reflect::DEFAULT_PORT).parse::<SocketAddr>());
+        match parsed {
+            Ok(addr) => reflectors.push(addr),
+            Err(e) => warn!("in config: reflector '{}': {}", r, e),
+        }
+    }
+
+    let names = config.names(&mut p);
+    let peers = config.peers(&mut p, &names);
+    let successions = config.successions(&mut p);
+    let publish = config.publisher(&mut p, secret.as_ref().map(|s| s.identity()), &successions);
+
+    let secret = match secret {
+        Some(ref secret) if p.problems.is_empty() => secret.clone(),
+        _ => return Err(p.problems),
+    };
+    if successions.is_retired(&secret.identity()) {
+        warn!("in config: our own identity {} was succeeded by {}. run with the new secret",
+              secret.identity(), successions.current(&secret.identity()));
+    }
+
     Ok(Config {
-        publish:    config.publisher(secret.identity())?,
+        publish,
         secret,
+        secret_store: config.secret.take().map(|s| s.value),
         keepalive:  config.keepalive,
-        names:      config.names()?,
+        congestion,
+        pacing:     config.pacing.unwrap_or(false),
+        mtu_floor:  mtu_floor.max(pmtu::MIN_PACKET_SIZE),
+        mtu_ceiling: mtu_ceiling.min(channel::MAX_PACKET_SIZE),
+        paths:      path_policy,
+        reflectors,
+        lan:        config.lan.unwrap_or(false),
+        brokers:    config.brokers.unwrap_or(1).max(1),
+        names,
+        peers,
+        clock_skew: config.clock_skew.unwrap_or(timestamps::DEFAULT_SKEW),
+        successions,
+        user_file:  None,
     })
 }
 
 
 impl Config {
+    /// the config again, from the same files.
+    /// the secret is kept as it is and fails to reload when it points somewhere else
+    pub fn reload(&self) -> Result<Config, Error> {
+        load_with(self.user_file.as_ref().map(PathBuf::as_path), Some(self))
+    }
+
+    /// an identity by name or in its string form. a retired identity resolves to its successor
     pub fn resolve_identity<S: Into<String>>(&self, s:S) -> Result<identity::Identity, Error> {
         let s = s.into();
-        if let Some(v) = self.names.get(&s) {
-            return Ok(v.clone());
-        }
-        s.parse()
+        let identity = match self.names.get(&s) {
+            Some(v) => v.clone(),
+            None => s.parse()?,
+        };
+        Ok(self.successions.current(&identity))
+    }
+}
+
+#[test]
+fn every_problem_is_reported() {
+    let file = |source: &str| Origin::File { path: "carrier.toml".into(), source: source.into() };
+    let secret = identity::Secret::gen();
+    let source = format!(r#"
+secret = "{}"
+congestion = "vegas"
+
+[names]
+good = "{}"
+bad = "nobody"
+
+[publish]
+shadow = "{}"
+
+[[authorize]]
+identity = "{}"
+resource = "*"
+
+[[authorize]]
+identity = "nobody"
+resource = "*"
+"#, secret.to_string().unwrap(), secret.identity(), secret.identity(), secret.identity());
+
+    let problems = match merge(vec![file(&source)], None) {
+        Err(problems) => problems,
+        Ok(_) => panic!("expected problems"),
+    };
+    let lines: Vec<Option<usize>> = problems
+        .iter()
+        .map(|e| match e {
+            Error::Config { line, .. } => *line,
+            _ => panic!("{:?}", e),
+        })
+        .collect();
+    // the shadow is an identity, not an address
+    assert_eq!(lines, vec![Some(3), Some(7), Some(10), Some(17)]);
+    assert!(problems[0].to_string().starts_with("carrier.toml:3: congestion 'vegas': "));
+
+    let source = format!("secret = \"{}\"\n[names]\nme = \"{}\"\n", secret.to_string().unwrap(), secret.identity());
+    let config = merge(vec![file(&source)], None).unwrap();
+    assert_eq!(config.resolve_identity("me").unwrap(), secret.identity());
+
+    match merge(vec![file("secret = \n")], None).err().unwrap().remove(0) {
+        Error::Config { line: Some(1), .. } => (),
+        e => panic!("expected a syntax error on line 1, got {:?}", e),
     }
+    match merge(vec![file("")], None).err().unwrap().remove(0) {
+        Error::Config { line: None, .. } => (),
+        e => panic!("expected a missing secret, got {:?}", e),
+    }
+}
+
+#[test]
+fn layers() {
+    let file = |path: &str, source: String| Origin::File { path: path.into(), source };
+    let (secret, shadow) = (identity::Secret::gen(), identity::Secret::gen().address());
+    let (ops, dev, other) = (
+        identity::Secret::gen().identity(),
+        identity::Secret::gen().identity(),
+        identity::Secret::gen().identity(),
+    );
+
+    let system = file("/etc/carrier/carrier.toml", format!(r#"
+secret = "{}"
+congestion = "cubic"
+[paths]
+relay = false
+internet = false
+[publish]
+shadow = "{}"
+[[authorize]]
+identity = "{}"
+resource = "*"
+[names]
+ops = "{}"
+"#, secret.to_string().unwrap(), shadow, ops, ops));
+    let team = file("/etc/carrier/conf.d/10-dev.toml", format!(r#"
+[[authorize]]
+identity = "{}"
+resource = "/v0/shell"
+[names]
+dev = "{}"
+[publish]
+disable = ["/v0/sft"]
+"#, dev, dev));
+    let user = file("/root/.devguard/carrier.toml", format!(r#"
+congestion = "bbr"
+[paths]
+relay = true
+[names]
+ops = "{}"
+"#, other));
+
+    let config = merge(vec![system, team, user, Origin::env("CARRIER_BROKERS", None, "brokers", "3")], None).unwrap();
+    let auth = &config.publish.as_ref().unwrap().auth;
+    auth.check(&ops, &"/v0/sft".to_string(), &vec![]).unwrap();
+    auth.check(&dev, &"/v0/shell".to_string(), &vec![]).unwrap();
+    assert!(auth.check(&dev, &"/v0/sft".to_string(), &vec![]).is_err());
+    assert_eq!(config.resolve_identity("ops").unwrap(), other);
+    assert_eq!(config.resolve_identity("dev").unwrap(), dev);
+    assert!(config.paths.relay);
+    assert!(!config.paths.internet);
+    assert_eq!(config.brokers, 3);
+    assert_eq!(config.secret_store, Some(secret.to_string().unwrap()));
+    let publish = config.publish.as_ref().unwrap();
+    assert_eq!(publish.shadow, shadow);
+    assert!(publish.disabled.contains("/v0/sft"));
+    assert!(!publish.close_revoked);
+
+    // problems point at the layer the value came from
+    let config = |shadow: &str, team: &str| merge(vec![
+        file("/etc/carrier/carrier.toml", format!("secret = \"{}\"\n", secret.to_string().unwrap())),
+        file("/etc/carrier/conf.d/10-dev.toml", team.to_string()),
+        Origin::env("CARRIER_PUBLISH_SHADOW", Some("publish"), "shadow", shadow),
+    ], None);
+    let problems = config(&secret.identity().to_string(), "\n[[authorize]]\nidentity = \"x\"\nresource = \"*\"\n")
+        .err()
+        .unwrap();
+    let problems: Vec<String> = problems.iter().map(|e| e.to_string()).collect();
+    assert_eq!(problems.len(), 2);
+    assert!(problems[0].starts_with("$CARRIER_PUBLISH_SHADOW: shadow "));
+    assert!(problems[1].starts_with("/etc/carrier/conf.d/10-dev.toml:3: authorize 'x': "));
+
+    // quotes in a variable can't escape the string
+    let problems = config("\"\nsecret = \"", "").err().unwrap();
+    assert!(problems[0].to_string().starts_with("$CARRIER_PUBLISH_SHADOW: shadow '\"\nsecret = \"': "));
+}
+
+#[test]
+fn reload_keeps_the_secret() {
+    let file = |source: &str| Origin::File { path: "carrier.toml".into(), source: source.into() };
+    let secret = identity::Secret::gen();
+    let source = format!("secret = \"{}\"\n", secret.to_string().unwrap());
+    let mut known = merge(vec![file(&source)], None).unwrap();
+
+    // the store isn't opened again
+    known.secret_store = Some("file:/nonexistent/carrier.secret".into());
+    let config = merge(vec![file("secret = \"file:/nonexistent/carrier.secret\"\n")], Some(&known)).unwrap();
+    assert_eq!(config.secret.identity(), secret.identity());
+
+    let problems = merge(vec![file(&source)], Some(&known)).err().unwrap();
+    assert_eq!(problems[0].to_string(), "carrier.toml:1: secret: changed, which needs a restart");
 }
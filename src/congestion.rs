@@ -0,0 +1,410 @@
+//! congestion controllers used by recovery::QuicRecovery
+//!
+//! the controller only decides how big the congestion window is.
+//! loss detection and bytes in flight accounting stay in recovery.
+
+use error::Error;
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+// 4.8.1.  Congestion Control Settings
+
+/// The max packet size is used for calculating initial and minimum congestion windows.
+pub const INITIAL_MSS: u64 = 1460;
+
+/// Limit on the initial amount of outstanding data in bytes.
+pub const INITIAL_WINDOW: u64 = 10 * INITIAL_MSS;
+
+/// Minimum congestion window in bytes.
+pub const MINIMUM_WINDOW: u64 = 2 * INITIAL_MSS;
+
+/// Reduction in congestion window when a new loss event is detected.
+const LOSS_REDUCTION_FACTOR: f64 = 0.5;
+
+/// RTT measurements as seen by recovery at the time of an ack. all in ms.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rtt {
+    pub latest: u64,
+    pub smoothed: u64,
+    /// zero if there is no measurement yet
+    pub min: u64,
+}
+
+pub trait CongestionController {
+    /// a packet with retransmittable frames was sent
+    fn on_packet_sent(&mut self, _bytes: usize, _now: u64) {}
+
+    /// a packet with retransmittable frames was acked.
+    /// in_recovery is true if the packet was sent before the last congestion event
+    fn on_packet_acked(&mut self, bytes: usize, in_recovery: bool, rtt: Rtt, now: u64);
+
+    /// packets were lost and we're not already in recovery
+    fn on_congestion_event(&mut self, now: u64);
+
+    /// 4.8.9.  On Retransmission Timeout Verified
+    fn on_retransmission_timeout_verified(&mut self);
+
+    /// current congestion window in bytes
+    fn window(&self) -> u64;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    NewReno,
+    Cubic,
+    Bbr,
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Algorithm::NewReno
+    }
+}
+
+impl Algorithm {
+    pub fn controller(&self) -> Box<CongestionController> {
+        match self {
+            Algorithm::NewReno => Box::new(NewReno::new()),
+            Algorithm::Cubic => Box::new(Cubic::new()),
+            Algorithm::Bbr => Box::new(Bbr::new()),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "newreno" | "reno" => Ok(Algorithm::NewReno),
+            "cubic" => Ok(Algorithm::Cubic),
+            "bbr" => Ok(Algorithm::Bbr),
+            _ => Err(Error::InvalidCongestionController { name: s.to_string() }),
+        }
+    }
+}
+
+// -- NewReno
+
+/// draft-ietf-quic-recovery-13 section 4.8
+pub struct NewReno {
+    /// Maximum number of bytes-in-flight that may be sent.
+    congestion_window: u64,
+
+    /// Slow start threshold in bytes.  When the congestion window
+    /// is below ssthresh, the mode is slow start and the window grows by
+    /// the number of bytes acknowledged.
+    ssthresh: u64,
+}
+
+impl NewReno {
+    pub fn new() -> Self {
+        Self {
+            congestion_window: INITIAL_WINDOW,
+            ssthresh: <u64>::max_value(),
+        }
+    }
+}
+
+impl CongestionController for NewReno {
+    /// 4.8.5. Congestion Control
+    fn on_packet_acked(&mut self, bytes: usize, in_recovery: bool, _: Rtt, _: u64) {
+        if in_recovery {
+            // Do not increase congestion window in recovery period.
+            return;
+        }
+
+        if self.congestion_window < self.ssthresh {
+            // Slow start.
+            self.congestion_window += bytes as u64;
+        } else {
+            // Congestion avoidance.
+            self.congestion_window += INITIAL_MSS * bytes as u64 / self.congestion_window;
+        }
+    }
+
+    /// 4.8.6 On New Congestion Event
+    fn on_congestion_event(&mut self, _: u64) {
+        self.congestion_window = (self.congestion_window as f64 * LOSS_REDUCTION_FACTOR) as u64;
+        self.congestion_window = max(self.congestion_window, MINIMUM_WINDOW);
+        self.ssthresh = self.congestion_window
+    }
+
+    // QUIC decreases the congestion window to the minimum value once the
+    // retransmission timeout has been verified.
+    fn on_retransmission_timeout_verified(&mut self) {
+        self.congestion_window = MINIMUM_WINDOW;
+    }
+
+    fn window(&self) -> u64 {
+        self.congestion_window
+    }
+}
+
+// -- Cubic
+
+/// RFC8312 scaling constant, in segments per second^3
+const CUBIC_C: f64 = 0.4;
+
+/// RFC8312 multiplicative decrease factor
+const CUBIC_BETA: f64 = 0.7;
+
+/// RFC8312 CUBIC, with fast convergence and the TCP friendly region
+pub struct Cubic {
+    congestion_window: u64,
+    ssthresh: u64,
+
+    /// window size in segments just before the last reduction
+    w_max: f64,
+
+    /// w_max before the previous reduction, for fast convergence
+    w_last_max: f64,
+
+    /// time period in seconds for the window to grow back to w_max
+    k: f64,
+
+    /// beginning of the current avoidance epoch
+    epoch_start: Option<u64>,
+
+    /// estimated window of a reno flow in segments, for the TCP friendly region
+    w_est: f64,
+}
+
+impl Cubic {
+    pub fn new() -> Self {
+        Self {
+            congestion_window: INITIAL_WINDOW,
+            ssthresh: <u64>::max_value(),
+            w_max: 0.0,
+            w_last_max: 0.0,
+            k: 0.0,
+            epoch_start: None,
+            w_est: 0.0,
+        }
+    }
+
+    fn segments(&self) -> f64 {
+        self.congestion_window as f64 / INITIAL_MSS as f64
+    }
+}
+
+impl CongestionController for Cubic {
+    fn on_packet_acked(&mut self, bytes: usize, in_recovery: bool, rtt: Rtt, now: u64) {
+        if in_recovery {
+            return;
+        }
+
+        if self.congestion_window < self.ssthresh {
+            self.congestion_window += bytes as u64;
+            return;
+        }
+
+        let cwnd = self.segments();
+        let epoch_start = match self.epoch_start {
+            Some(v) => v,
+            None => {
+                if cwnd < self.w_max {
+                    self.k = ((self.w_max - cwnd) / CUBIC_C).cbrt();
+                } else {
+                    self.k = 0.0;
+                    self.w_max = cwnd;
+                }
+                self.w_est = cwnd;
+                self.epoch_start = Some(now);
+                now
+            }
+        };
+
+        let rtt_ms = max(1, if rtt.min > 0 { rtt.min } else { rtt.smoothed });
+        let t = (now - epoch_start + rtt_ms) as f64 / 1000.0;
+        let target = CUBIC_C * (t - self.k).powi(3) + self.w_max;
+
+        // TCP friendly region
+        let acked = bytes as f64 / INITIAL_MSS as f64;
+        self.w_est += 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA) * acked / cwnd;
+
+        let next = if self.w_est > target {
+            self.w_est
+        } else {
+            target
+        };
+
+        if next > cwnd {
+            // never grow faster than slow start would
+            let inc = ((next - cwnd) / cwnd * bytes as f64).min(bytes as f64);
+            self.congestion_window += inc as u64;
+        }
+    }
+
+    fn on_congestion_event(&mut self, _: u64) {
+        let cwnd = self.segments();
+        self.epoch_start = None;
+
+        if cwnd < self.w_last_max {
+            // fast convergence
+            self.w_last_max = cwnd;
+            self.w_max = cwnd * (1.0 + CUBIC_BETA) / 2.0;
+        } else {
+            self.w_last_max = cwnd;
+            self.w_max = cwnd;
+        }
+
+        self.congestion_window = (self.congestion_window as f64 * CUBIC_BETA) as u64;
+        self.congestion_window = max(self.congestion_window, MINIMUM_WINDOW);
+        self.ssthresh = self.congestion_window;
+    }
+
+    fn on_retransmission_timeout_verified(&mut self) {
+        self.epoch_start = None;
+        self.congestion_window = MINIMUM_WINDOW;
+    }
+
+    fn window(&self) -> u64 {
+        self.congestion_window
+    }
+}
+
+// -- BBR
+
+/// number of bandwidth samples (one per round trip) kept in the max filter
+const BBR_BW_WINDOW: usize = 10;
+
+/// window gain over the estimated bandwidth delay product
+const BBR_CWND_GAIN: f64 = 2.0;
+
+/// startup ends when the bandwidth didn't grow by this factor ...
+const BBR_STARTUP_GROWTH: f64 = 1.25;
+
+/// ... for this many rounds
+const BBR_STARTUP_ROUNDS: u16 = 3;
+
+/// a simplified BBR.
+///
+/// bandwidth is sampled once per round trip from the bytes acked in that round,
+/// and the window follows the bandwidth delay product instead of reacting to loss.
+/// loss only ends startup.
+pub struct Bbr {
+    congestion_window: u64,
+
+    /// true until the bandwidth estimate stops growing
+    startup: bool,
+    full_bw: f64,
+    full_bw_rounds: u16,
+
+    /// bandwidth samples in bytes per ms, one per round
+    bw_samples: VecDeque<f64>,
+
+    round_start: Option<u64>,
+    round_delivered: u64,
+
+    min_rtt: u64,
+}
+
+impl Bbr {
+    pub fn new() -> Self {
+        Self {
+            congestion_window: INITIAL_WINDOW,
+            startup: true,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+            bw_samples: VecDeque::new(),
+            round_start: None,
+            round_delivered: 0,
+            min_rtt: 0,
+        }
+    }
+
+    fn btl_bw(&self) -> f64 {
+        self.bw_samples.iter().cloned().fold(0.0, f64::max)
+    }
+
+    fn bdp(&self) -> u64 {
+        (self.btl_bw() * self.min_rtt as f64) as u64
+    }
+
+    fn on_round_end(&mut self) {
+        let bw = self.btl_bw();
+        if !self.startup {
+            return;
+        }
+        if bw >= self.full_bw * BBR_STARTUP_GROWTH {
+            self.full_bw = bw;
+            self.full_bw_rounds = 0;
+        } else {
+            self.full_bw_rounds += 1;
+            if self.full_bw_rounds >= BBR_STARTUP_ROUNDS {
+                trace!("bbr: leaving startup at {} bytes/ms", bw);
+                self.startup = false;
+            }
+        }
+    }
+}
+
+impl CongestionController for Bbr {
+    fn on_packet_acked(&mut self, bytes: usize, _in_recovery: bool, rtt: Rtt, now: u64) {
+        if rtt.min > 0 {
+            self.min_rtt = rtt.min;
+        }
+
+        let round_start = *self.round_start.get_or_insert(now);
+        self.round_delivered += bytes as u64;
+
+        if self.min_rtt > 0 && now >= round_start + self.min_rtt {
+            let sample = self.round_delivered as f64 / (now - round_start) as f64;
+            self.bw_samples.push_back(sample);
+            if self.bw_samples.len() > BBR_BW_WINDOW {
+                self.bw_samples.pop_front();
+            }
+            self.round_start = Some(now);
+            self.round_delivered = 0;
+            self.on_round_end();
+        }
+
+        if self.startup {
+            self.congestion_window += bytes as u64;
+        } else {
+            let target = (self.bdp() as f64 * BBR_CWND_GAIN) as u64;
+            self.congestion_window = max(target, MINIMUM_WINDOW);
+        }
+    }
+
+    fn on_congestion_event(&mut self, _: u64) {
+        if self.startup {
+            self.startup = false;
+            let target = (self.bdp() as f64 * BBR_CWND_GAIN) as u64;
+            self.congestion_window = max(target, MINIMUM_WINDOW);
+        }
+    }
+
+    fn on_retransmission_timeout_verified(&mut self) {
+        self.congestion_window = MINIMUM_WINDOW;
+    }
+
+    fn window(&self) -> u64 {
+        self.congestion_window
+    }
+}
+
+#[test]
+fn parse_algorithm() {
+    assert_eq!("newreno".parse::<Algorithm>().unwrap(), Algorithm::NewReno);
+    assert_eq!("Cubic".parse::<Algorithm>().unwrap(), Algorithm::Cubic);
+    assert_eq!("bbr".parse::<Algorithm>().unwrap(), Algorithm::Bbr);
+    assert!("vegas".parse::<Algorithm>().is_err());
+}
+
+#[test]
+fn newreno_halves_on_loss() {
+    let mut cc = NewReno::new();
+    cc.on_congestion_event(0);
+    assert_eq!(cc.window(), INITIAL_WINDOW / 2);
+    cc.on_packet_acked(1000, true, Rtt::default(), 0);
+    assert_eq!(cc.window(), INITIAL_WINDOW / 2, "no growth in recovery");
+}
+
+#[test]
+fn cubic_reduces_less_than_newreno() {
+    let mut cc = Cubic::new();
+    cc.on_congestion_event(0);
+    assert_eq!(cc.window(), (INITIAL_WINDOW as f64 * CUBIC_BETA) as u64);
+}
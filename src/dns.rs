@@ -1,5 +1,11 @@
+use dirs;
+use error::Error;
 use identity::{Address, Secret, Signature};
+use rand;
+use std::fs::{create_dir_all, rename, File};
+use std::io::{Read, Write};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(Clone, Debug)]
 pub struct DnsRecord {
@@ -10,7 +16,7 @@ pub struct DnsRecord {
 }
 
 impl DnsRecord {
-    pub fn to_signed_txt(&self, sign: &Secret) -> String {
+    pub fn to_signed_txt(&self, sign: &Secret) -> Result<String, Error> {
         let txt = format!(
             "carrier=3 c={} p={} n={} x={}",
             self.epoch,
@@ -18,8 +24,8 @@ impl DnsRecord {
             self.addr,
             self.x.to_string()
         );
-        let sig = sign.sign(b"carrier dns record", txt.as_bytes());
-        format!("{} {}", txt, sig.to_string())
+        let sig = sign.sign(b"carrier dns record", txt.as_bytes())?;
+        Ok(format!("{} {}", txt, sig.to_string()))
     }
 
     pub fn from_signed_txt<S: AsRef<str>>(s: S) -> Option<Self> {
@@ -88,3 +94,78 @@ impl DnsRecord {
         }
     }
 }
+
+fn cache_path() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or(PathBuf::from("/"))
+        .join(".devguard/brokers")
+}
+
+/// brokers that won the last connect, best first
+pub fn load_cache() -> Vec<SocketAddr> {
+    let mut b = String::new();
+    match File::open(cache_path()).and_then(|mut f| f.read_to_string(&mut b)) {
+        Ok(_) => b.lines().filter_map(|l| l.trim().parse().ok()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn store_cache(addrs: &[SocketAddr]) -> Result<(), String> {
+    let path = cache_path();
+    let dir = path.parent().unwrap();
+    create_dir_all(&dir).map_err(|e| e.to_string())?;
+
+    let r: u64 = rand::random();
+    let path2 = dir.join(format!("brokers{}", r));
+    let mut f = File::create(&path2).map_err(|e| e.to_string())?;
+    for addr in addrs {
+        writeln!(f, "{}", addr).map_err(|e| e.to_string())?;
+    }
+    f.sync_all().map_err(|e| e.to_string())?;
+    rename(&path2, &path).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// the order to try brokers in: cached winners first, then by priority, lowest first.
+/// records are expected to be shuffled already, so equal priorities share the load
+pub fn order(mut records: Vec<DnsRecord>, cached: &[SocketAddr]) -> Vec<DnsRecord> {
+    records.sort_by_key(|r| {
+        let cache_rank = cached
+            .iter()
+            .position(|a| *a == r.addr)
+            .unwrap_or(cached.len());
+        (cache_rank, r.priority)
+    });
+    records
+}
+
+#[test]
+fn broker_order() {
+    let record = |addr: &str, priority| DnsRecord {
+        priority,
+        addr: addr.parse().unwrap(),
+        x: Secret::gen().address(),
+        epoch: 1,
+    };
+    let records = vec![
+        record("192.0.2.1:8443", 2),
+        record("192.0.2.2:8443", 1),
+        record("192.0.2.3:8443", 2),
+        record("192.0.2.4:8443", 0),
+    ];
+
+    let ordered: Vec<SocketAddr> = order(records.clone(), &[]).iter().map(|r| r.addr).collect();
+    let expect: Vec<SocketAddr> = vec![
+        "192.0.2.4:8443".parse().unwrap(),
+        "192.0.2.2:8443".parse().unwrap(),
+        "192.0.2.1:8443".parse().unwrap(),
+        "192.0.2.3:8443".parse().unwrap(),
+    ];
+    assert_eq!(ordered, expect);
+
+    // a cached winner goes first, even with a worse priority. unknown cache entries are ignored
+    let cached = vec!["198.51.100.1:8443".parse().unwrap(), "192.0.2.3:8443".parse().unwrap()];
+    let ordered: Vec<SocketAddr> = order(records, &cached).iter().map(|r| r.addr).collect();
+    assert_eq!(ordered[0], "192.0.2.3:8443".parse::<SocketAddr>().unwrap());
+    assert_eq!(ordered[1], "192.0.2.4:8443".parse::<SocketAddr>().unwrap());
+}
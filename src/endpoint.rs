@@ -1,29 +1,63 @@
-use channel::{Channel, ChannelProgress, MAX_PACKET_SIZE};
+use channel::{self, Channel, ChannelProgress, MAX_PACKET_SIZE};
 use clock;
 use config;
 use dns;
 use error::Error;
+use handle::{self, Command, Handle, Notice};
 use headers::Headers;
 use identity::{self, Identity};
+use lan;
+use mio_extras::channel as mio_channel;
 use local_addrs;
 use noise;
-use osaka::mio::net::UdpSocket;
+use socket::Socket;
+use timestamps::{self, Timestamps};
 use osaka::{osaka, FutureResult};
-use packet::{EncryptedPacket, RoutingKey};
+use packet::{EncryptedPacket, Frame, RoutingKey};
+use paths::{Paths, PathPolicy, MAX_PATH_CHALLENGES, PATH_CHALLENGE_TIMEOUT};
 use prost::Message;
 use proto;
+use reflect;
 use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
+use std::sync::mpsc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use util::defer;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use osaka::Future;
 use std::mem;
+use rand;
+
+/// how often to refresh the reflexive address from configured reflectors, in seconds
+const REFLECT_INTERVAL: u64 = 300;
+
+/// how long to wait for a reflector to answer before asking again, in ms
+const REFLECT_RETRY: u64 = 2000;
+
+/// how long to wait for a broker to answer before also trying the next one, in ms
+const BROKER_STAGGER: u64 = 250;
+
+/// how long a shutdown waits for data in flight, in seconds
+pub const SHUTDOWN_TIMEOUT: u64 = 3;
+
+/// how often a direct handshake is sent before giving up
+const DIRECT_CONNECT_ATTEMPTS: u32 = 4;
+
+/// how often a broker handshake is sent before trying the next broker
+const BROKER_ATTEMPTS: u32 = 4;
+
+/// when replacing lost brokers and none are left to try, how long until resolving again, in seconds
+const RESOLVE_INTERVAL: u64 = 60;
+
+/// an unvalidated address receives at most this many times the bytes it sent us,
+/// so a spoofed source address can't be used to reflect traffic at a victim
+const AMPLIFICATION_FACTOR: usize = 3;
 
 #[derive(Clone)]
 pub struct Stream {
@@ -34,6 +68,10 @@ pub struct Stream {
 }
 
 impl Stream {
+    pub fn id(&self) -> u32 {
+        self.stream
+    }
+
     pub fn send<M: Into<Vec<u8>>>(&mut self, m: M) {
         self.inner
             .try_borrow_mut()
@@ -86,19 +124,64 @@ struct StreamReceiver {
 }
 
 enum AddressMode {
-    Discovering(HashMap<SocketAddr, (proto::path::Category, usize)>),
-    Established(
-        SocketAddr,
-        HashMap<SocketAddr, (proto::path::Category, usize)>,
-    ),
+    Discovering(Paths),
+    Established(SocketAddr, Paths),
+}
+
+/// where packets of a channel are currently sent to
+#[derive(Clone, Debug)]
+pub enum Path {
+    /// no path settled yet, sending to every candidate
+    Discovering(Vec<(SocketAddr, proto::path::Category)>),
+    /// the category is None if it's unknown, like for a direct connect to a given address
+    Established(SocketAddr, Option<proto::path::Category>),
+}
+
+impl AddressMode {
+    fn path(&self) -> Path {
+        match self {
+            AddressMode::Discovering(paths) => Path::Discovering(paths.usable()),
+            AddressMode::Established(addr, paths) => Path::Established(*addr, paths.category(addr)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub identity: Identity,
+    pub path: Path,
+    pub channel: channel::Stats,
+}
+
+/// the peer showed up from a new address, which has not answered a path challenge yet
+struct Migration {
+    /// the new, unvalidated address
+    addr:           SocketAddr,
+    /// last validated address, used again when validation fails
+    previous:       SocketAddr,
+    challenge:      u64,
+    attempts:       u16,
+    sent_at:        Instant,
+    bytes_received: usize,
+    bytes_sent:     usize,
+}
+
+impl Migration {
+    fn may_send(&self, len: usize) -> bool {
+        self.bytes_sent + len <= AMPLIFICATION_FACTOR * self.bytes_received
+    }
 }
 
 struct UdpChannel {
     identity:   Identity,
     chan:       Arc<RefCell<Channel>>,
     addrs:      AddressMode,
+    migration:  Option<Migration>,
     streams:    HashMap<u32, StreamReceiver>,
     newhandl:   Option<Box<StreamFactory>>,
+    /// timestamp and response of a direct handshake we accepted,
+    /// resent if the initiator retransmits before the channel is used
+    direct_response: Option<(u64, Vec<u8>)>,
 }
 
 impl Drop for UdpChannel {
@@ -111,12 +194,47 @@ pub struct Endpoint {
     poll:               osaka::Poll,
     token:              osaka::Token,
     channels:           HashMap<RoutingKey, UdpChannel>,
-    socket:             UdpSocket,
-    broker_route:       RoutingKey,
+    socket:             Socket,
+    /// channels to brokers, in the order they were connected
+    brokers:            Vec<RoutingKey>,
+    /// how many brokers to keep. lost ones are replaced up to this
+    wanted_brokers:     usize,
+    /// brokers from dns that we haven't tried yet, best first
+    spare_brokers:      VecDeque<dns::DnsRecord>,
+    /// handshakes with brokers that replace lost ones
+    broker_probes:      HashMap<SocketAddr, BrokerProbe>,
+    resolver:           Option<osaka::Task<Result<Vec<dns::DnsRecord>, Error>>>,
+    next_resolve:       Instant,
     secret:             identity::Secret,
-    outstanding_connect_incomming: HashSet<u32>,
-    outstanding_connect_outgoing:  HashMap<u32, ConnectResponseStage>,
+    outstanding_connect_incomming: HashSet<(RoutingKey, u32)>,
+    outstanding_connect_outgoing:  HashMap<(RoutingKey, u32), ConnectResponseStage>,
+    /// connect streams on other brokers that lost the race, to be closed
+    cancelled_connects: Vec<(RoutingKey, u32)>,
+    /// connects that lost the race and may still succeed. the peer is told to drop those
+    losing_connects:    HashMap<(RoutingKey, u32), ConnectResponseStage>,
     publish_secret:     Option<identity::Secret>,
+    settings:           channel::Settings,
+    policy:             PathPolicy,
+    reflectors:         Vec<SocketAddr>,
+    reflect_txid:       u64,
+    next_reflect:       Instant,
+    /// our address as seen from outside, by the broker or a reflector
+    reflexive:          Arc<Cell<Option<SocketAddr>>>,
+    outstanding_connect_direct: HashMap<SocketAddr, DirectConnect>,
+    lan:                bool,
+    /// what we publish, once published
+    shadow:             Option<identity::Address>,
+    /// the publish stream on each broker. closing it unpublishes
+    published:          Vec<(RoutingKey, u32)>,
+    next_announce:      Instant,
+    /// commands from Handles on other threads, once a handle was made
+    commands:           Option<(mio_channel::Receiver<Command>, osaka::Token)>,
+    handle:             Option<Handle>,
+    subscribers:        Vec<mpsc::Sender<Notice>>,
+    /// static keys of peers, for mutual handshakes with them
+    peers:              HashMap<identity::Identity, identity::Address>,
+    /// the last handshake from each peer, so they can't be replayed
+    timestamps:         Timestamps,
 }
 
 pub struct ConnectRequest {
@@ -124,6 +242,10 @@ pub struct ConnectRequest {
     pub identity: identity::Identity,
     pub responder: noise::HandshakeResponder,
     pub cr: proto::PeerConnectRequest,
+    /// the broker that relayed the request
+    pub broker: Option<RoutingKey>,
+    /// where the peer is, if it handshaked with us directly instead of through a broker
+    pub direct: Option<SocketAddr>,
 }
 
 
@@ -139,10 +261,92 @@ enum ConnectResponseStage {
     },
 }
 
+impl ConnectResponseStage {
+    fn identity(&self) -> &identity::Identity {
+        match self {
+            ConnectResponseStage::WaitingForHeaders{identity, ..}  => identity,
+            ConnectResponseStage::WaitingForResponse{identity, ..} => identity,
+        }
+    }
+}
+
+/// one broker answered a connect that was sent through all of them.
+/// the first success wins and the others are cancelled, but kept in losing until they answer.
+/// a failure only counts once every broker failed
+fn settle_connect(
+    outstanding: &mut HashMap<(RoutingKey, u32), ConnectResponseStage>,
+    cancelled: &mut Vec<(RoutingKey, u32)>,
+    losing: &mut HashMap<(RoutingKey, u32), ConnectResponseStage>,
+    q: ConnectResponse,
+) -> Option<ConnectResponse> {
+    let others: Vec<(RoutingKey, u32)> = outstanding
+        .iter()
+        .filter(|(_, stage)| stage.identity() == &q.identity)
+        .map(|(k, _)| *k)
+        .collect();
+
+    let ok = q.cr.as_ref().map(|cr| cr.ok).unwrap_or(false);
+    if ok {
+        for k in others {
+            if let Some(stage) = outstanding.remove(&k) {
+                losing.insert(k, stage);
+            }
+            cancelled.push(k);
+        }
+        Some(q)
+    } else if others.is_empty() {
+        Some(q)
+    } else {
+        debug!("connect to {} failed on one broker, {} still trying", q.identity, others.len());
+        None
+    }
+}
+
+/// a connect that lost the race succeeded anyway, and the peer set up a channel for it.
+/// returns the disconnect that makes it drop the channel now rather than at its idle timeout
+fn disconnect_loser(
+    mut noise: noise::HandshakeRequester,
+    frame: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    let cr = proto::ConnectResponse::decode(frame)?;
+    if !cr.ok {
+        return Ok(None);
+    }
+    noise.recv_response(EncryptedPacket::decode(&cr.handshake)?)?;
+    let mut noise = noise.into_transport()?;
+    let mut pkt = Vec::new();
+    Frame::Disconnect.encode(noise.version(), &mut pkt)?;
+    Ok(Some(noise.send(&pkt)?.encode()))
+}
+
 pub struct ConnectResponse {
     pub identity:   identity::Identity,
     pub cr:         Option<proto::ConnectResponse>,
     pub requester:  Option<noise::HandshakeRequester>,
+    /// the broker that relayed the response
+    pub broker:     Option<RoutingKey>,
+    /// where the peer is, if this was a connect_direct
+    pub direct:     Option<SocketAddr>,
+}
+
+/// a broker handshake in flight
+struct BrokerProbe {
+    noise:      noise::HandshakeRequester,
+    pkt:        Vec<u8>,
+    /// the first send, which the rtt is measured from. an answer may be to any of the sends,
+    /// so a broker that needed retransmits counts as far away
+    first_sent: Instant,
+    sent_at:    Instant,
+    attempts:   u32,
+}
+
+/// a handshake sent with connect_direct, waiting for the response
+struct DirectConnect {
+    identity:   identity::Identity,
+    noise:      noise::HandshakeRequester,
+    pkt:        Vec<u8>,
+    attempts:   u32,
+    sent_at:    Instant,
 }
 
 impl Endpoint {
@@ -151,51 +355,474 @@ impl Endpoint {
         token: osaka::Token,
         noise: noise::Transport,
         identity: identity::Identity,
-        socket: UdpSocket,
+        socket: Socket,
         addr: SocketAddr,
         secret: identity::Secret,
+        settings: channel::Settings,
+        policy: PathPolicy,
+        reflectors: Vec<SocketAddr>,
+        lan: bool,
     ) -> Self {
+        let mut ep = Self::direct(poll, token, socket, secret, settings, policy, reflectors, lan);
+        ep.add_broker(noise, identity, addr);
+        ep
+    }
+
+    /// keep a channel to another broker. published shadows are published there too,
+    /// and connects race across all brokers
+    pub fn add_broker(&mut self, noise: noise::Transport, identity: identity::Identity, addr: SocketAddr) {
         let broker_route = noise.route();
-        let mut channels = HashMap::new();
         let debug_id = format!("{}::{}", broker_route, identity);
-        channels.insert(
-            noise.route(),
+        info!("using broker {} at {}", identity, addr);
+        let chan = self.new_channel(noise, debug_id);
+        self.channels.insert(
+            broker_route,
             UdpChannel {
                 identity,
-                chan:       Arc::new(RefCell::new(Channel::new(noise, debug_id))),
-                addrs:      AddressMode::Established(addr, HashMap::new()),
+                chan,
+                addrs:      AddressMode::Established(
+                    addr,
+                    Paths::fixed(Some(proto::path::Category::BrokerOrigin), self.policy, Instant::now()),
+                ),
+                migration:  None,
                 streams:    HashMap::new(),
                 newhandl:   None,
+                direct_response: None,
             },
         );
+        self.brokers.push(broker_route);
+        if self.brokers.len() == 1 {
+            self.reflect_broker();
+        }
+        if let Some(shadow) = self.shadow.clone() {
+            self.publish_on(broker_route, shadow);
+        }
+    }
 
+    /// an endpoint without a broker.
+    /// it reaches peers with connect_direct, and is reached by peers that know its address,
+    /// for example from a lan announcement.
+    pub fn direct(
+        poll: osaka::Poll,
+        token: osaka::Token,
+        socket: Socket,
+        secret: identity::Secret,
+        settings: channel::Settings,
+        policy: PathPolicy,
+        reflectors: Vec<SocketAddr>,
+        lan: bool,
+    ) -> Self {
         Self {
             poll,
             token,
-            channels,
+            channels: HashMap::new(),
             socket,
-            broker_route,
+            brokers: Vec::new(),
+            wanted_brokers: 0,
+            spare_brokers: VecDeque::new(),
+            broker_probes: HashMap::new(),
+            resolver: None,
+            next_resolve: Instant::now(),
             secret,
             outstanding_connect_incomming: HashSet::new(),
             outstanding_connect_outgoing: HashMap::new(),
+            cancelled_connects: Vec::new(),
+            losing_connects: HashMap::new(),
             publish_secret: None,
+            settings,
+            policy,
+            reflectors,
+            reflect_txid: 0,
+            next_reflect: Instant::now(),
+            reflexive: Arc::new(Cell::new(None)),
+            outstanding_connect_direct: HashMap::new(),
+            lan,
+            shadow: None,
+            published: Vec::new(),
+            next_announce: Instant::now(),
+            commands: None,
+            handle: None,
+            subscribers: Vec::new(),
+            peers: HashMap::new(),
+            timestamps: Timestamps::load(timestamps::DEFAULT_SKEW),
+        }
+    }
+
+    /// how far off our clock, in seconds, the timestamp of a peer's handshake may be.
+    /// 0 accepts any timestamp that is newer than the peer's last one
+    pub fn set_clock_skew(&mut self, seconds: u64) {
+        self.timestamps.set_skew(seconds);
+    }
+
+    /// handshake mutually with identity from now on.
+    /// address is the static key of its secret, see Secret::address
+    pub fn add_peer(&mut self, identity: identity::Identity, address: identity::Address) {
+        self.peers.insert(identity, address);
+    }
+
+    /// the first handshake message to target, mutual if we know its static key
+    fn initiate(&self, target: &identity::Identity, timestamp: u64)
+        -> Result<(noise::HandshakeRequester, EncryptedPacket), Error>
+    {
+        match self.peers.get(target) {
+            Some(address) => noise::initiate_mutual(address, &self.secret, timestamp),
+            None => noise::initiate(None, &self.secret, timestamp),
+        }
+    }
+
+    /// a handle to this endpoint that can be moved to other threads.
+    /// the endpoint still has to be polled on this one
+    pub fn handle(&mut self) -> Handle {
+        if let Some(ref handle) = self.handle {
+            return handle.clone();
+        }
+        let (tx, rx) = mio_channel::channel();
+        let token = self
+            .poll
+            .register(&rx, mio::Ready::readable(), mio::PollOpt::level())
+            .unwrap();
+        let handle = Handle::new(tx);
+        self.commands = Some((rx, token));
+        self.handle = Some(handle.clone());
+        handle
+    }
+
+    /// our address as seen from outside, if known
+    pub fn reflexive(&self) -> Option<SocketAddr> {
+        self.reflexive.get()
+    }
+
+    /// paths we can be reached on, for the peer to probe
+    fn my_paths(&self) -> Vec<proto::Path> {
+        let mut local = local_addrs::get(self.socket.local_addr().unwrap().port());
+        local.retain(|addr| self.socket.supports(addr));
+        let mut mypaths: Vec<proto::Path> = local
+            .iter()
+            .map(|addr| {
+                // the scope is an interface index on this host, meaningless to the peer
+                let mut addr = *addr;
+                if let SocketAddr::V6(ref mut v6) = addr {
+                    v6.set_scope_id(0);
+                }
+                addr
+            })
+            .map(|addr| proto::Path {
+                category: (proto::path::Category::Local as i32),
+                ipaddr: format!("{}", addr),
+            })
+            .collect();
+        if let Some(addr) = self.reflexive.get() {
+            if !local.contains(&addr) {
+                mypaths.push(proto::Path {
+                    category: (proto::path::Category::Internet as i32),
+                    ipaddr: format!("{}", addr),
+                });
+            }
         }
+        mypaths
     }
 
+    /// ask the broker which address it sees us coming from.
+    /// brokers that don't know this request just answer with an error
+    fn reflect_broker(&mut self) {
+        let reflexive = self.reflexive.clone();
+        let broker = match self.brokers.first() {
+            Some(v) => *v,
+            None => return,
+        };
+        self.open(
+            broker,
+            Headers::with_path("/carrier.broker.v1/broker/reflect"),
+            move |poll, stream| Self::reflect_stream(poll, stream, reflexive),
+        );
+    }
+
+    #[osaka]
+    fn reflect_stream(_poll: osaka::Poll, mut stream: Stream, reflexive: Arc<Cell<Option<SocketAddr>>>) {
+        let m = osaka::sync!(stream);
+        let headers = match Headers::decode(&m) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("broker reflect: {}", e);
+                return;
+            }
+        };
+        if headers.get(b":status") != Some(&b"200"[..]) {
+            debug!("broker can't reflect our address: {:?}", headers);
+            return;
+        }
+
+        let m = osaka::sync!(stream);
+        match proto::Reflection::decode(&m).map(|r| r.ipaddr.parse::<SocketAddr>()) {
+            Ok(Ok(addr)) => {
+                if reflexive.get() != Some(addr) {
+                    info!("broker sees us as {}", addr);
+                }
+                reflexive.set(Some(addr));
+            }
+            Ok(Err(e)) => warn!("broker reflect: {}", e),
+            Err(e) => warn!("broker reflect: {}", e),
+        }
+    }
+
+    /// ask configured reflectors for our address, if it's time to
+    fn reflect(&mut self, now: Instant) -> Option<Duration> {
+        if self.reflectors.is_empty() {
+            return None;
+        }
+        if now >= self.next_reflect {
+            self.reflect_txid = rand::random();
+            let req = reflect::request(self.reflect_txid);
+            for addr in &self.reflectors {
+                if let Err(e) = self.socket.send_to(&req, addr) {
+                    trace!("send to reflector {} didnt work {:?}", addr, e);
+                }
+            }
+            self.next_reflect = now + Duration::from_millis(REFLECT_RETRY);
+        }
+        Some(self.next_reflect - now)
+    }
 
+    /// multicast that we publish, if enabled
+    fn announce(&mut self, now: Instant) -> Option<Duration> {
+        if !self.lan {
+            return None;
+        }
+        let shadow = match self.shadow {
+            Some(ref v) => v,
+            None => return None,
+        };
+        if now >= self.next_announce {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() * 1000 + d.subsec_millis() as u64)
+                .unwrap_or(0);
+            match lan::announcement(&self.secret, shadow, timestamp) {
+                Ok(msg) => {
+                    if let Err(e) = self.socket.send_to(&msg, &lan::group()) {
+                        debug!("lan announcement didnt work {:?}", e);
+                    }
+                }
+                Err(e) => warn!("cannot sign lan announcement: {}", e),
+            }
+            self.next_announce = now + Duration::from_secs(lan::ANNOUNCE_INTERVAL);
+        }
+        Some(self.next_announce - now)
+    }
+
+    /// resend direct handshakes that weren't answered yet.
+    /// returns the connect that ran out of attempts, if any
+    fn retry_direct(&mut self, now: Instant) -> Result<Option<Duration>, ConnectResponse> {
+        let mut next: Option<Duration> = None;
+        let mut failed = None;
+        for (addr, d) in &mut self.outstanding_connect_direct {
+            let mut timeout = Duration::from_millis(2u64.pow(d.attempts) * 200);
+            if now >= d.sent_at + timeout {
+                if d.attempts >= DIRECT_CONNECT_ATTEMPTS {
+                    failed = Some(*addr);
+                    continue;
+                }
+                d.attempts += 1;
+                d.sent_at = now;
+                timeout = Duration::from_millis(2u64.pow(d.attempts) * 200);
+                if let Err(e) = self.socket.send_to(&d.pkt, addr) {
+                    trace!("send to {} didnt work {:?}", addr, e);
+                }
+            }
+            let wait = d.sent_at + timeout - now;
+            next = Some(next.map_or(wait, |n| n.min(wait)));
+        }
+
+        if let Some(addr) = failed {
+            let d = self.outstanding_connect_direct.remove(&addr).unwrap();
+            warn!("direct connect to {} at {} timed out", d.identity, addr);
+            return Err(ConnectResponse {
+                identity: d.identity,
+                cr: None,
+                requester: None,
+                broker: None,
+                direct: Some(addr),
+            });
+        }
+        Ok(next)
+    }
+
+    /// bring the brokers back up to wanted_brokers after losing some,
+    /// with brokers from dns we haven't tried yet, or from resolving again once there are none.
+    /// returns when to look again
+    fn replace_brokers(&mut self, now: Instant, later: &mut osaka::Again) -> Option<Duration> {
+        let mut next: Option<Duration> = None;
+        let mut failed = Vec::new();
+        for (addr, probe) in &mut self.broker_probes {
+            let mut timeout = Duration::from_millis(2u64.pow(probe.attempts) * 200);
+            if now >= probe.sent_at + timeout {
+                if probe.attempts >= BROKER_ATTEMPTS {
+                    failed.push(*addr);
+                    continue;
+                }
+                probe.attempts += 1;
+                probe.sent_at = now;
+                timeout = Duration::from_millis(2u64.pow(probe.attempts) * 200);
+                if let Err(e) = self.socket.send_to(&probe.pkt, addr) {
+                    trace!("send to {} didnt work {:?}", addr, e);
+                }
+            }
+            let wait = probe.sent_at + timeout - now;
+            next = Some(next.map_or(wait, |n| n.min(wait)));
+        }
+        for addr in failed {
+            info!("broker {} did not answer", addr);
+            self.broker_probes.remove(&addr);
+        }
+
+        while self.brokers.len() + self.broker_probes.len() < self.wanted_brokers {
+            let record = match self.spare_brokers.pop_front() {
+                Some(v) => v,
+                None => {
+                    if self.resolver.is_none() {
+                        if now < self.next_resolve {
+                            let wait = self.next_resolve - now;
+                            next = Some(next.map_or(wait, |n| n.min(wait)));
+                            break;
+                        }
+                        self.resolver = Some(resolve_brokers(self.poll.clone()));
+                    }
+                    match self.resolver.as_mut().unwrap().poll() {
+                        FutureResult::Done(r) => {
+                            self.resolver = None;
+                            self.next_resolve = now + Duration::from_secs(RESOLVE_INTERVAL);
+                            match r {
+                                Ok(records) => self.spare_brokers = records.into(),
+                                Err(e) => warn!("cannot resolve brokers: {}", e),
+                            }
+                        }
+                        FutureResult::Again(a) => {
+                            later.merge(a);
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            };
 
+            let connected = self.brokers.iter().any(|route| match self.channels.get(route) {
+                Some(UdpChannel { addrs: AddressMode::Established(addr, _), .. }) => *addr == record.addr,
+                _ => false,
+            });
+            if connected || self.broker_probes.contains_key(&record.addr) {
+                continue;
+            }
+
+            info!("replacing a lost broker with {} (priority {})", &record.addr, record.priority);
+            let (noise, pkt) = match noise::initiate(Some(&record.x), &self.secret, clock::dns_time(&record)) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("{}: {}", record.addr, e);
+                    continue;
+                }
+            };
+            let pkt = pkt.encode();
+            if let Err(e) = self.socket.send_to(&pkt, &record.addr) {
+                warn!("{}: {}", record.addr, e);
+            }
+            self.broker_probes.insert(record.addr, BrokerProbe {
+                noise,
+                pkt,
+                first_sent: now,
+                sent_at: now,
+                attempts: 1,
+            });
+            let wait = Duration::from_millis(2 * 200);
+            next = Some(next.map_or(wait, |n| n.min(wait)));
+        }
+        next
+    }
+
+    /// the handshake response of a broker that replaces a lost one
+    fn broker_response(&mut self, addr: SocketAddr, pkt: EncryptedPacket) {
+        let mut probe = match self.broker_probes.remove(&addr) {
+            Some(v) => v,
+            None => return,
+        };
+        match probe.noise.recv_response(pkt) {
+            Ok(identity) => match probe.noise.into_transport() {
+                Ok(noise) => self.add_broker(noise, identity, addr),
+                Err(e) => warn!("{}: {}", addr, e),
+            },
+            Err(e) => {
+                warn!("{}: {}", addr, e);
+                self.broker_probes.insert(addr, probe);
+            }
+        }
+    }
+
+    /// candidate paths to a peer from what it advertised, plus relaying through the broker
+    /// that introduced us, which is the only one that knows the route
+    fn discover(&self, advertised: &[proto::Path], broker: Option<RoutingKey>) -> Paths {
+        let mut candidates = HashMap::new();
+        for path in advertised {
+            let cat = match path.category {
+                o if proto::path::Category::Local as i32 == o => proto::path::Category::Local,
+                o if proto::path::Category::Internet as i32 == o => proto::path::Category::Internet,
+                o if proto::path::Category::BrokerOrigin as i32 == o => {
+                    proto::path::Category::BrokerOrigin
+                }
+                o => {
+                    debug!("ignoring path {} with unknown category {}", path.ipaddr, o);
+                    continue;
+                }
+            };
+            let addr: SocketAddr = match path.ipaddr.parse() {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("ignoring invalid path '{}': {}", path.ipaddr, e);
+                    continue;
+                }
+            };
+            if !local_addrs::usable(&addr.ip()) || !self.socket.supports(&addr) {
+                trace!("ignoring unusable path {}", addr);
+                continue;
+            }
+            for addr in local_addrs::scoped(addr) {
+                candidates.insert(addr, cat);
+            }
+        }
+        if let Some(chan) = broker.and_then(|route| self.channels.get(&route)) {
+            if let AddressMode::Established(addr, _) = chan.addrs {
+                candidates.insert(addr.clone(), proto::path::Category::BrokerOrigin);
+            }
+        }
+
+        let paths = Paths::new(candidates, self.policy, Instant::now());
+        if paths.is_empty() {
+            warn!("path policy {:?} excludes every path to this peer", self.policy);
+        }
+        paths
+    }
+
+    fn new_channel(&self, noise: noise::Transport, debug_id: String) -> Arc<RefCell<Channel>> {
+        Arc::new(RefCell::new(Channel::with_settings(noise, debug_id, self.settings)))
+    }
+
+
+
+    /// route of the first broker channel. panics on an endpoint without brokers
     pub fn broker(&self) -> RoutingKey {
-        self.broker_route
+        *self.brokers.first().expect("endpoint has no broker")
+    }
+
+    /// routes of all broker channels
+    pub fn brokers(&self) -> &[RoutingKey] {
+        &self.brokers
     }
 
     #[osaka]
     fn publish_stream(poll: osaka::Poll, mut stream: Stream) {
+        // other brokers may still have us. the publisher exits when the last one is gone
         let _omg = defer(|| {
-            panic!("publish closed");
+            warn!("publish closed");
         });
 
-
-
         let m = osaka::sync!(stream);
         let headers = Headers::decode(&m).unwrap();
         info!("pubres: {:?}", headers);
@@ -207,16 +834,32 @@ impl Endpoint {
         if self.publish_secret.is_none() {
             self.publish_secret = Some(identity::Secret::gen());
         }
-        let xaddr = identity::SignedAddress::sign(
+        self.shadow = Some(shadow.clone());
+        self.next_announce = Instant::now();
+
+        for broker in self.brokers.clone() {
+            self.publish_on(broker, shadow.clone());
+        }
+    }
+
+    fn publish_on(&mut self, broker: RoutingKey, shadow: identity::Address) {
+        let xaddr = match identity::SignedAddress::sign(
             &self.secret,
             self.publish_secret.as_ref().unwrap().address(),
-        );
+        ) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("cannot publish on {:#x}: {}", broker, e);
+                return;
+            }
+        };
 
-        let broker = self.broker_route;
+        let mut id = None;
         self.open(
             broker,
             Headers::with_path("/carrier.broker.v1/broker/publish"),
             |poll, mut stream| {
+                id = Some(stream.id());
                 stream.small_message(proto::PublishRequest{
                     xaddr: xaddr.to_vec(),
                     shadow: shadow.as_bytes().to_vec(),
@@ -224,51 +867,159 @@ impl Endpoint {
                 Self::publish_stream(poll, stream)
             },
         );
+        self.published.push((broker, id.unwrap()));
     }
 
-    pub fn connect(&mut self, target: identity::Identity) -> Result<(), Error> {
+    /// stop being published on all brokers and the local network
+    pub fn unpublish(&mut self) {
+        self.shadow = None;
+        self.publish_secret = None;
+        for (broker, stream) in mem::replace(&mut self.published, Vec::new()) {
+            if let Some(chan) = self.channels.get_mut(&broker) {
+                chan.streams.remove(&stream);
+                chan.chan
+                    .try_borrow_mut()
+                    .expect("carrier is not thread safe")
+                    .close(stream);
+            }
+        }
+    }
 
-        let timestamp = clock::network_time();
-        let (noise, pkt) = noise::initiate(None, &self.secret, timestamp)?;
-        let handshake = pkt.encode();
+    /// leave cleanly: unpublish, give data that is in flight up to timeout to be acked,
+    /// then tell every peer and broker that we're gone, so they don't wait for idle timeouts.
+    /// incomming connects are rejected meanwhile
+    #[osaka]
+    pub fn shutdown(mut self, timeout: Duration) -> Result<(), Error> {
+        info!("shutting down");
+        self.unpublish();
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.poll() {
+                FutureResult::Done(Err(e)) => return Err(e),
+                FutureResult::Done(Ok(Event::IncommingConnect(q))) => self.reject(q),
+                FutureResult::Done(Ok(_)) => (),
+                FutureResult::Again(mut again) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        warn!("shutdown timed out with {} bytes not sent or not acked", self.bytes_pending());
+                        break;
+                    }
+                    if self.bytes_pending() == 0 {
+                        break;
+                    }
+                    again.merge(self.poll.later(deadline - now));
+                    yield again;
+                }
+            }
+        }
+        self.disconnect_all();
+        Ok(())
+    }
 
-        let mut mypaths = Vec::new();
-        for addr in local_addrs::get(self.socket.local_addr().unwrap().port()) {
-            mypaths.push(proto::Path {
-                category: (proto::path::Category::Local as i32),
-                ipaddr: format!("{}", addr),
-            });
+    /// bytes queued on any channel, or sent and not acked yet
+    fn bytes_pending(&self) -> usize {
+        self.channels
+            .values()
+            .map(|chan| {
+                let chan = chan.chan.try_borrow().expect("carrier is not thread safe");
+                chan.bytes_queued() + chan.bytes_in_flight()
+            })
+            .sum()
+    }
+
+    /// send a disconnect on every channel and drop them all
+    fn disconnect_all(&mut self) {
+        for (route, chan) in self.channels.drain() {
+            let pkt = match chan.chan.try_borrow_mut().expect("carrier is not thread safe").disconnect() {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("[{}] disconnect: {}", chan.identity, e);
+                    continue;
+                }
+            };
+            debug!("[{}] disconnecting {}", chan.identity, route);
+            let addrs = match chan.addrs {
+                AddressMode::Discovering(ref paths) => paths.usable().into_iter().map(|(addr, _)| addr).collect(),
+                AddressMode::Established(addr, _) => vec![addr],
+            };
+            for addr in addrs {
+                if let Err(e) = self.socket.send_to(&pkt, &addr) {
+                    trace!("send to {} didnt work {:?}", addr, e);
+                }
+            }
         }
+        self.brokers.clear();
+        self.outstanding_connect_incomming.clear();
+        self.outstanding_connect_outgoing.clear();
+        self.losing_connects.clear();
+        self.outstanding_connect_direct.clear();
+    }
 
-        let chan = self.channels.get_mut(&self.broker_route).unwrap();
-        let stream_id = {
-            let mut chanchan = chan
-                .chan
-                .try_borrow_mut()
-                .expect("carrier is not thread safe");
-            let stream_id = chanchan.open(Headers::with_path("/carrier.broker.v1/broker/connect").encode(), true);
+    /// connect to target through every broker. the first one to succeed wins
+    pub fn connect(&mut self, target: identity::Identity) -> Result<(), Error> {
+        if self.brokers.is_empty() {
+            return Err(Error::NoBroker);
+        }
+        let mypaths = self.my_paths();
+
+        for broker_route in self.brokers.clone() {
+            let timestamp = clock::network_time();
+            let (noise, pkt) = self.initiate(&target, timestamp)?;
+            let handshake = pkt.encode();
+
+            let chan = self.channels.get_mut(&broker_route).unwrap();
+            let stream_id = {
+                let mut chanchan = chan
+                    .chan
+                    .try_borrow_mut()
+                    .expect("carrier is not thread safe");
+                let stream_id = chanchan.open(Headers::with_path("/carrier.broker.v1/broker/connect").encode(), true);
+
+                let mut m = Vec::new();
+                proto::ConnectRequest{
+                    identity: target.as_bytes().to_vec(),
+                    timestamp,
+                    handshake,
+                    paths: mypaths.clone(),
+                }.encode(&mut m).unwrap();
+                chanchan.stream(stream_id, m);
+
+                stream_id
+            };
 
-            let mut m = Vec::new();
-            proto::ConnectRequest{
-                identity: target.as_bytes().to_vec(),
-                timestamp,
-                handshake,
-                paths: mypaths,
-            }.encode(&mut m).unwrap();
-            chanchan.stream(stream_id, m);
+            self.outstanding_connect_outgoing.insert((broker_route, stream_id), ConnectResponseStage::WaitingForHeaders{
+                identity: target.clone(),
+                noise,
+            });
+        }
 
-            stream_id
-        };
+        Ok(())
+    }
 
-        self.outstanding_connect_outgoing.insert(stream_id, ConnectResponseStage::WaitingForHeaders{
+    /// handshake with target at addr, without going through the broker.
+    /// completes with Event::OutgoingConnect like connect
+    pub fn connect_direct(&mut self, addr: SocketAddr, target: identity::Identity) -> Result<(), Error> {
+        let timestamp = clock::network_time();
+        let (noise, pkt) = self.initiate(&target, timestamp)?;
+        let pkt = pkt.encode();
+
+        info!("connecting to {} at {} directly", target, addr);
+        self.socket.send_to(&pkt, &addr)?;
+        self.outstanding_connect_direct.insert(addr, DirectConnect {
             identity: target,
             noise,
+            pkt,
+            attempts: 1,
+            sent_at: Instant::now(),
         });
-
         Ok(())
     }
 
     pub fn reject(&mut self, q: ConnectRequest) {
+        if q.direct.is_some() {
+            // there is no way to say no in a handshake. the initiator will time out
+            return;
+        }
         let mut m = Vec::new();
         proto::PeerConnectResponse {
             ok:         false,
@@ -277,12 +1028,15 @@ impl Endpoint {
         }
         .encode(&mut m)
         .unwrap();
-        let broker_route = self.broker_route;
-        self.stream(broker_route, q.qstream, m);
+        if let Some(broker_route) = q.broker {
+            self.stream(broker_route, q.qstream, m);
+        }
     }
 
     pub fn accept_outgoing<F: 'static + StreamFactory>(&mut self, q: ConnectResponse, sf: F) -> Result<RoutingKey, Error>{
         let identity = q.identity;
+        let direct = q.direct;
+        let broker = q.broker;
         let (cr, mut requester) = match (q.cr, q.requester) {
             (Some(a), Some(b)) => (a,b),
             (cr,_) => return Err(Error::OutgoingConnectFailed{identity: identity, cr}),
@@ -293,10 +1047,15 @@ impl Endpoint {
         }
 
         let pkt         = EncryptedPacket::decode(&cr.handshake)?;
-        let hs_identity = requester.recv_response(pkt).unwrap();
+        let hs_identity = requester.recv_response(pkt)?;
         let noise       = requester.into_transport()?;
 
         if identity != hs_identity {
+            if direct.is_some() {
+                // anyone on the path can answer a direct handshake
+                warn!("direct connect to {} was answered by {}", identity, hs_identity);
+                return Err(Error::SecurityViolation);
+            }
             panic!("SECURITY ALERT: handshake for outgoing connect has unexpected identity");
         }
         if cr.route != noise.route() {
@@ -304,33 +1063,26 @@ impl Endpoint {
         }
 
 
-        let mut paths = HashMap::new();
-        for path in cr.paths {
-            let cat = match path.category {
-                o if proto::path::Category::Local as i32 == o => proto::path::Category::Local,
-                o if proto::path::Category::Internet as i32 == o => proto::path::Category::Internet,
-                o if proto::path::Category::BrokerOrigin as i32 == o => {
-                    proto::path::Category::BrokerOrigin
-                }
-                _ => unreachable!(),
-            };
-            paths.insert(path.ipaddr.parse().unwrap(), (cat, 0));
-        }
-        if let Some(chan) = self.channels.get(&self.broker_route) {
-            if let AddressMode::Established(addr, _) = chan.addrs {
-                paths.insert(addr.clone(), (proto::path::Category::BrokerOrigin, 0));
-            }
-        }
-
         let debug_id = format!("{}::{}", identity, cr.route);
+        let chan = self.new_channel(noise, debug_id);
+        let addrs = match direct {
+            Some(addr) => AddressMode::Established(addr, Paths::fixed(None, self.policy, Instant::now())),
+            None => AddressMode::Discovering(self.discover(&cr.paths, broker)),
+        };
+        self.notify(Notice::Connected {
+            route:    cr.route,
+            identity: identity.clone(),
+        });
         self.channels.insert(
             cr.route,
             UdpChannel {
                 identity,
-                chan: Arc::new(RefCell::new(Channel::new(noise, debug_id))),
-                addrs: AddressMode::Discovering(paths.clone()),
+                chan,
+                addrs,
+                migration: None,
                 streams: HashMap::new(),
                 newhandl: Some(Box::new(sf)),
+                direct_response: None,
             },
         );
 
@@ -342,56 +1094,57 @@ impl Endpoint {
             .responder
             .send_response(q.cr.route, &self.secret)
             .expect("send_response");
-
-        let mut paths = HashMap::new();
-        for path in q.cr.paths {
-            let cat = match path.category {
-                o if proto::path::Category::Local as i32 == o => proto::path::Category::Local,
-                o if proto::path::Category::Internet as i32 == o => proto::path::Category::Internet,
-                o if proto::path::Category::BrokerOrigin as i32 == o => {
-                    proto::path::Category::BrokerOrigin
-                }
-                _ => unreachable!(),
-            };
-            paths.insert(path.ipaddr.parse().unwrap(), (cat, 0));
-        }
-        if let Some(chan) = self.channels.get(&self.broker_route) {
-            if let AddressMode::Established(addr, _) = chan.addrs {
-                paths.insert(addr.clone(), (proto::path::Category::BrokerOrigin, 0));
-            }
-        }
+        let pkt = pkt.encode();
 
         let debug_id = format!("{}::{}", q.identity, q.cr.route);
+        let chan = self.new_channel(noise, debug_id);
+        let (addrs, direct_response) = match q.direct {
+            Some(addr) => {
+                if let Err(e) = self.socket.send_to(&pkt, &addr) {
+                    warn!("{}: {}", addr, e);
+                }
+                (
+                    AddressMode::Established(addr, Paths::fixed(None, self.policy, Instant::now())),
+                    Some((q.cr.timestamp, pkt.clone())),
+                )
+            }
+            None => (AddressMode::Discovering(self.discover(&q.cr.paths, q.broker)), None),
+        };
+        self.notify(Notice::Connected {
+            route:    q.cr.route,
+            identity: q.identity.clone(),
+        });
         self.channels.insert(
             q.cr.route,
             UdpChannel {
                 identity: q.identity,
-                chan: Arc::new(RefCell::new(Channel::new(noise, debug_id))),
-                addrs: AddressMode::Discovering(paths.clone()),
+                chan,
+                addrs,
+                migration: None,
                 streams: HashMap::new(),
                 newhandl: Some(Box::new(sf)),
+                direct_response,
             },
         );
 
-        let mut mypaths = Vec::new();
-        for addr in local_addrs::get(self.socket.local_addr().unwrap().port()) {
-            mypaths.push(proto::Path {
-                category: (proto::path::Category::Local as i32),
-                ipaddr: format!("{}", addr),
-            });
+        if q.direct.is_some() {
+            return;
         }
 
+        let mypaths = self.my_paths();
+
         let mut m = Vec::new();
         proto::PeerConnectResponse {
             ok: true,
-            handshake: pkt.encode(),
+            handshake: pkt,
             paths: mypaths,
         }
         .encode(&mut m)
         .unwrap();
 
-        let broker_route = self.broker_route;
-        self.stream(broker_route, q.qstream, m);
+        if let Some(broker_route) = q.broker {
+            self.stream(broker_route, q.qstream, m);
+        }
     }
 
     pub fn open<F>(&mut self, route: RoutingKey, headers: Headers, f: F)
@@ -435,27 +1188,179 @@ impl Endpoint {
         chanchan.stream(stream, m)
     }
 
+    /// close a stream and drop its driver. nothing happens if it is already closed
+    pub fn close(&mut self, route: RoutingKey, stream: u32) {
+        if let Some(chan) = self.channels.get_mut(&route) {
+            if chan.streams.remove(&stream).is_some() {
+                chan.chan
+                    .try_borrow_mut()
+                    .expect("carrier is not thread safe")
+                    .close(stream);
+            }
+        }
+    }
+
+    pub fn is_open(&self, route: RoutingKey, stream: u32) -> bool {
+        self.channels
+            .get(&route)
+            .map(|chan| chan.streams.contains_key(&stream))
+            .unwrap_or(false)
+    }
+
+    /// send an unreliable datagram to the peer on route.
+    /// lost datagrams are not retransmitted
+    pub fn send_datagram<M: Into<Vec<u8>>>(&mut self, route: RoutingKey, m: M) {
+        let chan = self.channels.get_mut(&route).unwrap();
+        let mut chanchan = chan
+            .chan
+            .try_borrow_mut()
+            .expect("carrier is not thread safe");
+        chanchan.datagram(m)
+    }
+
+    /// send a ping to the peer on route, which also produces a fresh rtt sample
+    pub fn probe(&mut self, route: RoutingKey) {
+        let chan = self.channels.get_mut(&route).unwrap();
+        let mut chanchan = chan
+            .chan
+            .try_borrow_mut()
+            .expect("carrier is not thread safe");
+        chanchan.probe()
+    }
+
+    /// transport statistics of the channel on route
+    pub fn stats(&self, route: RoutingKey) -> Option<Stats> {
+        let chan = self.channels.get(&route)?;
+        let chanchan = chan
+            .chan
+            .try_borrow()
+            .expect("carrier is not thread safe");
+        Some(Stats {
+            identity: chan.identity.clone(),
+            path: chan.addrs.path(),
+            channel: chanchan.stats(),
+        })
+    }
+
     fn peer_connect_request(
+        broker: RoutingKey,
         qstream: u32,
-        publish_secret: &identity::Secret,
+        secret: &identity::Secret,
+        peers: &HashMap<identity::Identity, identity::Address>,
+        timestamps: &mut Timestamps,
         frame: Vec<u8>,
     ) -> Result<ConnectRequest, Error> {
         let cr = proto::PeerConnectRequest::decode(&frame)?;
         let identity = identity::Identity::from_bytes(&cr.identity)?;
         let pkt = EncryptedPacket::decode(&cr.handshake)?;
-        let (responder, id2, ts) = noise::respond(None, pkt)?;
+        let (responder, id2, ts) = noise::respond_peer(secret, pkt)?;
 
         if id2 != identity || ts != cr.timestamp {
             return Err(Error::SecurityViolation);
         }
+        Self::check_peer(peers, &identity, &responder)?;
+        timestamps.check(&identity, ts)?;
 
         Ok(ConnectRequest {
             identity,
             responder,
             cr,
             qstream,
+            broker: Some(broker),
+            direct: None,
         })
     }
+
+    /// a peer we know the static key of must prove it with a mutual handshake.
+    /// everyone else may use a plain one
+    fn check_peer(
+        peers: &HashMap<identity::Identity, identity::Address>,
+        identity: &identity::Identity,
+        responder: &noise::HandshakeResponder,
+    ) -> Result<(), Error> {
+        match peers.get(identity) {
+            Some(expected) if responder.remote_address().as_ref() != Some(expected) => {
+                Err(Error::SecurityViolation)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// a packet for a route we don't have a channel for.
+    /// either a direct handshake to us, or the response to connect_direct
+    fn direct_handshake(&mut self, addr: SocketAddr, pkt: EncryptedPacket) -> Option<Event> {
+        if pkt.route != 0 {
+            let d = self.outstanding_connect_direct.remove(&addr)?;
+            let route = pkt.route;
+            return Some(Event::OutgoingConnect(ConnectResponse {
+                identity: d.identity,
+                cr: Some(proto::ConnectResponse {
+                    ok: true,
+                    handshake: pkt.encode(),
+                    route,
+                    paths: Vec::new(),
+                }),
+                requester: Some(d.noise),
+                broker: None,
+                direct: Some(addr),
+            }));
+        }
+
+        if self.publish_secret.is_none() || !self.lan {
+            return None;
+        }
+        let (responder, identity, timestamp) = match noise::respond_peer(&self.secret, pkt) {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("direct handshake from {}: {}", addr, e);
+                return None;
+            }
+        };
+        if let Err(e) = Self::check_peer(&self.peers, &identity, &responder) {
+            warn!("direct handshake from {} at {}: {}", identity, addr, e);
+            return None;
+        }
+
+        // the initiator didn't get our response yet
+        for chan in self.channels.values() {
+            if let Some((ts, ref response)) = chan.direct_response {
+                if ts == timestamp && chan.identity == identity {
+                    if let Err(e) = self.socket.send_to(response, &addr) {
+                        trace!("send to {} didnt work {:?}", addr, e);
+                    }
+                    return None;
+                }
+            }
+        }
+
+        if let Err(e) = self.timestamps.check(&identity, timestamp) {
+            warn!("direct handshake from {}: {}", addr, e);
+            return None;
+        }
+
+        let route = loop {
+            let route = rand::random::<RoutingKey>();
+            if route != 0 && !self.channels.contains_key(&route) {
+                break route;
+            }
+        };
+
+        info!("direct handshake from {} at {}", identity, addr);
+        Some(Event::IncommingConnect(ConnectRequest {
+            qstream: 0,
+            cr: proto::PeerConnectRequest {
+                identity: identity.as_bytes().to_vec(),
+                timestamp,
+                handshake: Vec::new(),
+                route,
+                paths: Vec::new(),
+            },
+            identity,
+            responder,
+            broker: None,
+            direct: Some(addr),
+        }))
+    }
 }
 
 
@@ -466,12 +1371,93 @@ pub enum Event {
         route: RoutingKey,
         identity: Identity
     },
+    Datagram{
+        route: RoutingKey,
+        identity: Identity,
+        payload: Vec<u8>,
+    },
+    /// the peer moved to a new address, which answered a path challenge
+    PathChanged{
+        route: RoutingKey,
+        identity: Identity,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
 }
 
 
-
 impl Future<Result<Event, Error>> for Endpoint {
     fn poll(&mut self) -> FutureResult<Result<Event, Error>> {
+        self.run_commands();
+        match self.poll_endpoint() {
+            FutureResult::Done(Ok(event)) => {
+                if !self.subscribers.is_empty() {
+                    if let Some(notice) = Notice::from_event(&event) {
+                        self.notify(notice);
+                    }
+                }
+                FutureResult::Done(Ok(event))
+            }
+            FutureResult::Again(mut later) => {
+                if let Some((_, ref token)) = self.commands {
+                    later.merge(self.poll.again(token.clone(), None));
+                }
+                FutureResult::Again(later)
+            }
+            r => r,
+        }
+    }
+}
+
+impl Endpoint {
+    /// run what handles asked for since the last poll
+    fn run_commands(&mut self) {
+        let mut commands = Vec::new();
+        if let Some((ref rx, _)) = self.commands {
+            while let Ok(cmd) = rx.try_recv() {
+                commands.push(cmd);
+            }
+        }
+        for cmd in commands {
+            match cmd {
+                Command::Open { route, headers, reply } => {
+                    if !self.channels.contains_key(&route) {
+                        reply.send(Err(Error::NoRoute { route })).ok();
+                        continue;
+                    }
+                    let (tx, rx) = mpsc::channel();
+                    let mut id = None;
+                    self.open(route, headers, |poll, stream| {
+                        id = Some(stream.id());
+                        handle::forward(poll, stream, tx)
+                    });
+                    // if the handle gave up waiting, the driver ends with its first message
+                    reply.send(Ok((id.unwrap(), rx))).ok();
+                }
+                Command::Send { route, stream, payload } => {
+                    if !self.channels.contains_key(&route) {
+                        debug!("dropping message from handle for closed route {}", route);
+                    } else if !self.is_open(route, stream) {
+                        debug!("dropping message from handle for closed stream {} on route {}", stream, route);
+                    } else {
+                        self.stream(route, stream, payload);
+                    }
+                }
+                Command::Close { route, stream } => {
+                    self.close(route, stream);
+                }
+                Command::Subscribe(tx) => {
+                    self.subscribers.push(tx);
+                }
+            }
+        }
+    }
+
+    fn notify(&mut self, notice: Notice) {
+        self.subscribers.retain(|s| s.send(notice.clone()).is_ok());
+    }
+
+    fn poll_endpoint(&mut self) -> FutureResult<Result<Event, Error>> {
         // receive one packet
         let mut buf = vec![0; MAX_PACKET_SIZE];
         match self.socket.recv_from(&mut buf) {
@@ -480,45 +1466,33 @@ impl Future<Result<Event, Error>> for Endpoint {
                     return FutureResult::Done(Err(Error::Io(e)));
                 }
             }
+            Ok((len, addr)) if reflect::is_reflect(&buf[..len]) => match reflect::decode(&buf[..len]) {
+                Some(reflect::Message::Response { txid, addr: reflexive })
+                    if txid == self.reflect_txid && self.reflectors.contains(&addr) =>
+                {
+                    if self.reflexive.get() != Some(reflexive) {
+                        info!("reflector {} sees us as {}", addr, reflexive);
+                    }
+                    self.reflexive.set(Some(reflexive));
+                    self.next_reflect = Instant::now() + Duration::from_secs(REFLECT_INTERVAL);
+                }
+                _ => trace!("ignoring reflector message from {}", addr),
+            },
             Ok((len, addr)) => match EncryptedPacket::decode(&buf[..len]) {
                 Err(e) => warn!("{}: {}", addr, e),
                 Ok(pkt) => {
-                    if let Some(chan) = self.channels.get_mut(&pkt.route) {
+                    let route = pkt.route;
+                    if !self.channels.contains_key(&route) {
+                        if self.broker_probes.contains_key(&addr) {
+                            self.broker_response(addr, pkt);
+                        } else if let Some(event) = self.direct_handshake(addr, pkt) {
+                            return FutureResult::Done(Ok(event));
+                        }
+                    } else if let Some(chan) = self.channels.get_mut(&pkt.route) {
 
-                        let settle = if let AddressMode::Discovering(ref mut addrs) = chan.addrs {
+                        if let AddressMode::Discovering(ref mut paths) = chan.addrs {
                             trace!("in discovery: received from {}", addr);
-                            let count = {
-                                let (_, count) = addrs.entry(addr).or_insert((proto::path::Category::Internet, 0));
-                                *count += 1;
-                                *count
-                            };
-                            if count >= 5 {
-                                let mut m = None;
-                                let mut bestest = None;
-                                for (addr, (cat, count)) in &*addrs {
-                                    if *count >= 1 {
-                                        if let Some(ref mut bestest) = bestest {
-                                            if *bestest > *cat as i32 {
-                                                m = Some(addr.clone());
-                                                *bestest = *cat as i32;
-                                            }
-                                        } else {
-                                            m = Some(addr.clone());
-                                            bestest = Some(*cat as i32);
-                                        }
-                                    }
-                                }
-                                Some((m.unwrap(), mem::replace(addrs, HashMap::new())))
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        };
-
-                        if let Some((addr, previous)) = settle {
-                            info!("settled peering with adress {}", addr);
-                            chan.addrs = AddressMode::Established(addr, previous);
+                            paths.seen(addr);
                         }
 
                         let mut chanchan = chan
@@ -529,19 +1503,84 @@ impl Future<Result<Event, Error>> for Endpoint {
                             Err(Error::AntiReplay) => debug!("{}: {}", addr, Error::AntiReplay),
                             Err(e) => warn!("{}: {}", addr, e),
                             Ok(()) => {
-                                if let AddressMode::Established(ref mut addr_, ref previous) = chan.addrs {
-                                    if addr != *addr_ {
-                                        let current_cat = previous.get(addr_).unwrap_or(&(proto::path::Category::Internet, 0)).0;
-                                        let migrate_cat = previous.get(&addr).unwrap_or(&(proto::path::Category::Internet, 0)).0;
-
-                                        if current_cat as i32 >= migrate_cat as i32 {
-                                            warn!(
-                                                "channel migration not fully implemented yet. migrating from  {} to {}",
-                                                addr_, addr,
-                                                );
-                                            *addr_ = addr;
+                                // the peer has the transport keys, so it got the handshake response
+                                chan.direct_response = None;
+
+                                let now = Instant::now();
+                                match chan.addrs {
+                                    AddressMode::Discovering(ref mut paths) |
+                                    AddressMode::Established(_, ref mut paths) => {
+                                        paths.heard(addr);
+                                        paths.on_recv(now, |challenge| chanchan.path_validated(challenge));
+                                    }
+                                }
+
+                                let mut validated = None;
+                                let mut migration = None;
+                                if let AddressMode::Established(ref mut addr_, ref paths) = chan.addrs {
+                                    if let Some(ref mut m) = chan.migration {
+                                        // packets from any other address are ignored for migration
+                                        // until this one is resolved
+                                        if addr == m.addr {
+                                            m.bytes_received += len;
+                                            if chanchan.path_validated(m.challenge) {
+                                                validated = Some((m.previous, m.addr));
+                                            }
                                         }
                                     }
+                                    if chan.migration.is_none() && addr != *addr_ {
+                                        let current_cat = paths.category(addr_).unwrap_or(proto::path::Category::Internet);
+                                        let migrate_cat = paths.category(&addr).unwrap_or(proto::path::Category::Internet);
+
+                                        if current_cat as i32 >= migrate_cat as i32 && self.policy.allows(migrate_cat) {
+                                            if !chanchan.validates_paths() {
+                                                // version 0x08 peers can't answer a challenge.
+                                                // the packet is authenticated, so follow it
+                                                validated = Some((*addr_, addr));
+                                                *addr_ = addr;
+                                            } else {
+                                                let challenge = rand::random::<u64>();
+                                                match chanchan.path_challenge(challenge) {
+                                                    Err(e) => warn!("{}: {}", addr, e),
+                                                    Ok(challenge_pkt) => {
+                                                        debug!(
+                                                            "[{}] peer moved from {} to {}. validating new path",
+                                                            chan.identity, addr_, addr,
+                                                            );
+                                                        if let Err(e) = self.socket.send_to(&challenge_pkt, &addr) {
+                                                            trace!("send to {} didnt work {:?}", addr, e);
+                                                        }
+                                                        // switch right away, so a peer that lost its old
+                                                        // address (nat rebinding) doesn't stall.
+                                                        // the amplification limit applies until validated
+                                                        migration = Some(Migration {
+                                                            addr,
+                                                            previous: *addr_,
+                                                            challenge,
+                                                            attempts: 1,
+                                                            sent_at: now,
+                                                            bytes_received: len,
+                                                            bytes_sent: challenge_pkt.len(),
+                                                        });
+                                                        *addr_ = addr;
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                                if migration.is_some() {
+                                    chan.migration = migration;
+                                }
+                                if let Some((from, to)) = validated {
+                                    info!("[{}] migrated from {} to {}", chan.identity, from, to);
+                                    chan.migration = None;
+                                    return FutureResult::Done(Ok(Event::PathChanged {
+                                        route,
+                                        identity: chan.identity.clone(),
+                                        from,
+                                        to,
+                                    }));
                                 }
                             }
                         }
@@ -554,6 +1593,28 @@ impl Future<Result<Event, Error>> for Endpoint {
         let mut later = self
             .poll
             .again(self.token.clone(), Some(Duration::from_secs(600)));
+        if let Some(next) = self.reflect(Instant::now()) {
+            later.merge(self.poll.later(next));
+        }
+        if let Some(next) = self.announce(Instant::now()) {
+            later.merge(self.poll.later(next));
+        }
+        match self.retry_direct(Instant::now()) {
+            Ok(Some(next)) => later.merge(self.poll.later(next)),
+            Ok(None) => (),
+            Err(q) => return FutureResult::Done(Ok(Event::OutgoingConnect(q))),
+        }
+        if let Some(next) = self.replace_brokers(Instant::now(), &mut later) {
+            later.merge(self.poll.later(next));
+        }
+        for (route, stream) in mem::replace(&mut self.cancelled_connects, Vec::new()) {
+            if let Some(chan) = self.channels.get(&route) {
+                chan.chan
+                    .try_borrow_mut()
+                    .expect("carrier is not thread safe")
+                    .close(stream);
+            }
+        }
         loop {
             let mut again = false;
             let mut killme = Vec::new();
@@ -584,12 +1645,137 @@ impl Future<Result<Event, Error>> for Endpoint {
                     }
                 }
 
+                // probe candidate paths
+                let now = Instant::now();
+                let mut settled = None;
+                let mut better = None;
+                {
+                    let mut chanchan = chan
+                        .chan
+                        .try_borrow_mut()
+                        .expect("carrier is not thread safe");
+                    let socket = &self.socket;
+                    let validates = chanchan.validates_paths();
+                    let (paths, current) = match chan.addrs {
+                        AddressMode::Discovering(ref mut paths) => {
+                            if validates {
+                                paths.retry(now);
+                            }
+                            (paths, None)
+                        }
+                        AddressMode::Established(addr, ref mut paths) => {
+                            if validates {
+                                paths.reevaluate(addr, now);
+                            }
+                            (paths, Some(addr))
+                        }
+                    };
+                    if !validates {
+                        // version 0x08 peers can't answer challenges. go by where their packets come from
+                        if current.is_none() {
+                            settled = paths.settle_unprobed(now);
+                            if settled.is_none() {
+                                later.merge(self.poll.later(Duration::from_millis(PATH_CHALLENGE_TIMEOUT)));
+                            }
+                        }
+                    } else {
+                        let next = osaka::try!(paths.tick(now, |addr, challenge| {
+                            let pkt = chanchan.path_challenge(challenge)?;
+                            if let Err(e) = socket.send_to(&pkt, addr) {
+                                trace!("send to {} didnt work {:?}", addr, e);
+                            }
+                            Ok(())
+                        }));
+                        if let Some(next) = next {
+                            later.merge(self.poll.later(next));
+                        }
+                        if let Some(best) = paths.settle(now) {
+                            match current {
+                                None => settled = Some(best),
+                                Some(current) => {
+                                    if chan.migration.is_none() && paths.better(&best, &current) {
+                                        better = Some((current, best));
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                if let Some(addr) = settled {
+                    let paths = match mem::replace(
+                        &mut chan.addrs,
+                        AddressMode::Discovering(Paths::fixed(None, self.policy, now)),
+                    ) {
+                        AddressMode::Discovering(paths) | AddressMode::Established(_, paths) => paths,
+                    };
+                    info!(
+                        "[{}] settled peering with address {} ({:?}, rtt {:?})",
+                        chan.identity,
+                        addr,
+                        paths.category(&addr),
+                        paths.rtt(&addr)
+                    );
+                    chan.addrs = AddressMode::Established(addr, paths);
+                }
+                if let Some((from, to)) = better {
+                    info!("[{}] switching to better path {} from {}", chan.identity, to, from);
+                    if let AddressMode::Established(ref mut addr, _) = chan.addrs {
+                        *addr = to;
+                    }
+                    return FutureResult::Done(Ok(Event::PathChanged {
+                        route: *route,
+                        identity: chan.identity.clone(),
+                        from,
+                        to,
+                    }));
+                }
+
+                // path validation timer
+                let mut failed = false;
+                if let Some(ref mut m) = chan.migration {
+                    let timeout = Duration::from_millis(PATH_CHALLENGE_TIMEOUT);
+                    let elapsed = m.sent_at.elapsed();
+                    if elapsed < timeout {
+                        later.merge(self.poll.later(timeout - elapsed));
+                    } else if m.attempts >= MAX_PATH_CHALLENGES {
+                        failed = true;
+                    } else {
+                        m.attempts += 1;
+                        m.sent_at = Instant::now();
+                        let mut chanchan = chan
+                            .chan
+                            .try_borrow_mut()
+                            .expect("carrier is not thread safe");
+                        let pkt = osaka::try!(chanchan.path_challenge(m.challenge));
+                        if m.may_send(pkt.len()) {
+                            m.bytes_sent += pkt.len();
+                            if let Err(e) = self.socket.send_to(&pkt, &m.addr) {
+                                trace!("send to {} didnt work {:?}", m.addr, e);
+                            }
+                        }
+                        later.merge(self.poll.later(timeout));
+                    }
+                }
+                if failed {
+                    let m = chan.migration.take().unwrap();
+                    warn!(
+                        "[{}] path validation of {} failed, falling back to {}",
+                        chan.identity, m.addr, m.previous
+                    );
+                    if let AddressMode::Established(ref mut addr, _) = chan.addrs {
+                        *addr = m.previous;
+                    }
+                }
+
                 let r = {
                     let mut chanchan = chan
                         .chan
                         .try_borrow_mut()
                         .expect("carrier is not thread safe");
 
+                    if let AddressMode::Established(addr, _) = chan.addrs {
+                        chanchan.set_ipv6(addr.is_ipv6());
+                    }
                     osaka::try!(chanchan.progress())
                 };
                 match r {
@@ -599,15 +1785,27 @@ impl Future<Result<Event, Error>> for Endpoint {
                     ChannelProgress::SendPacket(pkt) => {
                         again = true;
                         match &chan.addrs {
-                            AddressMode::Discovering(addrs) => {
-                                for (addr, _) in addrs.iter() {
-                                    match self.socket.send_to(&pkt, addr) {
+                            AddressMode::Discovering(paths) => {
+                                for (addr, _) in paths.usable() {
+                                    match self.socket.send_to(&pkt, &addr) {
                                         Ok(len) if len == pkt.len() => (),
                                         e => trace!("send to {} didnt work {:?}", addr, e),
                                     }
                                 }
                             }
                             AddressMode::Established(addr, _) => {
+                                let mut addr = *addr;
+                                if let Some(ref mut m) = chan.migration {
+                                    if m.may_send(pkt.len()) {
+                                        m.bytes_sent += pkt.len();
+                                    } else {
+                                        trace!(
+                                            "{} is not validated yet and at its amplification limit. sending to {}",
+                                            m.addr, m.previous
+                                        );
+                                        addr = m.previous;
+                                    }
+                                }
                                 match self.socket.send_to(&pkt, &addr) {
                                     Ok(len) if len == pkt.len() => (),
                                     e => error!("send didnt work {:?}", e),
@@ -619,10 +1817,10 @@ impl Future<Result<Event, Error>> for Endpoint {
                         let headers = osaka::try!(Headers::decode(&frame));
                         debug!("incomming request {:?}", headers);
 
-                        if route == &self.broker_route {
+                        if self.brokers.contains(route) {
                             let m = match headers.path().as_ref() {
                                 Some(&b"/carrier.broker.v1/peer/connect") => {
-                                    self.outstanding_connect_incomming.insert(stream);
+                                    self.outstanding_connect_incomming.insert((*route, stream));
                                     Headers::ok()
                                 }
                                 _ => Headers::with_error(404, "not found"),
@@ -659,13 +1857,16 @@ impl Future<Result<Event, Error>> for Endpoint {
                         again = true;
                     }
                     ChannelProgress::ReceiveStream(stream, frame) => {
-                        if route == &self.broker_route
-                            && self.outstanding_connect_incomming.remove(&stream)
+                        if self.brokers.contains(route)
+                            && self.outstanding_connect_incomming.remove(&(*route, stream))
                             && self.publish_secret.is_some()
                         {
                             match Self::peer_connect_request(
+                                *route,
                                 stream,
-                                self.publish_secret.as_ref().unwrap(),
+                                &self.secret,
+                                &self.peers,
+                                &mut self.timestamps,
                                 frame,
                             ) {
                                 Ok(q) => return FutureResult::Done(Ok(Event::IncommingConnect(q))),
@@ -687,16 +1888,14 @@ impl Future<Result<Event, Error>> for Endpoint {
                                     chanchan.close(stream);
                                 }
                             }
-                        } else if route == &self.broker_route &&
-                            self.outstanding_connect_outgoing.contains_key(&stream)
-                        {
-                            let mut cr = self.outstanding_connect_outgoing.remove(&stream).unwrap();
+                        } else if self.outstanding_connect_outgoing.contains_key(&(*route, stream)) {
+                            let mut cr = self.outstanding_connect_outgoing.remove(&(*route, stream)).unwrap();
                             match cr {
                                 ConnectResponseStage::WaitingForHeaders{identity, noise} => {
                                     let headers = Headers::decode(&frame).unwrap();
                                     trace!("conres: {:?}", headers);
                                     self.outstanding_connect_outgoing.insert(
-                                        stream, ConnectResponseStage::WaitingForResponse{
+                                        (*route, stream), ConnectResponseStage::WaitingForResponse{
                                             identity, noise});
                                 },
                                 ConnectResponseStage::WaitingForResponse{identity, noise} => {
@@ -708,15 +1907,47 @@ impl Future<Result<Event, Error>> for Endpoint {
                                         .expect("carrier is not thread safe")
                                         .close(stream);
 
-                                    return FutureResult::Done(Ok(Event::OutgoingConnect(ConnectResponse{
+                                    let q = ConnectResponse{
                                         identity,
                                         requester: Some(noise),
                                         cr: Some(cr),
-                                    })));
+                                        broker: Some(*route),
+                                        direct: None,
+                                    };
+                                    if let Some(q) = settle_connect(
+                                        &mut self.outstanding_connect_outgoing,
+                                        &mut self.cancelled_connects,
+                                        &mut self.losing_connects,
+                                        q,
+                                    ) {
+                                        return FutureResult::Done(Ok(Event::OutgoingConnect(q)));
+                                    }
 
                                 },
                             }
 
+                        } else if let Some(stage) = self.losing_connects.remove(&(*route, stream)) {
+                            match stage {
+                                ConnectResponseStage::WaitingForHeaders{identity, noise} => {
+                                    self.losing_connects.insert(
+                                        (*route, stream), ConnectResponseStage::WaitingForResponse{
+                                            identity, noise});
+                                },
+                                ConnectResponseStage::WaitingForResponse{identity, noise} => {
+                                    match disconnect_loser(noise, &frame) {
+                                        Ok(None) => (),
+                                        Ok(Some(pkt)) => {
+                                            debug!("connect to {} through {} lost the race, disconnecting", identity, route);
+                                            if let AddressMode::Established(addr, _) = chan.addrs {
+                                                if let Err(e) = self.socket.send_to(&pkt, &addr) {
+                                                    trace!("send to {} didnt work {:?}", addr, e);
+                                                }
+                                            }
+                                        }
+                                        Err(e) => debug!("late connect response for {}: {}", identity, e),
+                                    }
+                                },
+                            }
                         } else if let Some(driver) = chan.streams.get_mut(&stream) {
                             driver.a.set(osaka::FutureResult::Done(frame));
                             driver.f.wakeup_now();
@@ -731,20 +1962,36 @@ impl Future<Result<Event, Error>> for Endpoint {
 
                         again = true;
                     }
+                    ChannelProgress::ReceiveDatagram(payload) => {
+                        return FutureResult::Done(Ok(Event::Datagram{
+                            route: route.clone(),
+                            identity: chan.identity.clone(),
+                            payload,
+                        }));
+                    }
                     ChannelProgress::Close(stream) => {
                         chan.streams.remove(&stream);
+                        self.losing_connects.remove(&(*route, stream));
                         again = true;
-                        if route == &self.broker_route &&
-                        self.outstanding_connect_outgoing.contains_key(&stream)
-                        {
-                            return FutureResult::Done(Ok(Event::OutgoingConnect(ConnectResponse{
-                                identity: match self.outstanding_connect_outgoing.remove(&stream).unwrap() {
+                        if let Some(cr) = self.outstanding_connect_outgoing.remove(&(*route, stream)) {
+                            let q = ConnectResponse{
+                                identity: match cr {
                                     ConnectResponseStage::WaitingForHeaders{identity, ..}  => identity,
                                     ConnectResponseStage::WaitingForResponse{identity, ..} => identity,
                                 },
                                 cr: None,
                                 requester: None,
-                            })));
+                                broker: Some(*route),
+                                direct: None,
+                            };
+                            if let Some(q) = settle_connect(
+                                &mut self.outstanding_connect_outgoing,
+                                &mut self.cancelled_connects,
+                                &mut self.losing_connects,
+                                q,
+                            ) {
+                                return FutureResult::Done(Ok(Event::OutgoingConnect(q)));
+                            }
                         }
                     }
                     ChannelProgress::Disconnect => {
@@ -787,6 +2034,19 @@ impl Future<Result<Event, Error>> for Endpoint {
                     self.channels.len()
                 );
 
+                if let Some(pos) = self.brokers.iter().position(|b| *b == killme) {
+                    self.brokers.remove(pos);
+                    self.published.retain(|&(route, _)| route != killme);
+                    self.outstanding_connect_incomming.retain(|&(route, _)| route != killme);
+                    self.outstanding_connect_outgoing.retain(|&(route, _), _| route != killme);
+                    self.losing_connects.retain(|&(route, _), _| route != killme);
+                    if self.brokers.is_empty() {
+                        warn!("lost connection to the last broker");
+                    } else {
+                        warn!("lost connection to a broker, {} left", self.brokers.len());
+                    }
+                }
+
                 if let Some(rm) = rm {
                     return FutureResult::Done(Ok(Event::Disconnect{
                         route: killme,
@@ -805,8 +2065,33 @@ impl Future<Result<Event, Error>> for Endpoint {
 
 // -- builder
 
+/// broker records from dns, in the order to try them
+#[osaka]
+fn resolve_brokers(poll: osaka::Poll) -> Result<Vec<dns::DnsRecord>, Error> {
+    let mut a = osaka_dns::resolve(
+        poll,
+        vec![
+        "x.carrier.devguard.io".into(),
+        "3.carrier.devguard.io".into(),
+        ],
+        );
+    let mut records: Vec<dns::DnsRecord> = osaka::sync!(a)?
+        .into_iter()
+        .filter_map(|v| dns::DnsRecord::from_signed_txt(v))
+        .collect();
+    records.shuffle(&mut thread_rng());
+    Ok(dns::order(records, &dns::load_cache()))
+}
+
 pub struct EndpointBuilder {
     secret: identity::Secret,
+    settings: channel::Settings,
+    policy: PathPolicy,
+    reflectors: Vec<SocketAddr>,
+    lan: bool,
+    brokers: usize,
+    peers: HashMap<identity::Identity, identity::Address>,
+    clock_skew: u64,
 }
 
 impl EndpointBuilder {
@@ -815,92 +2100,311 @@ impl EndpointBuilder {
 
         Ok(Self {
             secret: config.secret.clone(),
+            settings: channel::Settings {
+                congestion:     config.congestion,
+                pacing:         config.pacing,
+                mtu_floor:      config.mtu_floor,
+                mtu_ceiling:    config.mtu_ceiling,
+                ..channel::Settings::default()
+            },
+            policy: config.paths,
+            reflectors: config.reflectors.clone(),
+            lan: config.lan,
+            brokers: config.brokers,
+            peers: config.peers.clone(),
+            clock_skew: config.clock_skew,
         })
     }
 
+    /// an endpoint that doesn't connect to a broker
+    pub fn direct(self, poll: osaka::Poll) -> Result<Endpoint, Error> {
+        let sock = Socket::bind_any(0).map_err(|e| Error::Io(e))?;
+        let token = poll
+            .register(&sock, mio::Ready::readable(), mio::PollOpt::level())
+            .unwrap();
+        info!("listening for direct connections on port {}", sock.local_addr()?.port());
+
+        let mut ep = Endpoint::direct(
+            poll,
+            token,
+            sock,
+            self.secret,
+            self.settings,
+            self.policy,
+            self.reflectors,
+            self.lan,
+        );
+        ep.peers = self.peers;
+        ep.set_clock_skew(self.clock_skew);
+        Ok(ep)
+    }
+
     #[osaka]
     pub fn connect(
         self,
         poll: osaka::Poll,
     ) -> Result<Endpoint, Error> {
 
-        let mut a = osaka_dns::resolve(
-            poll.clone(),
-            vec![
-            "x.carrier.devguard.io".into(),
-            "3.carrier.devguard.io".into(),
-            ],
-            );
-        let mut records: Vec<dns::DnsRecord> = osaka::sync!(a)?
-            .into_iter()
-            .filter_map(|v| dns::DnsRecord::from_signed_txt(v))
-            .collect();
-        records.shuffle(&mut thread_rng());
-
+        let mut a = resolve_brokers(poll.clone());
+        let mut records: VecDeque<dns::DnsRecord> = osaka::sync!(a)?.into();
+
+        // all brokers share this socket, so peers see one address for us
+        let sock = Socket::bind_any(0).map_err(|e| Error::Io(e))?;
+        let token = poll
+            .register(&sock, mio::Ready::readable(), mio::PollOpt::level())
+            .unwrap();
+
+        // happy eyeballs: start the next broker whenever the previous ones are slow to answer,
+        // and keep all of them running
+        let mut probes: HashMap<SocketAddr, BrokerProbe> = HashMap::new();
+        let mut winners = Vec::new();
+        let mut next_start = Instant::now();
+        let mut settle_at: Option<Instant> = None;
+        let mut buf = vec![0; MAX_PACKET_SIZE];
         loop {
-            let record = match records.pop() {
-                Some(v) => v,
-                None => return Err(Error::OutOfOptions),
-            };
+            let now = Instant::now();
+
+            while let Ok((len, from)) = sock.recv_from(&mut buf) {
+                let mut probe = match probes.remove(&from) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                match EncryptedPacket::decode(&buf[..len])
+                    .and_then(|pkt| probe.noise.recv_response(pkt))
+                {
+                    Ok(identity) => {
+                        let rtt = now - probe.first_sent;
+                        let noise = probe.noise.into_transport()?;
+                        info!(
+                            "established connection with {} :: {} in {:?}",
+                            identity,
+                            noise.route(),
+                            rtt,
+                        );
+                        winners.push((noise, identity, from, rtt));
+                    }
+                    Err(e) => {
+                        warn!("EndpointFuture::WaitingForResponse: {}", e);
+                        probes.insert(from, probe);
+                    }
+                }
+            }
 
-            info!("attempting connection with {}", &record.addr);
+            if winners.len() >= self.brokers && settle_at.is_none() {
+                // brokers that answer a little later may still be closer
+                settle_at = Some(now + Duration::from_millis(BROKER_STAGGER));
+            }
+            if let Some(at) = settle_at {
+                if now >= at {
+                    break;
+                }
+            }
 
-            let timestamp = clock::dns_time(&record);
-            let (mut noise, pkt) = noise::initiate(Some(&record.x), &self.secret, timestamp)?;
-            let pkt = pkt.encode();
+            if settle_at.is_none() && (now >= next_start || probes.is_empty()) {
+                if let Some(record) = records.pop_front() {
+                    info!("attempting connection with {} (priority {})", &record.addr, record.priority);
+                    let timestamp = clock::dns_time(&record);
+                    let (noise, pkt) = noise::initiate(Some(&record.x), &self.secret, timestamp)?;
+                    let pkt = pkt.encode();
+                    if let Err(e) = sock.send_to(&pkt, &record.addr) {
+                        warn!("{}: {}", record.addr, e);
+                    }
+                    probes.insert(record.addr, BrokerProbe {
+                        noise,
+                        pkt,
+                        first_sent: now,
+                        sent_at: now,
+                        attempts: 1,
+                    });
+                    next_start = now + Duration::from_millis(BROKER_STAGGER);
+                }
+            }
 
-            let sock = UdpSocket::bind(&"0.0.0.0:0".parse().unwrap()).map_err(|e| Error::Io(e))?;
-            let token = poll
-                .register(&sock, mio::Ready::readable(), mio::PollOpt::level())
-                .unwrap();
-
-            let mut attempts = 0;
-            let r = loop {
-                attempts += 1;
-                if attempts > 4 {
-                    break None;
-                }
-                let mut buf = vec![0; MAX_PACKET_SIZE];
-                if let Ok((len, _from)) = sock.recv_from(&mut buf) {
-                    match EncryptedPacket::decode(&buf[..len])
-                        .and_then(|pkt| noise.recv_response(pkt))
-                    {
-                        Ok(identity) => {
-                            let noise = noise.into_transport()?;
-                            break Some((identity, noise));
-                        }
-                        Err(e) => {
-                            warn!("EndpointFuture::WaitingForResponse: {}", e);
-                            continue;
-                        }
+            let mut wakeup = settle_at;
+            if settle_at.is_none() && !records.is_empty() {
+                wakeup = Some(next_start);
+            }
+            let mut failed = Vec::new();
+            for (addr, probe) in probes.iter_mut() {
+                let mut at = probe.sent_at + Duration::from_millis(2u64.pow(probe.attempts) * 200);
+                if now >= at {
+                    if probe.attempts >= 4 {
+                        failed.push(*addr);
+                        continue;
                     }
-                };
-                sock.send_to(&pkt, &record.addr)?;
-                yield poll.again(
-                    token.clone(),
-                    Some(Duration::from_millis(2u64.pow(attempts) * 200)),
-                );
-            };
-            let (identity, noise) = match r {
-                Some(v) => v,
-                None => continue,
+                    probe.attempts += 1;
+                    probe.sent_at = now;
+                    if let Err(e) = sock.send_to(&probe.pkt, addr) {
+                        warn!("{}: {}", addr, e);
+                    }
+                    at = now + Duration::from_millis(2u64.pow(probe.attempts) * 200);
+                }
+                wakeup = Some(wakeup.map_or(at, |w| w.min(at)));
+            }
+            for addr in failed {
+                info!("broker {} did not answer", addr);
+                probes.remove(&addr);
+            }
+
+            if probes.is_empty() && settle_at.is_none() {
+                if records.is_empty() {
+                    break;
+                }
+                continue;
+            }
+
+            let wait = match wakeup {
+                Some(at) if at > now => at - now,
+                _ => Duration::from_millis(1),
             };
+            yield poll.again(token.clone(), Some(wait));
+        }
 
-            info!(
-                "established connection with {} :: {}",
-                identity,
-                noise.route()
-            );
+        // closest first
+        winners.sort_by_key(|w| w.3);
+        winners.truncate(self.brokers);
+        if !winners.is_empty() {
+            let addrs: Vec<SocketAddr> = winners.iter().map(|w| w.2).collect();
+            if let Err(e) = dns::store_cache(&addrs) {
+                warn!("cannot store broker cache: {}", e);
+            }
+        }
+        let brokers: Vec<(noise::Transport, Identity, SocketAddr)> = winners
+            .into_iter()
+            .map(|(noise, identity, addr, _)| (noise, identity, addr))
+            .collect();
 
-            return Ok(Endpoint::new(
-                poll,
-                token,
-                noise,
-                identity,
-                sock,
-                record.addr,
-                self.secret,
-            ));
+        let mut brokers = brokers.into_iter();
+        let (noise, identity, addr) = match brokers.next() {
+            Some(v) => v,
+            None => return Err(Error::OutOfOptions),
+        };
+        let mut ep = Endpoint::new(
+            poll,
+            token,
+            noise,
+            identity,
+            sock,
+            addr,
+            self.secret,
+            self.settings,
+            self.policy,
+            self.reflectors,
+            self.lan,
+        );
+        ep.peers = self.peers;
+        ep.set_clock_skew(self.clock_skew);
+        for (noise, identity, addr) in brokers {
+            ep.add_broker(noise, identity, addr);
+        }
+        ep.wanted_brokers = self.brokers;
+        ep.spare_brokers = records;
+        Ok(ep)
+    }
+}
+
+#[test]
+fn replayed_connect_requests() {
+    let alice = identity::Secret::gen();
+    let bob = identity::Secret::gen();
+    let mut timestamps = Timestamps::new(16, 60);
+    let peers = HashMap::new();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let now = now.as_secs() * 1000 + now.subsec_millis() as u64;
+
+    let request = |from: &identity::Secret, timestamp: u64| {
+        let (_, pkt) = noise::initiate(None, from, timestamp).unwrap();
+        let mut m = Vec::new();
+        proto::PeerConnectRequest {
+            identity: from.identity().as_bytes().to_vec(),
+            timestamp,
+            handshake: pkt.encode(),
+            route: 1,
+            paths: Vec::new(),
         }
+        .encode(&mut m)
+        .unwrap();
+        m
+    };
+
+    let first = request(&alice, now);
+    assert!(Endpoint::peer_connect_request(1, 3, &bob, &peers, &mut timestamps, first.clone()).is_ok());
+
+    // the same request relayed again, and an older one recorded earlier
+    match Endpoint::peer_connect_request(1, 5, &bob, &peers, &mut timestamps, first) {
+        Err(Error::HandshakeReplay { .. }) => (),
+        _ => panic!("expected HandshakeReplay"),
+    }
+    let older = request(&alice, now - 1000);
+    assert!(Endpoint::peer_connect_request(1, 7, &bob, &peers, &mut timestamps, older).is_err());
+
+    // recorded last week, from someone we never saw
+    let carol = identity::Secret::gen();
+    let stale = request(&carol, now - 7 * 24 * 3600 * 1000);
+    match Endpoint::peer_connect_request(1, 9, &bob, &peers, &mut timestamps, stale) {
+        Err(Error::ClockSkew { .. }) => (),
+        _ => panic!("expected ClockSkew"),
+    }
+
+    let newer = request(&alice, now + 1);
+    assert!(Endpoint::peer_connect_request(1, 11, &bob, &peers, &mut timestamps, newer).is_ok());
+}
+
+#[test]
+fn configured_peers_need_mutual_handshakes() {
+    let alice = identity::Secret::gen();
+    let bob = identity::Secret::gen();
+    let mut peers = HashMap::new();
+
+    let (_, pkt) = noise::initiate(None, &alice, 1).unwrap();
+    let (plain, _, _) = noise::respond_peer(&bob, pkt).unwrap();
+    let (_, pkt) = noise::initiate_mutual(&bob.address(), &alice, 2).unwrap();
+    let (mutual, _, _) = noise::respond_peer(&bob, pkt).unwrap();
+
+    assert!(Endpoint::check_peer(&peers, &alice.identity(), &plain).is_ok());
+
+    peers.insert(alice.identity(), alice.address());
+    match Endpoint::check_peer(&peers, &alice.identity(), &plain) {
+        Err(Error::SecurityViolation) => (),
+        _ => panic!("expected SecurityViolation"),
+    }
+    assert!(Endpoint::check_peer(&peers, &alice.identity(), &mutual).is_ok());
+
+    peers.insert(alice.identity(), identity::Secret::gen().address());
+    assert!(Endpoint::check_peer(&peers, &alice.identity(), &mutual).is_err());
+}
+
+#[test]
+fn late_connects_are_disconnected() {
+    let alice = identity::Secret::gen();
+    let bob = identity::Secret::gen();
+
+    let (requester, pkt) = noise::initiate(None, &alice, 1).unwrap();
+    let (responder, _, _) = noise::respond(None, pkt).unwrap();
+    let (mut responder, pkt) = responder.send_response(7, &bob).unwrap();
+
+    let mut frame = Vec::new();
+    proto::ConnectResponse {
+        ok: true,
+        handshake: pkt.encode(),
+        route: 7,
+        paths: Vec::new(),
+    }
+    .encode(&mut frame)
+    .unwrap();
+    let pkt = disconnect_loser(requester, &frame).unwrap().unwrap();
+    let plain = responder.recv(EncryptedPacket::decode(&pkt).unwrap()).unwrap();
+    assert_eq!(Frame::decode(responder.version(), &plain[..]).unwrap(), vec![Frame::Disconnect]);
+
+    let mut frame = Vec::new();
+    proto::ConnectResponse {
+        ok: false,
+        handshake: Vec::new(),
+        route: 0,
+        paths: Vec::new(),
     }
+    .encode(&mut frame)
+    .unwrap();
+    let (requester, _) = noise::initiate(None, &alice, 2).unwrap();
+    assert!(disconnect_loser(requester, &frame).unwrap().is_none());
 }
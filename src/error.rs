@@ -1,13 +1,14 @@
 use bs58::decode::DecodeError;
 use ed25519_dalek::SignatureError;
 use osaka_dns;
-use packet::{RoutingDirection, RoutingKey};
+use packet::{self, RoutingDirection, RoutingKey};
 use prost;
 use snow::SnowError;
 use std::fmt;
 use std::io;
 use identity;
 use proto;
+use channel;
 
 #[derive(Debug)]
 pub enum Error {
@@ -45,7 +46,25 @@ pub enum Error {
     OutgoingConnectFailed {
         identity: identity::Identity,
         cr: Option<proto::ConnectResponse>,
-    }
+    },
+    InvalidCongestionController { name: String },
+    NoBroker,
+    NotOnLan { identity: identity::Identity },
+    NoRoute { route: RoutingKey },
+    EndpointGone,
+    StreamClosed,
+    MessageTooBig { len: usize },
+    HandshakeReplay { identity: identity::Identity, timestamp: u64, last: u64 },
+    ClockSkew { identity: identity::Identity, skew: i64 },
+    SecretStore { uri: String, reason: String },
+    ExternalSecret,
+    ConflictingSuccession {
+        predecessor: identity::Identity,
+        successor:   identity::Identity,
+        other:       identity::Identity,
+    },
+    InvalidMnemonic { reason: String },
+    Config { file: String, line: Option<usize>, reason: String },
 }
 
 impl fmt::Display for Error {
@@ -73,7 +92,9 @@ impl fmt::Display for Error {
             Error::WrongDirection{dir} =>
                 write!(f, "packet arrived with the same routing direction we're sending with {:?}", dir),
             Error::InvalidCookie => write!(f, "invalid cookie. probably a replay"),
-            Error::InvalidVersion{version} => write!(f, "invalid version: {}", version),
+            Error::InvalidVersion{version} => write!(
+                f, "unsupported version {:#04x}. this build speaks protocol versions {:#04x} to {:#04x}, upgrade carrier on the older side",
+                version, packet::VERSIONS[0], packet::VERSIONS[packet::VERSIONS.len() - 1]),
             Error::InvalidFrameType{typ} => write!(f, "invalid frame type: {}", typ),
             Error::Underflow { prev, this} => write!(
                 f, "stream underflow: incomming packet {} is too far ahead of previous {}",
@@ -93,6 +114,28 @@ impl fmt::Display for Error {
             Error::AccessDenied     => write!(f, "access denied: no certs left"),
             Error::NoMatchingGrant  => write!(f, "access denied: no matching grant in cert"),
             Error::OutgoingConnectFailed{identity, cr} => write!(f, "outgoing connection  to {} failed: {:?}", identity, cr),
+            Error::InvalidCongestionController{name} =>
+                write!(f, "unknown congestion controller '{}'. expected newreno, cubic or bbr", name),
+            Error::NoBroker => write!(f, "endpoint is not connected to a broker"),
+            Error::NotOnLan{identity} => write!(f, "{} did not announce itself on the local network", identity),
+            Error::NoRoute{route}   => write!(f, "no channel on route {:#x}", route),
+            Error::EndpointGone     => write!(f, "endpoint is no longer being polled"),
+            Error::StreamClosed     => write!(f, "stream was closed"),
+            Error::MessageTooBig{len} => write!(
+                f, "message of {} bytes does not fit a frame, it must be smaller than {}", len, channel::MAX_MESSAGE_SIZE),
+            Error::HandshakeReplay{identity, timestamp, last} => write!(
+                f, "replayed handshake from {}: timestamp {} is not newer than {}", identity, timestamp, last),
+            Error::ClockSkew{identity, skew} => write!(
+                f, "handshake from {} is {}ms off our clock. check both clocks or raise clock_skew in the config",
+                identity, skew),
+            Error::SecretStore{uri, reason} => write!(f, "secret store {}: {}", uri, reason),
+            Error::ExternalSecret => write!(f, "the secret is kept by an external signer and can't be read"),
+            Error::ConflictingSuccession{predecessor, successor, other} => write!(
+                f, "{} named both {} and {} as its successor. its secret may be compromised",
+                predecessor, successor, other),
+            Error::InvalidMnemonic{reason} => write!(f, "invalid mnemonic: {}", reason),
+            Error::Config{file, line: Some(line), reason} => write!(f, "{}:{}: {}", file, line, reason),
+            Error::Config{file, line: None, reason} => write!(f, "{}: {}", file, reason),
         }
     }
 }
@@ -0,0 +1,244 @@
+//! using an endpoint from other threads.
+//!
+//! the endpoint and everything it owns lives on the thread that polls it.
+//! a Handle sends commands into that poll loop over a channel, and can be cloned
+//! and moved to any number of worker threads. the endpoint picks the commands up
+//! the next time it is polled, which the channel itself triggers.
+
+use channel::MAX_MESSAGE_SIZE;
+use endpoint::{Event, Stream};
+use error::Error;
+use headers::Headers;
+use identity::Identity;
+use mio_extras::channel;
+use osaka::osaka;
+use packet::RoutingKey;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub enum Command {
+    Open {
+        route:   RoutingKey,
+        headers: Headers,
+        reply:   mpsc::Sender<Result<(u32, mpsc::Receiver<Vec<u8>>), Error>>,
+    },
+    Send {
+        route:   RoutingKey,
+        stream:  u32,
+        payload: Vec<u8>,
+    },
+    Close {
+        route:  RoutingKey,
+        stream: u32,
+    },
+    Subscribe(mpsc::Sender<Notice>),
+}
+
+/// what subscribers learn about the endpoint.
+/// connect requests still have to be answered on the poll thread,
+/// so subscribers only hear about channels once they are accepted
+#[derive(Clone, Debug)]
+pub enum Notice {
+    Connected {
+        route:    RoutingKey,
+        identity: Identity,
+    },
+    Disconnect {
+        route:    RoutingKey,
+        identity: Identity,
+    },
+    Datagram {
+        route:    RoutingKey,
+        identity: Identity,
+        payload:  Vec<u8>,
+    },
+    PathChanged {
+        route:    RoutingKey,
+        identity: Identity,
+        from:     SocketAddr,
+        to:       SocketAddr,
+    },
+}
+
+impl Notice {
+    /// the notice for an event, if subscribers get one
+    pub fn from_event(event: &Event) -> Option<Notice> {
+        match event {
+            Event::Disconnect { route, identity } => Some(Notice::Disconnect {
+                route:    *route,
+                identity: identity.clone(),
+            }),
+            Event::Datagram { route, identity, payload } => Some(Notice::Datagram {
+                route:    *route,
+                identity: identity.clone(),
+                payload:  payload.clone(),
+            }),
+            Event::PathChanged { route, identity, from, to } => Some(Notice::PathChanged {
+                route:    *route,
+                identity: identity.clone(),
+                from:     *from,
+                to:       *to,
+            }),
+            Event::IncommingConnect(_) | Event::OutgoingConnect(_) => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    tx: Arc<Mutex<channel::Sender<Command>>>,
+}
+
+impl Handle {
+    pub(crate) fn new(tx: channel::Sender<Command>) -> Self {
+        Self {
+            tx: Arc::new(Mutex::new(tx)),
+        }
+    }
+
+    fn command(&self, cmd: Command) -> Result<(), Error> {
+        self.tx
+            .lock()
+            .map_err(|_| Error::EndpointGone)?
+            .send(cmd)
+            .map_err(|_| Error::EndpointGone)
+    }
+
+    /// open a stream on the channel to route.
+    /// blocks until the poll thread has opened it
+    pub fn open(&self, route: RoutingKey, headers: Headers) -> Result<RemoteStream, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.command(Command::Open { route, headers, reply })?;
+        let (stream, incomming) = rx.recv().map_err(|_| Error::EndpointGone)??;
+        Ok(RemoteStream {
+            handle: self.clone(),
+            route,
+            stream,
+            incomming,
+        })
+    }
+
+    /// send one message. it has to fit a frame, the poll thread doesn't split it
+    pub fn send<M: Into<Vec<u8>>>(&self, route: RoutingKey, stream: u32, m: M) -> Result<(), Error> {
+        let payload = m.into();
+        if payload.len() >= MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooBig { len: payload.len() });
+        }
+        self.command(Command::Send {
+            route,
+            stream,
+            payload,
+        })
+    }
+
+    pub fn close(&self, route: RoutingKey, stream: u32) -> Result<(), Error> {
+        self.command(Command::Close { route, stream })
+    }
+
+    /// receive a Notice for everything that happens on the endpoint from now on.
+    /// dropping the receiver unsubscribes
+    pub fn subscribe(&self) -> Result<mpsc::Receiver<Notice>, Error> {
+        let (tx, rx) = mpsc::channel();
+        self.command(Command::Subscribe(tx))?;
+        Ok(rx)
+    }
+}
+
+/// a stream opened through a Handle.
+/// it is closed when dropped, or by the peer, after which recv fails
+pub struct RemoteStream {
+    handle:    Handle,
+    route:     RoutingKey,
+    stream:    u32,
+    incomming: mpsc::Receiver<Vec<u8>>,
+}
+
+impl RemoteStream {
+    pub fn route(&self) -> RoutingKey {
+        self.route
+    }
+
+    pub fn id(&self) -> u32 {
+        self.stream
+    }
+
+    pub fn send<M: Into<Vec<u8>>>(&self, m: M) -> Result<(), Error> {
+        self.handle.send(self.route, self.stream, m)
+    }
+
+    /// the next message from the peer. the first one is the peer's headers
+    pub fn recv(&self) -> Result<Vec<u8>, Error> {
+        self.incomming.recv().map_err(|_| Error::StreamClosed)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+        match self.incomming.recv_timeout(timeout) {
+            Ok(m) => Ok(Some(m)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::StreamClosed),
+        }
+    }
+}
+
+impl Drop for RemoteStream {
+    fn drop(&mut self) {
+        // nothing to close if the endpoint is already gone
+        self.handle.close(self.route, self.stream).ok();
+    }
+}
+
+/// stream driver on the poll thread, handing everything received to a RemoteStream
+#[osaka]
+pub(crate) fn forward(_poll: osaka::Poll, mut stream: Stream, tx: mpsc::Sender<Vec<u8>>) {
+    loop {
+        let m = osaka::sync!(stream);
+        if tx.send(m).is_err() {
+            debug!("stream {} was dropped by its handle", stream.id());
+            return;
+        }
+    }
+}
+
+#[test]
+fn handle_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Handle>();
+
+    fn assert_send<T: Send>() {}
+    assert_send::<RemoteStream>();
+    assert_send::<Notice>();
+}
+
+#[test]
+fn commands_fail_without_endpoint() {
+    let (tx, rx) = channel::channel();
+    let handle = Handle::new(tx);
+    assert!(handle.send(1, 2, vec![1, 2, 3]).is_ok());
+    match rx.try_recv() {
+        Ok(Command::Send { route, stream, payload }) => {
+            assert_eq!((route, stream, payload), (1, 2, vec![1, 2, 3]));
+        }
+        _ => panic!("expected a send command"),
+    }
+
+    drop(rx);
+    match handle.close(1, 2) {
+        Err(Error::EndpointGone) => (),
+        _ => panic!("expected EndpointGone"),
+    }
+    assert!(handle.subscribe().is_err());
+}
+
+#[test]
+fn oversized_messages() {
+    let (tx, rx) = channel::channel();
+    let handle = Handle::new(tx);
+    match handle.send(1, 2, vec![0; MAX_MESSAGE_SIZE]) {
+        Err(Error::MessageTooBig { len }) => assert_eq!(len, MAX_MESSAGE_SIZE),
+        _ => panic!("expected MessageTooBig"),
+    }
+    assert!(rx.try_recv().is_err(), "nothing may reach the poll thread");
+    assert!(handle.send(1, 2, vec![0; MAX_MESSAGE_SIZE - 1]).is_ok());
+}
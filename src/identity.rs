@@ -3,48 +3,128 @@ use bs58;
 use clear_on_drop::ClearOnDrop;
 use crc8;
 use ed25519_dalek;
+use mnemonic;
 use rand::RngCore;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
 use std::fmt;
 use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Identity([u8; 32]);
 #[derive(Clone)]
-pub struct Secret(ClearOnDrop<Box<[u8; 32]>>);
+pub struct Secret(Key);
 #[derive(Clone)]
 pub struct Signature([u8; 64]);
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Address([u8; 32]);
 #[derive(Clone)]
 pub struct SignedAddress(Address, Signature);
+/// an identity naming the identity that replaces it
+#[derive(Clone)]
+pub struct Succession {
+    pub predecessor: Identity,
+    pub successor:   Identity,
+    /// when the statement was made, in ms since the epoch
+    pub timestamp:   u64,
+    signature:       Signature,
+}
 
 // --- Secret
 
+#[derive(Clone)]
+enum Key {
+    Local(ClearOnDrop<Box<[u8; 32]>>),
+    External(Arc<External>),
+}
+
+struct External {
+    identity: Identity,
+    address:  Address,
+    signer:   Box<Signer>,
+}
+
+/// the secret key operations for a secret carrier never sees, like one in a hardware keystore.
+/// see keystore::Exec
+pub trait Signer: Send + Sync {
+    /// ed25519 signature over text
+    fn sign(&self, text: &[u8]) -> Result<Signature, Error>;
+
+    /// x25519 of the secret and a peer's public key
+    fn dh(&self, public: &[u8; 32]) -> Result<[u8; 32], Error>;
+}
 
 impl Secret {
+    /// a secret kept by signer, which belongs to identity and address
+    pub fn external(identity: Identity, address: Address, signer: Box<Signer>) -> Self {
+        Secret(Key::External(Arc::new(External {
+            identity,
+            address,
+            signer,
+        })))
+    }
+
+    fn local(&self) -> Result<&[u8; 32], Error> {
+        match self.0 {
+            Key::Local(ref k) => Ok(&**k),
+            Key::External(_) => Err(Error::ExternalSecret),
+        }
+    }
+
     pub fn identity(&self) -> Identity {
         use ed25519_dalek::{PublicKey, SecretKey};
-        let secret_key: SecretKey = SecretKey::from_bytes(&*self.0).unwrap();
+        let k = match self.0 {
+            Key::Local(ref k) => k,
+            Key::External(ref e) => return e.identity.clone(),
+        };
+        let secret_key: SecretKey = SecretKey::from_bytes(&**k).unwrap();
         let pk: PublicKey = (&secret_key).into();
         Identity::from_bytes(pk.as_bytes()).unwrap()
     }
 
     pub fn address(&self) -> Address {
-        Address(x25519_dalek::x25519(*self.0.clone(), x25519_dalek::X25519_BASEPOINT_BYTES))
+        match self.0 {
+            Key::Local(ref k) => Address(x25519_dalek::x25519(**k, x25519_dalek::X25519_BASEPOINT_BYTES)),
+            Key::External(ref e) => e.address.clone(),
+        }
     }
 
-    pub fn sign(&self, purpose: &[u8], text: &[u8]) -> Signature {
+    pub fn sign(&self, purpose: &[u8], text: &[u8]) -> Result<Signature, Error> {
         use ed25519_dalek::{PublicKey, SecretKey};
-        let sk: SecretKey = SecretKey::from_bytes(&*self.0).unwrap();
+        let mut stext = purpose.to_vec();
+        stext.extend_from_slice(&text);
+
+        let k = match self.0 {
+            Key::Local(ref k) => k,
+            Key::External(ref e) => {
+                // a signer answering for another key would only show up as failed handshakes later
+                let signature = e.signer.sign(&stext)?;
+                e.identity.verify(purpose, text, &signature)?;
+                return Ok(signature);
+            }
+        };
+        let sk: SecretKey = SecretKey::from_bytes(&**k).unwrap();
         let pk: PublicKey = (&sk).into();
 
         let kp = ed25519_dalek::Keypair {
             secret: sk,
             public: pk,
         };
-        let mut stext = purpose.to_vec();
-        stext.extend_from_slice(&text);
-        Signature(kp.sign(&stext).to_bytes())
+        Ok(Signature(kp.sign(&stext).to_bytes()))
+    }
+
+    /// x25519 of the secret and a peer's public key, the static part of a noise handshake
+    pub fn dh(&self, public: &[u8]) -> Result<[u8; 32], Error> {
+        if public.len() != 32 {
+            return Err(Error::InvalidLen);
+        }
+        let mut p = [0u8; 32];
+        p.copy_from_slice(public);
+        match self.0 {
+            Key::Local(ref k) => Ok(x25519_dalek::x25519(**k, p)),
+            Key::External(ref e) => e.signer.dh(&p),
+        }
     }
 
     pub fn gen() -> Self {
@@ -52,16 +132,18 @@ impl Secret {
         let mut a = [0u8; 32];
         let mut rng = OsRng::new().unwrap();
         rng.try_fill_bytes(&mut a).unwrap();
-        Secret(ClearOnDrop::new(Box::new(a)))
+        Self::from_array(a)
     }
 
     pub fn from_array(a: [u8; 32]) -> Self {
-        Secret(ClearOnDrop::new(Box::new(a)))
+        Secret(Key::Local(ClearOnDrop::new(Box::new(a))))
     }
 
-    pub fn as_bytes(&self) -> &[u8] {
-        &*self.0
+    /// the raw secret. fails for an external one
+    pub fn as_bytes(&self) -> Result<&[u8], Error> {
+        Ok(&self.local()?[..])
     }
+
     pub fn from_bytes<B: AsRef<[u8]>>(b: B) -> Result<Self, Error> {
         let b = b.as_ref();
         if b.len() != 32 {
@@ -72,19 +154,29 @@ impl Secret {
         Ok(Self::from_array(a))
     }
 
-    pub fn to_string(&self) -> String {
+    /// fails for an external secret
+    pub fn to_string(&self) -> Result<String, Error> {
         let mut v = Vec::new();
         v.push(8 as u8);
         v.push(3 as u8);
-        v.extend_from_slice(&*self.0);
+        v.extend_from_slice(self.local()?);
 
         let mut crc8 = crc8::Crc8::create_lsb(130);
         let crc = crc8.calc(&v.as_ref(), v.len() as i32, 0);
         v.push(crc);
 
-        bs58::encode(v)
+        Ok(bs58::encode(v)
             .with_alphabet(bs58::alphabet::BITCOIN)
-            .into_string()
+            .into_string())
+    }
+
+    /// 24 words to write down instead of to_string(), see mnemonic.rs
+    pub fn to_mnemonic(&self) -> Result<String, Error> {
+        mnemonic::encode(self.local()?)
+    }
+
+    pub fn from_mnemonic(phrase: &str) -> Result<Self, Error> {
+        Self::from_bytes(mnemonic::decode(phrase)?)
     }
 }
 
@@ -95,7 +187,7 @@ impl FromStr for Secret {
             .with_alphabet(bs58::alphabet::BITCOIN)
             .into_vec()?;
 
-        if s.len() < 35 {
+        if s.len() != 35 {
             return Err(Error::InvalidLen.into());
         }
 
@@ -116,7 +208,7 @@ impl FromStr for Secret {
         let mut a = [0u8; 32];
         a.copy_from_slice(&s[2..s.len() - 1]);
 
-        Ok(Secret(ClearOnDrop::new(Box::new(a))))
+        Ok(Secret::from_array(a))
     }
 }
 
@@ -170,7 +262,7 @@ impl FromStr for Address {
             .with_alphabet(bs58::alphabet::BITCOIN)
             .into_vec()?;
 
-        if s.len() < 35 {
+        if s.len() != 35 {
             return Err(Error::InvalidLen.into());
         }
 
@@ -254,7 +346,7 @@ impl FromStr for Signature {
         let s = bs58::decode(s)
             .with_alphabet(bs58::alphabet::BITCOIN)
             .into_vec()?;
-        if s.len() < 67 {
+        if s.len() != 67 {
             return Err(Error::InvalidLen.into());
         }
 
@@ -340,7 +432,7 @@ impl FromStr for Identity {
             .with_alphabet(bs58::alphabet::BITCOIN)
             .into_vec()?;
 
-        if s.len() < 35 {
+        if s.len() != 35 {
             return Err(Error::InvalidLen.into());
         }
 
@@ -379,9 +471,9 @@ impl fmt::Debug for Identity {
 // -- Signed Address
 
 impl SignedAddress {
-    pub fn sign(secret: &Secret, address: Address) -> SignedAddress {
-        let signature = secret.sign(b"carrier signed exchange address", &address.0);
-        SignedAddress(address, signature)
+    pub fn sign(secret: &Secret, address: Address) -> Result<SignedAddress, Error> {
+        let signature = secret.sign(b"carrier signed exchange address", &address.0)?;
+        Ok(SignedAddress(address, signature))
     }
     pub fn to_vec(&self) -> Vec<u8> {
         let mut b = (self.0).0.to_vec();
@@ -412,6 +504,150 @@ impl SignedAddress {
     }
 }
 
+// -- Succession
+
+const SUCCESSION_PURPOSE: &[u8] = b"carrier identity succession 1";
+
+impl Succession {
+    pub fn sign(predecessor: &Secret, successor: Identity, timestamp: u64) -> Result<Self, Error> {
+        let mut text = successor.0.to_vec();
+        text.extend_from_slice(&timestamp.to_be_bytes());
+        Ok(Succession {
+            predecessor: predecessor.identity(),
+            successor,
+            timestamp,
+            signature: predecessor.sign(SUCCESSION_PURPOSE, &text)?,
+        })
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        let mut text = self.successor.0.to_vec();
+        text.extend_from_slice(&self.timestamp.to_be_bytes());
+        self.predecessor.verify(SUCCESSION_PURPOSE, &text, &self.signature)
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut v = Vec::new();
+        v.push(8 as u8);
+        v.push(11 as u8);
+        v.extend_from_slice(&self.predecessor.0);
+        v.extend_from_slice(&self.successor.0);
+        v.extend_from_slice(&self.timestamp.to_be_bytes());
+        v.extend_from_slice(&self.signature.0);
+
+        let mut crc8 = crc8::Crc8::create_lsb(130);
+        let crc = crc8.calc(&v.as_ref(), v.len() as i32, 0);
+        v.push(crc);
+
+        bs58::encode(v)
+            .with_alphabet(bs58::alphabet::BITCOIN)
+            .into_string()
+    }
+}
+
+/// the signature is not checked here, see Successions::add
+impl FromStr for Succession {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = bs58::decode(s)
+            .with_alphabet(bs58::alphabet::BITCOIN)
+            .into_vec()?;
+        if s.len() != 2 + 32 + 32 + 8 + 64 + 1 {
+            return Err(Error::InvalidLen.into());
+        }
+
+        let mut crc8 = crc8::Crc8::create_lsb(130);
+        let crc = crc8.calc(&s, s.len() as i32 - 1, 0);
+
+        if crc != s[s.len() - 1] {
+            return Err(Error::InvalidAddress.into());
+        }
+
+        if s[0] != 8 {
+            return Err(Error::InvalidAddress.into());
+        }
+        if s[1] != 11 {
+            return Err(Error::InvalidAddressType{need: address_type_name(11), got: address_type_name(s[1])}.into());
+        }
+
+        Ok(Succession {
+            predecessor: Identity::from_bytes(&s[2..34])?,
+            successor:   Identity::from_bytes(&s[34..66])?,
+            timestamp:   BigEndian::read_u64(&s[66..74]),
+            signature:   Signature::from_bytes(&s[74..138])?,
+        })
+    }
+}
+
+impl fmt::Display for Succession {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        self.to_string().fmt(fmt)
+    }
+}
+
+/// verified succession statements.
+/// a retired identity keeps nothing: what was granted to it now belongs to its successor
+#[derive(Clone, Default)]
+pub struct Successions {
+    next: HashMap<Identity, Identity>,
+}
+
+impl Successions {
+    /// successions may form chains, but an identity only ever has one successor.
+    /// a second, different one means the old secret signed something it shouldn't have
+    pub fn add(&mut self, s: Succession) -> Result<(), Error> {
+        s.verify()?;
+        if s.predecessor == s.successor {
+            return Err(Error::SecurityViolation);
+        }
+        if let Some(existing) = self.next.get(&s.predecessor) {
+            if *existing == s.successor {
+                return Ok(());
+            }
+            return Err(Error::ConflictingSuccession {
+                predecessor: s.predecessor,
+                successor:   existing.clone(),
+                other:       s.successor,
+            });
+        }
+        self.next.insert(s.predecessor, s.successor);
+        Ok(())
+    }
+
+    pub fn is_retired(&self, identity: &Identity) -> bool {
+        self.next.contains_key(identity)
+    }
+
+    /// the identity that now stands for identity, at the end of its chain of successors
+    pub fn current(&self, identity: &Identity) -> Identity {
+        let mut cur = identity;
+        // a chain can't be longer than the number of statements, unless it loops
+        for _ in 0..self.next.len() {
+            match self.next.get(cur) {
+                Some(next) => cur = next,
+                None => break,
+            }
+        }
+        cur.clone()
+    }
+
+    /// identity and every identity it succeeded
+    pub fn lineage(&self, identity: &Identity) -> Vec<Identity> {
+        let mut r = vec![identity.clone()];
+        loop {
+            let before = {
+                let last = r.last().unwrap();
+                match self.next.iter().find(|(_, next)| *next == last) {
+                    Some((pred, _)) if !r.contains(pred) => pred.clone(),
+                    _ => break,
+                }
+            };
+            r.push(before);
+        }
+        r
+    }
+}
+
 pub fn generate_x25519() -> (Secret, [u8; 32]) {
     let secret = Secret::gen();
     let public = secret.address();
@@ -426,6 +662,7 @@ fn address_type_name(t: u8) -> String {
         3 =>"Secret".into(),
         6 => "Address".into(),
         9 => "Identity".into(),
+        11 => "Succession".into(),
         a => format!("unknown({})", a),
     }
 }
@@ -462,7 +699,7 @@ fn sign() {
     let client_identity = client_secret.identity();
 
     let text = b"beeb bob";
-    let signature = client_secret.sign(b"goes on postcards", text);
+    let signature = client_secret.sign(b"goes on postcards", text).unwrap();
     assert!(client_identity
         .verify(b"goes on postcards", text, &signature)
         .is_ok());
@@ -489,3 +726,71 @@ fn sign() {
         .verify(b"goes on postcards", &text_invalid, &signature)
         .is_err());
 }
+
+#[test]
+fn succession() {
+    let (a, b, c) = (Secret::gen(), Secret::gen(), Secret::gen());
+    let ab = Succession::sign(&a, b.identity(), 1).unwrap();
+    let bc = Succession::sign(&b, c.identity(), 2).unwrap();
+
+    let parsed: Succession = ab.to_string().parse().unwrap();
+    assert!(parsed.verify().is_ok());
+    assert_eq!(parsed.successor, b.identity());
+
+    // signed by someone who isn't the predecessor
+    let mut forged = Succession::sign(&c, Secret::gen().identity(), 3).unwrap();
+    forged.predecessor = b.identity();
+    assert!(forged.verify().is_err());
+
+    let mut s = Successions::default();
+    s.add(bc).unwrap();
+    s.add(ab.clone()).unwrap();
+    s.add(ab).unwrap();
+    assert!(s.add(forged).is_err());
+    assert!(s.add(Succession::sign(&a, c.identity(), 4).unwrap()).is_err());
+
+    assert_eq!(s.current(&a.identity()), c.identity());
+    assert_eq!(s.current(&c.identity()), c.identity());
+    assert!(s.is_retired(&a.identity()) && !s.is_retired(&c.identity()));
+    assert_eq!(s.lineage(&c.identity()), vec![c.identity(), b.identity(), a.identity()]);
+}
+
+#[test]
+fn round_trip() {
+    let secret = Secret::gen();
+    let succession = Succession::sign(&secret, Secret::gen().identity(), 1234).unwrap();
+    let encoded = vec![
+        (secret.to_string().unwrap(), "Secret"),
+        (secret.identity().to_string(), "Identity"),
+        (secret.address().to_string(), "Address"),
+        (secret.sign(b"test", b"text").unwrap().to_string(), "Signature"),
+        (succession.to_string(), "Succession"),
+    ];
+
+    assert_eq!(encoded[0].0.parse::<Secret>().unwrap().as_bytes().unwrap(), secret.as_bytes().unwrap());
+    assert_eq!(encoded[1].0.parse::<Identity>().unwrap(), secret.identity());
+    assert_eq!(encoded[2].0.parse::<Address>().unwrap(), secret.address());
+    assert_eq!(encoded[3].0.parse::<Signature>().unwrap().as_bytes(), secret.sign(b"test", b"text").unwrap().as_bytes());
+    let parsed = encoded[4].0.parse::<Succession>().unwrap();
+    parsed.verify().unwrap();
+    assert_eq!((parsed.predecessor, parsed.successor, parsed.timestamp),
+               (succession.predecessor, succession.successor, 1234));
+
+    // every type refuses the others
+    for (s, name) in &encoded {
+        let results = vec![
+            ("Secret", s.parse::<Secret>().err()),
+            ("Identity", s.parse::<Identity>().err()),
+            ("Address", s.parse::<Address>().err()),
+            ("Signature", s.parse::<Signature>().err()),
+            ("Succession", s.parse::<Succession>().err()),
+        ];
+        for (typ, err) in results {
+            assert_eq!(err.is_none(), typ == *name, "{} parsed as {}", name, typ);
+        }
+    }
+
+    let words = secret.to_mnemonic().unwrap();
+    assert_eq!(words.split(' ').count(), 24);
+    assert_eq!(Secret::from_mnemonic(&words).unwrap().as_bytes().unwrap(), secret.as_bytes().unwrap());
+}
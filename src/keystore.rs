@@ -0,0 +1,580 @@
+//! where the identity secret is kept.
+//!
+//! the `secret` key in the config picks a store with a uri-like prefix:
+//!
+//! ```text
+//! secret = "3fMd..."                          the secret itself, base58
+//! secret = "file:/etc/carrier/secret"         a file with the base58 secret, readable only by its owner
+//! secret = "encrypted:/etc/carrier/secret"    a file encrypted with a passphrase,
+//!                                             from CARRIER_PASSPHRASE or asked on the terminal
+//! secret = "mtd:/dev/mtd3?offset=40"          32 raw bytes on a flash partition
+//! secret = "mtdname:art?offset=40"            the same, by partition name from /proc/mtd
+//! secret = "exec:/usr/bin/carrier-keys arg"   a helper process that never hands out the secret, see Exec
+//! ```
+//!
+//! the older `:mtd:/dev/mtd3:40` and `:mtdname:art:40` forms still work.
+
+use bs58;
+use error::Error;
+use identity::{Address, Identity, Secret, Signature, Signer};
+use libc;
+use mtdparts::parse_mtd;
+use rand::{self, thread_rng, RngCore};
+use scrypt;
+use snow::params::CipherChoice;
+use snow::resolvers::{CryptoResolver, HaclStarResolver};
+use snow::types::Cipher;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+pub trait SecretStore {
+    /// the stored secret
+    fn load(&mut self) -> Result<Secret, Error>;
+
+    /// replace the stored secret
+    fn store(&mut self, secret: &Secret) -> Result<(), Error>;
+}
+
+fn fail<U: Into<String>, R: ToString>(uri: U, reason: R) -> Error {
+    Error::SecretStore {
+        uri:    uri.into(),
+        reason: reason.to_string(),
+    }
+}
+
+/// the store that a `secret = ` config value names
+pub fn open(uri: &str) -> Result<Box<SecretStore>, Error> {
+    if uri.starts_with(":") {
+        return legacy(uri);
+    }
+
+    let (scheme, rest) = match uri.find(':') {
+        Some(i) => (&uri[..i], &uri[i + 1..]),
+        None => return Ok(Box::new(Inline(uri.to_string()))),
+    };
+    let (location, offset) = match rest.find("?offset=") {
+        Some(i) => {
+            let offset = rest[i + 8..]
+                .parse()
+                .map_err(|e| fail(uri, format!("offset: {}", e)))?;
+            (&rest[..i], Some(offset))
+        }
+        None => (rest, None),
+    };
+
+    match scheme {
+        "file" => Ok(Box::new(PlainFile {
+            path: location.into(),
+        })),
+        "encrypted" => Ok(Box::new(Encrypted {
+            path:       location.into(),
+            cost:       ENCRYPTED_COST,
+            passphrase: None,
+        })),
+        "mtd" => Ok(Box::new(Mtd {
+            device: location.into(),
+            offset: offset.unwrap_or(MTD_OFFSET),
+        })),
+        "mtdname" => Ok(Box::new(Mtd {
+            device: mtd_device(location)?,
+            offset: offset.unwrap_or(MTD_OFFSET),
+        })),
+        "exec" if location.trim().is_empty() => Err(fail(uri, "no command")),
+        "exec" => Ok(Box::new(Exec {
+            command: location.split_whitespace().map(String::from).collect(),
+        })),
+        _ => Err(fail(uri, format!("unknown secret store '{}'", scheme))),
+    }
+}
+
+/// `:mtd:<device>:<offset>` and `:mtdname:<name>:<offset>`
+fn legacy(uri: &str) -> Result<Box<SecretStore>, Error> {
+    let s: Vec<&str> = uri.split(':').collect();
+    let offset = match s.get(3) {
+        Some(v) => v.parse().map_err(|e| fail(uri, format!("offset: {}", e)))?,
+        None => MTD_OFFSET,
+    };
+    match (s.get(1), s.get(2)) {
+        (Some(&"mtd"), Some(device)) => Ok(Box::new(Mtd {
+            device: device.to_string(),
+            offset,
+        })),
+        (Some(&"mtdname"), Some(name)) => Ok(Box::new(Mtd {
+            device: mtd_device(name)?,
+            offset,
+        })),
+        _ => Err(fail(uri, "expected :mtd:<device>:<offset> or :mtdname:<name>:<offset>")),
+    }
+}
+
+/// the secret written straight into the config
+pub struct Inline(String);
+
+impl SecretStore for Inline {
+    fn load(&mut self) -> Result<Secret, Error> {
+        self.0.parse()
+    }
+
+    fn store(&mut self, _: &Secret) -> Result<(), Error> {
+        Err(fail("inline", "the secret is in the config file, edit it there"))
+    }
+}
+
+/// a file holding the base58 secret.
+/// refuses to load it when anyone but the owner can read it
+pub struct PlainFile {
+    pub path: PathBuf,
+}
+
+/// only the owner may read or write a file with a secret in it
+fn check_permissions(path: &PathBuf) -> Result<(), Error> {
+    let mode = path
+        .metadata()
+        .map_err(|e| fail(path.to_string_lossy(), e))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(fail(
+            path.to_string_lossy(),
+            format!("permissions {:o} let others read the secret. run chmod 600 on it", mode & 0o777),
+        ));
+    }
+    Ok(())
+}
+
+/// replace path with content, created with mode 0600
+fn write_private(path: &PathBuf, content: &[u8]) -> Result<(), Error> {
+    let r: u64 = rand::random();
+    let mut path2 = path.clone().into_os_string();
+    path2.push(format!(".{}", r));
+    let path2 = PathBuf::from(path2);
+
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path2)
+        .map_err(|e| fail(path2.to_string_lossy(), e))?;
+    f.write_all(content)?;
+    f.sync_all()?;
+    ::std::fs::rename(&path2, path)?;
+    Ok(())
+}
+
+impl SecretStore for PlainFile {
+    fn load(&mut self) -> Result<Secret, Error> {
+        check_permissions(&self.path)?;
+        let mut b = String::new();
+        File::open(&self.path)
+            .and_then(|mut f| f.read_to_string(&mut b))
+            .map_err(|e| fail(self.path.to_string_lossy(), e))?;
+        b.trim().parse()
+    }
+
+    fn store(&mut self, secret: &Secret) -> Result<(), Error> {
+        write_private(&self.path, format!("{}\n", secret.to_string()?).as_bytes())
+    }
+}
+
+const ENCRYPTED_MAGIC: &[u8; 4] = b"CKS1";
+/// magic, cost and salt, which the mac covers too
+const ENCRYPTED_HEADER: usize = 4 + 1 + 16;
+const ENCRYPTED_LEN: usize = ENCRYPTED_HEADER + 32 + 16;
+/// log2 of the scrypt cost
+const ENCRYPTED_COST: u8 = 15;
+
+/// a file with the secret encrypted by a passphrase.
+///
+/// the layout is magic, scrypt cost, salt, and the secret sealed with chacha20-poly1305
+/// under scrypt(passphrase, salt), with the header as associated data.
+/// the salt is new on every write, so a key never seals twice.
+/// a wrong passphrase and a tampered file both fail the mac
+pub struct Encrypted {
+    pub path:   PathBuf,
+    cost:       u8,
+    passphrase: Option<String>,
+}
+
+impl Encrypted {
+    /// a passphrase from CARRIER_PASSPHRASE, or from the terminal
+    fn passphrase(&mut self) -> Result<String, Error> {
+        if let Some(ref p) = self.passphrase {
+            return Ok(p.clone());
+        }
+        let p = match env::var("CARRIER_PASSPHRASE") {
+            Ok(p) => p,
+            Err(_) => ask(&format!("passphrase for {}: ", self.path.to_string_lossy()))?,
+        };
+        self.passphrase = Some(p.clone());
+        Ok(p)
+    }
+
+    fn key(&self, passphrase: &str, cost: u8, salt: &[u8]) -> Result<[u8; 32], Error> {
+        let params = scrypt::ScryptParams::new(cost, 8, 1)
+            .map_err(|_| fail(self.path.to_string_lossy(), format!("invalid scrypt cost {}", cost)))?;
+        let mut key = [0u8; 32];
+        scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+            .map_err(|_| fail(self.path.to_string_lossy(), "scrypt failed"))?;
+        Ok(key)
+    }
+
+    fn cipher(&self, key: &[u8; 32]) -> Result<Box<Cipher>, Error> {
+        let mut cipher = HaclStarResolver::default()
+            .resolve_cipher(&CipherChoice::ChaChaPoly)
+            .ok_or_else(|| fail(self.path.to_string_lossy(), "no chacha20-poly1305"))?;
+        cipher.set(key);
+        Ok(cipher)
+    }
+}
+
+impl SecretStore for Encrypted {
+    fn load(&mut self) -> Result<Secret, Error> {
+        check_permissions(&self.path)?;
+        let mut b = Vec::new();
+        File::open(&self.path)
+            .and_then(|mut f| f.read_to_end(&mut b))
+            .map_err(|e| fail(self.path.to_string_lossy(), e))?;
+        if b.len() != ENCRYPTED_LEN || &b[..4] != ENCRYPTED_MAGIC {
+            return Err(fail(self.path.to_string_lossy(), "not an encrypted carrier secret"));
+        }
+
+        let passphrase = self.passphrase()?;
+        let key = self.key(&passphrase, b[4], &b[5..ENCRYPTED_HEADER])?;
+        let mut a = [0u8; 32 + 16];
+        let (header, sealed) = b.split_at(ENCRYPTED_HEADER);
+        if self.cipher(&key)?.decrypt(0, header, sealed, &mut a).is_err() {
+            self.passphrase = None;
+            return Err(fail(self.path.to_string_lossy(), "wrong passphrase or damaged file"));
+        }
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&a[..32]);
+        Ok(Secret::from_array(secret))
+    }
+
+    fn store(&mut self, secret: &Secret) -> Result<(), Error> {
+        let passphrase = self.passphrase()?;
+        let mut salt = [0u8; 16];
+        thread_rng().fill_bytes(&mut salt);
+        let key = self.key(&passphrase, self.cost, &salt)?;
+
+        let mut b = Vec::with_capacity(ENCRYPTED_LEN);
+        b.extend_from_slice(ENCRYPTED_MAGIC);
+        b.push(self.cost);
+        b.extend_from_slice(&salt);
+        let mut sealed = [0u8; 32 + 16];
+        self.cipher(&key)?.encrypt(0, &b, secret.as_bytes()?, &mut sealed);
+        b.extend_from_slice(&sealed);
+        write_private(&self.path, &b)
+    }
+}
+
+/// read a line from the terminal without echo
+fn ask(prompt: &str) -> Result<String, Error> {
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|e| fail("/dev/tty", format!("no passphrase in CARRIER_PASSPHRASE and no terminal: {}", e)))?;
+    tty.write_all(prompt.as_bytes())?;
+
+    let fd = tty.as_raw_fd();
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    let echo_off = unsafe { libc::tcgetattr(fd, &mut termios) } == 0;
+    if echo_off {
+        let mut silent = termios.clone();
+        silent.c_lflag &= !libc::ECHO;
+        unsafe {
+            libc::tcsetattr(fd, libc::TCSANOW, &silent);
+        }
+    }
+
+    let mut line = String::new();
+    let r = BufReader::new(&tty).read_line(&mut line);
+
+    if echo_off {
+        unsafe {
+            libc::tcsetattr(fd, libc::TCSANOW, &termios);
+        }
+    }
+    tty.write_all(b"\n")?;
+    r?;
+    Ok(line.trim_end_matches(|c| c == '\n' || c == '\r').to_string())
+}
+
+const MTD_OFFSET: u64 = 40;
+
+/// 32 raw bytes at offset on a flash partition.
+/// an erased or zeroed partition is initialized with a new random secret on first load
+pub struct Mtd {
+    pub device: String,
+    pub offset: u64,
+}
+
+fn mtd_device(name: &str) -> Result<String, Error> {
+    let f = File::open("/proc/mtd").map_err(|e| fail("/proc/mtd", e))?;
+    let names = parse_mtd(f).map_err(|e| fail("/proc/mtd", format!("{:?}", e)))?;
+    match names.get(name) {
+        Some(dev) => Ok(format!("/dev/{}", dev)),
+        None => Err(fail(format!("mtdname:{}", name), "no such partition in /proc/mtd")),
+    }
+}
+
+impl Mtd {
+    fn open(&self) -> Result<File, Error> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.device)
+            .map_err(|e| fail(self.device.as_str(), e))?;
+        f.seek(SeekFrom::Start(self.offset))?;
+        Ok(f)
+    }
+}
+
+impl SecretStore for Mtd {
+    fn load(&mut self) -> Result<Secret, Error> {
+        let mut b = [0u8; 32];
+        self.open()?.read_exact(&mut b)?;
+
+        if b == [0xff; 32] || b == [0x0; 32] {
+            let secret = Secret::gen();
+            self.store(&secret)?;
+            return Ok(secret);
+        }
+        Ok(Secret::from_array(b))
+    }
+
+    fn store(&mut self, secret: &Secret) -> Result<(), Error> {
+        let secret = secret.as_bytes()?;
+        let mut f = self.open()?;
+        f.write_all(secret)?;
+        f.sync_all()?;
+        Ok(())
+    }
+}
+
+/// a helper process that keeps the secret, for example in a hardware keystore.
+/// it signs and does key agreement for carrier, the secret itself never leaves it.
+///
+/// carrier starts it for every request, writes one request line to its stdin
+/// and reads one answer line from its stdout:
+///
+/// ```text
+/// > public
+/// < <identity> <address>
+/// > sign <base58 text>
+/// < <signature>
+/// > dh <base58 public key>
+/// < <base58 shared secret>
+/// > store <secret>
+/// < ok
+/// ```
+///
+/// sign is ed25519 over the text, dh is x25519 of the secret and the public key.
+/// identity, address, signature and secret are in carrier's own encoding.
+/// handshakes wait for the helper, so it should answer quickly
+pub struct Exec {
+    pub command: Vec<String>,
+}
+
+impl Exec {
+    fn uri(&self) -> String {
+        format!("exec:{}", self.command.join(" "))
+    }
+
+    fn request(&self, line: &str) -> Result<String, Error> {
+        let uri = self.uri();
+        let program = match self.command.first() {
+            Some(v) => v,
+            None => return Err(fail(uri, "no command")),
+        };
+        let mut child = Command::new(program)
+            .args(&self.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| fail(uri.as_str(), e))?;
+
+        {
+            let stdin = child.stdin.as_mut().unwrap();
+            writeln!(stdin, "{}", line).map_err(|e| fail(uri.as_str(), e))?;
+        }
+        drop(child.stdin.take());
+
+        let mut answer = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut answer)
+            .map_err(|e| fail(uri.as_str(), e))?;
+
+        let status = child.wait().map_err(|e| fail(uri.as_str(), e))?;
+        if !status.success() {
+            return Err(fail(uri, format!("helper exited with {}", status)));
+        }
+        Ok(answer.trim().to_string())
+    }
+}
+
+impl SecretStore for Exec {
+    /// only the public half, the secret stays with the helper
+    fn load(&mut self) -> Result<Secret, Error> {
+        let answer = self.request("public")?;
+        let public: Vec<&str> = answer.split_whitespace().collect();
+        if public.len() != 2 {
+            return Err(fail(self.uri(), format!("helper answered '{}' to public", answer)));
+        }
+        let identity: Identity = public[0].parse()?;
+        let address: Address = public[1].parse()?;
+        let signer = Exec {
+            command: self.command.clone(),
+        };
+        Ok(Secret::external(identity, address, Box::new(signer)))
+    }
+
+    fn store(&mut self, secret: &Secret) -> Result<(), Error> {
+        match self.request(&format!("store {}", secret.to_string()?))?.as_str() {
+            "ok" => Ok(()),
+            other => Err(fail(self.uri(), format!("helper answered '{}'", other))),
+        }
+    }
+}
+
+impl Signer for Exec {
+    fn sign(&self, text: &[u8]) -> Result<Signature, Error> {
+        let text = bs58::encode(text)
+            .with_alphabet(bs58::alphabet::BITCOIN)
+            .into_string();
+        self.request(&format!("sign {}", text))?.parse()
+    }
+
+    fn dh(&self, public: &[u8; 32]) -> Result<[u8; 32], Error> {
+        let public = bs58::encode(&public[..])
+            .with_alphabet(bs58::alphabet::BITCOIN)
+            .into_string();
+        let b = bs58::decode(self.request(&format!("dh {}", public))?)
+            .with_alphabet(bs58::alphabet::BITCOIN)
+            .into_vec()?;
+        if b.len() != 32 {
+            return Err(fail(self.uri(), format!("helper answered {} bytes to dh", b.len())));
+        }
+        let mut shared = [0u8; 32];
+        shared.copy_from_slice(&b);
+        Ok(shared)
+    }
+}
+
+#[cfg(test)]
+fn scratch(name: &str) -> PathBuf {
+    let r: u64 = rand::random();
+    env::temp_dir().join(format!("carrier-keystore-{}-{}", name, r))
+}
+
+#[test]
+fn plain_file() {
+    let path = scratch("plain");
+    let mut store = open(&format!("file:{}", path.to_string_lossy())).unwrap();
+    assert!(store.load().is_err());
+
+    let secret = Secret::gen();
+    store.store(&secret).unwrap();
+    assert_eq!(store.load().unwrap().identity(), secret.identity());
+
+    ::std::fs::set_permissions(&path, ::std::fs::Permissions::from_mode(0o644)).unwrap();
+    match store.load() {
+        Err(Error::SecretStore { ref reason, .. }) if reason.contains("chmod") => (),
+        _ => panic!("expected a permission error"),
+    }
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn encrypted_file() {
+    let path = scratch("encrypted");
+    let secret = Secret::gen();
+    let mut store = Encrypted {
+        path:       path.clone(),
+        cost:       4,
+        passphrase: Some("correct horse".into()),
+    };
+    store.store(&secret).unwrap();
+    assert_eq!(store.load().unwrap().identity(), secret.identity());
+
+    let mut raw = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut raw).unwrap();
+    assert!(!raw.windows(32).any(|w| w == secret.as_bytes().unwrap()));
+
+    let mut wrong = Encrypted {
+        path:       path.clone(),
+        cost:       4,
+        passphrase: Some("battery staple".into()),
+    };
+    match wrong.load() {
+        Err(Error::SecretStore { ref reason, .. }) if reason.starts_with("wrong passphrase") => (),
+        _ => panic!("expected wrong passphrase"),
+    }
+
+    // a flipped bit anywhere, including the salt, fails the mac
+    for i in &[10, ENCRYPTED_LEN - 1] {
+        let mut tampered = raw.clone();
+        tampered[*i] ^= 1;
+        write_private(&path, &tampered).unwrap();
+        let mut store = Encrypted {
+            path:       path.clone(),
+            cost:       4,
+            passphrase: Some("correct horse".into()),
+        };
+        assert!(store.load().is_err());
+    }
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn exec_helper() {
+    let secret = Secret::gen();
+    let peer = Secret::gen().address();
+    let shared = secret.dh(peer.as_bytes()).unwrap();
+    let path = scratch("helper");
+    {
+        let mut f = OpenOptions::new().write(true).create(true).mode(0o700).open(&path).unwrap();
+        write!(
+            f,
+            "#!/bin/sh\nread cmd arg\ncase $cmd in\n  public) echo {} {} ;;\n  sign) echo {} ;;\n  \
+             dh) echo {} ;;\n  store) echo ok ;;\n  *) exit 1 ;;\nesac\n",
+            secret.identity().to_string(),
+            secret.address().to_string(),
+            secret.sign(b"test", b"text").unwrap().to_string(),
+            bs58::encode(&shared[..]).with_alphabet(bs58::alphabet::BITCOIN).into_string(),
+        )
+        .unwrap();
+    }
+    let mut store = open(&format!("exec:{}", path.to_string_lossy())).unwrap();
+    let external = store.load().unwrap();
+    assert_eq!(external.identity(), secret.identity());
+    assert_eq!(external.address(), secret.address());
+    assert!(external.to_string().is_err());
+
+    external.sign(b"test", b"text").unwrap();
+    // the helper's canned answer is a signature over something else
+    assert!(external.sign(b"test", b"other text").is_err());
+    assert_eq!(external.dh(peer.as_bytes()).unwrap(), shared);
+
+    store.store(&Secret::gen()).unwrap();
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn uris() {
+    let secret = Secret::gen();
+    assert_eq!(open(&secret.to_string().unwrap()).unwrap().load().unwrap().identity(), secret.identity());
+    assert!(open("tpm:0").is_err());
+    assert!(open("exec:/bin/true").is_ok());
+    assert!(open("exec: ").is_err());
+    assert!(open("mtd:/dev/mtd3?offset=x").is_err());
+    assert!(open(":mtd:/dev/mtd3:40").is_ok());
+    assert!(open(":nope").is_err());
+}
@@ -0,0 +1,141 @@
+//! finding publishers on the local network, without a broker.
+//!
+//! a publisher with lan announcements enabled multicasts its identity and shadow
+//! from its endpoint socket every few seconds. the announcement is signed by the identity,
+//! so a listener knows which identity is reachable at the source address,
+//! and can then handshake with it directly using Endpoint::connect_direct.
+//!
+//! announcements go to an administratively scoped ipv4 group with a ttl of 1,
+//! so they don't leave the link. they are not encrypted: anyone on the link
+//! learns which identities are published there.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use error::Error;
+use identity::{Address, Identity, Secret, Signature};
+use osaka::mio;
+use osaka::osaka;
+use socket::Socket;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const MAGIC: [u8; 4] = [b'C', b'L', b'A', 0x01];
+
+const SIGNED_LEN: usize = 4 + 32 + 32 + 8;
+
+/// port the announcement group is on
+pub const PORT: u16 = 8445;
+
+/// how often a publisher announces itself, in seconds
+pub const ANNOUNCE_INTERVAL: u64 = 5;
+
+fn group_ip() -> Ipv4Addr {
+    Ipv4Addr::new(239, 255, 67, 82)
+}
+
+/// where announcements are sent to
+pub fn group() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(group_ip()), PORT)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Announcement {
+    pub identity:  Identity,
+    pub shadow:    Address,
+    /// ms since the unix epoch on the publisher's clock. only used to tell announcements apart
+    pub timestamp: u64,
+}
+
+pub fn announcement(secret: &Secret, shadow: &Address, timestamp: u64) -> Result<Vec<u8>, Error> {
+    let mut w = Vec::with_capacity(SIGNED_LEN + 64);
+    w.extend_from_slice(&MAGIC);
+    w.extend_from_slice(secret.identity().as_bytes());
+    w.extend_from_slice(shadow.as_bytes());
+    w.write_u64::<BigEndian>(timestamp).unwrap();
+    let signature = secret.sign(b"carrier lan announcement 1", &w)?;
+    w.extend_from_slice(signature.as_bytes());
+    Ok(w)
+}
+
+/// decode an announcement and check its signature
+pub fn decode(buf: &[u8]) -> Option<Announcement> {
+    if buf.len() != SIGNED_LEN + 64 || buf[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let identity = Identity::from_bytes(&buf[4..36]).ok()?;
+    let shadow = Address::from_bytes(&buf[36..68]).ok()?;
+    let timestamp = (&buf[68..76]).read_u64::<BigEndian>().ok()?;
+    let signature = Signature::from_bytes(&buf[SIGNED_LEN..]).ok()?;
+
+    if let Err(e) = identity.verify(b"carrier lan announcement 1", &buf[..SIGNED_LEN], &signature) {
+        debug!("announcement from {} with invalid signature: {}", identity, e);
+        return None;
+    }
+
+    Some(Announcement {
+        identity,
+        shadow,
+        timestamp,
+    })
+}
+
+/// wait for target to announce itself, and return the address of its endpoint
+#[osaka]
+pub fn discover(poll: osaka::Poll, target: Identity, timeout: Duration) -> Result<SocketAddr, Error> {
+    let deadline = Instant::now() + timeout;
+    let socket = Socket::multicast(group_ip(), PORT)?;
+    let token = poll
+        .register(&socket, mio::Ready::readable(), mio::PollOpt::level())
+        .unwrap();
+    info!("looking for {} on the local network", target);
+
+    loop {
+        let mut buf = [0; 256];
+        match socket.recv_from(&mut buf) {
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::WouldBlock {
+                    return Err(Error::Io(e));
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(Error::NotOnLan { identity: target });
+                }
+                yield poll.again(token.clone(), Some(deadline - now));
+            }
+            Ok((len, addr)) => match decode(&buf[..len]) {
+                Some(ref a) if a.identity == target => {
+                    info!("found {} at {}", target, addr);
+                    return Ok(addr);
+                }
+                Some(a) => trace!("ignoring announcement of {} from {}", a.identity, addr),
+                None => trace!("ignoring {} bytes from {}", len, addr),
+            },
+        }
+    }
+}
+
+#[test]
+fn signed_announcement() {
+    let secret = Secret::gen();
+    let shadow = Secret::gen().address();
+
+    let a = announcement(&secret, &shadow, 1234).unwrap();
+    assert_eq!(
+        decode(&a),
+        Some(Announcement {
+            identity:  secret.identity(),
+            shadow:    shadow.clone(),
+            timestamp: 1234,
+        })
+    );
+
+    // claiming someone else's identity doesn't verify
+    let mut forged = a.clone();
+    forged[4..36].copy_from_slice(Secret::gen().identity().as_bytes());
+    assert_eq!(decode(&forged), None);
+
+    let mut tampered = a.clone();
+    tampered[70] ^= 1;
+    assert_eq!(decode(&tampered), None);
+
+    assert_eq!(decode(&a[..a.len() - 1]), None);
+}
@@ -34,6 +34,11 @@ extern crate mio_extras;
 extern crate num_cpus;
 extern crate wait_timeout;
 extern crate mtdparts;
+extern crate scrypt;
+#[cfg(feature = "tokio-compat")]
+extern crate futures;
+#[cfg(feature = "tokio-compat")]
+extern crate tokio;
 
 #[macro_use]
 #[cfg(target_arch = "wasm32")]
@@ -41,18 +46,30 @@ extern crate wasm_bindgen;
 
 pub mod channel;
 pub mod clock;
+#[cfg(feature = "tokio-compat")]
+pub mod compat;
 pub mod config;
+pub mod congestion;
 pub mod dns;
 pub mod endpoint;
 pub mod error;
+pub mod handle;
 pub mod headers;
 pub mod identity;
+pub mod keystore;
+pub mod lan;
 pub mod local_addrs;
+pub mod mnemonic;
 pub mod noise;
 pub mod packet;
+pub mod paths;
+pub mod pmtu;
 pub mod recovery;
+pub mod reflect;
 pub mod replay;
+pub mod socket;
 pub mod stream;
+pub mod timestamps;
 pub mod util;
 pub mod certificate;
 #[cfg(any(
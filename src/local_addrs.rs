@@ -1,6 +1,10 @@
 use interfaces;
-use std::net::SocketAddr;
+use libc;
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
 
+/// addresses of all interfaces that a peer could reach us on.
+/// ipv6 link-local addresses carry the scope of their interface.
 pub fn get(port: u16) -> Vec<SocketAddr> {
     let mut r = Vec::new();
     for i in interfaces::Interface::get_all().unwrap() {
@@ -9,10 +13,122 @@ pub fn get(port: u16) -> Vec<SocketAddr> {
         }
         for a in &i.addresses {
             if let Some(mut addr) = a.addr {
+                if !usable(&addr.ip()) {
+                    continue;
+                }
                 addr.set_port(port);
+                if let SocketAddr::V6(ref mut v6) = addr {
+                    if is_link_local(v6.ip()) {
+                        match scope_id(&i.name) {
+                            Some(scope) => v6.set_scope_id(scope),
+                            None => continue,
+                        }
+                    }
+                }
                 r.push(addr)
             }
         }
     }
+    r.dedup();
     r
 }
+
+/// a link-local address received from a peer doesn't say which of our links it's on,
+/// so it's a candidate on every interface that has a link-local address itself.
+pub fn scoped(addr: SocketAddr) -> Vec<SocketAddr> {
+    let ip = match addr {
+        SocketAddr::V6(v6) if is_link_local(v6.ip()) => *v6.ip(),
+        _ => return vec![addr],
+    };
+    let mut r = Vec::new();
+    for local in get(0) {
+        if let SocketAddr::V6(local) = local {
+            if is_link_local(local.ip()) {
+                let a = SocketAddr::V6(SocketAddrV6::new(ip, addr.port(), 0, local.scope_id()));
+                if !r.contains(&a) {
+                    r.push(a);
+                }
+            }
+        }
+    }
+    r
+}
+
+/// false for addresses that can never be used to reach a peer
+pub fn usable(ip: &IpAddr) -> bool {
+    if ip.is_unspecified() || ip.is_multicast() || ip.is_loopback() {
+        return false;
+    }
+    match ip {
+        // 169.254/16 is kept like fe80::/10, directly connected peers without dhcp end up there
+        IpAddr::V4(v4) => !v4.is_broadcast(),
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            // deprecated site-local
+            if s[0] & 0xffc0 == 0xfec0 {
+                return false;
+            }
+            // documentation
+            if s[0] == 0x2001 && s[1] == 0x0db8 {
+                return false;
+            }
+            // v4-mapped and v4-compatible, the v4 address is advertised by itself
+            if s[0..5] == [0, 0, 0, 0, 0] && (s[5] == 0 || s[5] == 0xffff) {
+                return false;
+            }
+            true
+        }
+    }
+}
+
+pub fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+fn scope_id(ifname: &str) -> Option<u32> {
+    let name = CString::new(ifname).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        i => Some(i),
+    }
+}
+
+#[test]
+fn unusable_addresses() {
+    for a in &[
+        "0.0.0.0",
+        "127.0.0.1",
+        "255.255.255.255",
+        "224.0.0.251",
+        "::",
+        "::1",
+        "ff02::1",
+        "fec0::1",
+        "2001:db8::1",
+        "::ffff:192.168.1.1",
+        "::192.168.1.1",
+    ] {
+        let ip: IpAddr = a.parse().unwrap();
+        assert!(!usable(&ip), "{} should not be usable", a);
+    }
+    for a in &[
+        "192.168.1.1",
+        "10.0.0.1",
+        "203.0.113.7",
+        "169.254.10.1",
+        "fe80::1",
+        "fd00::1",
+        "2a00:1450::1",
+    ] {
+        let ip: IpAddr = a.parse().unwrap();
+        assert!(usable(&ip), "{} should be usable", a);
+    }
+}
+
+#[test]
+fn scoped_leaves_global_addresses_alone() {
+    let addr: SocketAddr = "[2a00:1450::1]:8443".parse().unwrap();
+    assert_eq!(scoped(addr), vec![addr]);
+    let addr: SocketAddr = "192.168.1.1:8443".parse().unwrap();
+    assert_eq!(scoped(addr), vec![addr]);
+}
@@ -10,9 +10,12 @@ extern crate log;
 extern crate prost;
 extern crate nix;
 extern crate sha2;
+extern crate qrcode;
 
 use carrier::error::Error;
+use std::cell::Cell;
 use std::env;
+use std::rc::Rc;
 use osaka::{osaka};
 use log::{
     warn,
@@ -48,21 +51,76 @@ pub fn main() -> Result<(), Error> {
         .author(crate_authors!())
         .setting(clap::AppSettings::ArgRequiredElseHelp)
         .setting(clap::AppSettings::UnifiedHelpMessage)
-        .subcommand(SubCommand::with_name("mkshadow").about("create a shadow address"))
-        .subcommand(SubCommand::with_name("identity").about("print public identity"))
+        .arg(
+            Arg::with_name("config")
+            .help("read this file instead of ~/.devguard/carrier.toml, on top of /etc/carrier")
+            .short("c")
+            .long("config")
+            .takes_value(true)
+            .value_name("FILE")
+            .global(true)
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+            .about("inspect the config file")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("check")
+                .about("report everything wrong with the config, the secret it points to included")
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("mkshadow")
+            .about("create a shadow address")
+            .arg(
+                Arg::with_name("mnemonic")
+                .help("also print the secret as words to write down")
+                .long("mnemonic")
+            )
+        )
         .subcommand(
             SubCommand::with_name("identity")
-            .about("publish services on carrier")
+            .about("print public identity")
             .arg(
-                Arg::with_name("config")
-                .help("read config from this location instead of ~/.devguard/carrier.toml")
-                .short("c")
-                .long("config")
-                .takes_value(true)
-                .value_name("FILE")
-                .required(false)
+                Arg::with_name("static")
+                .help("print the static key that peers put in [peers] to handshake mutually with us")
+                .long("static")
+            )
+            .subcommand(
+                SubCommand::with_name("rotate")
+                .about("generate a new identity, and a statement signed by the current one that the new one succeeds it")
+                .arg(
+                    Arg::with_name("store")
+                    .help("write the new secret to where the current one was loaded from")
+                    .long("store")
+                )
+            )
+            .subcommand(
+                SubCommand::with_name("export")
+                .about("print the secret for a backup")
+                .arg(
+                    Arg::with_name("mnemonic")
+                    .help("as 24 words instead of base58")
+                    .long("mnemonic")
+                )
+                .arg(
+                    Arg::with_name("qr")
+                    .help("as a QR code")
+                    .long("qr")
+                )
+            )
+            .subcommand(
+                SubCommand::with_name("import")
+                .about("read a secret from stdin, as base58 or words, and print it as base58")
+                .arg(
+                    Arg::with_name("store")
+                    .help("write it to this secret store instead, like the secret in the config")
+                    .long("store")
+                    .takes_value(true)
+                    .value_name("URI")
                 )
             )
+        )
         .subcommand(
             SubCommand::with_name("subscribe")
             .about("watch a shadow")
@@ -86,6 +144,10 @@ pub fn main() -> Result<(), Error> {
             SubCommand::with_name("shell")
                 .about("open a remote shell")
                 .arg(Arg::with_name("target").takes_value(true).required(true).index(1))
+                .arg(Arg::with_name("lan")
+                     .help("find the target on the local network and connect without a broker")
+                     .long("lan")
+                     .required(false))
                 )
         .subcommand(
             SubCommand::with_name("sysinfo")
@@ -97,6 +159,21 @@ pub fn main() -> Result<(), Error> {
                 .about("get netsurvey")
                 .arg(Arg::with_name("target").takes_value(true).required(true).index(1))
                 )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("connect to a target and print live connection statistics")
+                .arg(Arg::with_name("target").takes_value(true).required(true).index(1))
+                )
+        .subcommand(
+            SubCommand::with_name("reflector")
+                .about("tell peers their public address, for nat traversal without the broker")
+                .arg(Arg::with_name("bind")
+                     .help("listen on this address instead of port 8444 on all addresses")
+                     .long("bind")
+                     .takes_value(true)
+                     .value_name("ADDR")
+                     .required(false))
+                )
         .subcommand(
             SubCommand::with_name("rtest")
                 .about("remote tests against a target")
@@ -115,21 +192,29 @@ pub fn main() -> Result<(), Error> {
     let clap = clap.subcommand(
         SubCommand::with_name("publish")
         .aliases(&["axon", "axiom"])
-        .about("publish services on carrier")
-        .arg(
-            Arg::with_name("config")
-            .help("read config from this location instead of ~/.devguard/carrier.toml")
-            .short("c")
-            .long("config")
-            .takes_value(true)
-            .value_name("FILE")
-            .required(false)
-            )
+        .about("publish services on carrier. SIGHUP reloads who is authorized from the config")
         );
 
     let matches = clap.get_matches();
+    let config_file = config_flag(&matches);
+    let config_file = config_file.as_ref().map(|v| std::path::Path::new(v));
     match matches.subcommand() {
-        ("mkshadow", Some(_submatches)) => {
+        ("config", Some(submatches)) => {
+            if let ("check", Some(submatches)) = submatches.subcommand() {
+                let problems = carrier::config::check(config_file);
+                if !problems.is_empty() {
+                    for e in &problems {
+                        eprintln!("{}", e);
+                    }
+                    std::process::exit(1);
+                }
+                for path in carrier::config::files(config_file) {
+                    println!("{}: ok", path.to_string_lossy());
+                }
+            }
+            Ok(())
+        }
+        ("mkshadow", Some(submatches)) => {
             use rand::RngCore;
 
             let mut secret = vec![0; 32];
@@ -140,12 +225,58 @@ pub fn main() -> Result<(), Error> {
             let address = secret.address();
 
             println!("address: {}", address.to_string());
-            println!("secret:  {}", secret.to_string());
+            println!("secret:  {}", secret.to_string()?);
+            if submatches.is_present("mnemonic") {
+                println!("words:   {}", secret.to_mnemonic()?);
+            }
             Ok(())
         }
-        ("identity", Some(_submatches)) => {
-            let config = carrier::config::load()?;
-            println!("{}", config.secret.identity());
+        ("identity", Some(submatches)) => {
+            if let ("import", Some(submatches)) = submatches.subcommand() {
+                return import_secret(submatches.value_of("store"));
+            }
+            let config = carrier::config::load_from(config_file)?;
+            if let ("export", Some(submatches)) = submatches.subcommand() {
+                let text = if submatches.is_present("mnemonic") {
+                    config.secret.to_mnemonic()?
+                } else {
+                    config.secret.to_string()?
+                };
+                if submatches.is_present("qr") {
+                    println!("{}", qr(&text)?);
+                } else {
+                    println!("{}", text);
+                }
+            } else if let ("rotate", Some(submatches)) = submatches.subcommand() {
+                let secret = carrier::Secret::gen();
+                let timestamp = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+                    Ok(d) => d.as_secs() * 1000 + d.subsec_millis() as u64,
+                    Err(_) => 0,
+                };
+                let succession = carrier::identity::Succession::sign(&config.secret, secret.identity(), timestamp)?;
+
+                if submatches.is_present("store") {
+                    let uri = match config.secret_store {
+                        Some(ref uri) => uri,
+                        None => {
+                            eprintln!("the config has no secret to store the new one in place of");
+                            std::process::exit(1);
+                        }
+                    };
+                    carrier::keystore::open(uri)?.store(&secret)?;
+                    println!("stored the new secret in {}", uri);
+                } else {
+                    println!("secret:     {}", secret.to_string()?);
+                }
+                println!("identity:   {}", secret.identity());
+                println!("succession: {}", succession);
+                println!();
+                println!("add the succession to `succession = [..]` in the config of every publisher and subscriber that knows {}", config.secret.identity());
+            } else if submatches.is_present("static") {
+                println!("{}", config.secret.address());
+            } else {
+                println!("{}", config.secret.identity());
+            }
             Ok(())
         }
 
@@ -156,7 +287,7 @@ pub fn main() -> Result<(), Error> {
 ))]
         ("publish", Some(_submatches)) => {
             let poll            = osaka::Poll::new();
-            let config          = carrier::config::load()?;
+            let config          = carrier::config::load_from(config_file)?;
             let mut publisher   = carrier::publisher::new(config)
                 .route("/v0/shell", carrier::publisher::shell::main)
                 .route("/v0/sft",   carrier::publisher::sft::main)
@@ -166,7 +297,7 @@ pub fn main() -> Result<(), Error> {
         },
         ("subscribe", Some(submatches)) => {
             let poll    = osaka::Poll::new();
-            let config  = carrier::config::load()?;
+            let config  = carrier::config::load_from(config_file)?;
             let shadow  = submatches.value_of("address").unwrap().to_string().parse().expect("parsing shadow");
 
             let mut subscriber  = carrier::subscriber::new(config)
@@ -177,7 +308,7 @@ pub fn main() -> Result<(), Error> {
         }
         ("get", Some(submatches)) => {
             let poll    = osaka::Poll::new();
-            let config  = carrier::config::load()?;
+            let config  = carrier::config::load_from(config_file)?;
             let target = config
                 .resolve_identity(submatches.value_of("target").unwrap().to_string()).expect("resolving identity from cli");
             let resource = submatches.value_of("resource").unwrap().to_string();
@@ -192,7 +323,7 @@ pub fn main() -> Result<(), Error> {
         }
         ("sysinfo", Some(submatches)) => {
             let poll    = osaka::Poll::new();
-            let config  = carrier::config::load()?;
+            let config  = carrier::config::load_from(config_file)?;
             let target = config
                 .resolve_identity(submatches.value_of("target").unwrap().to_string()).expect("resolving identity from cli");
 
@@ -201,15 +332,15 @@ pub fn main() -> Result<(), Error> {
         }
         ("shell", Some(submatches)) => {
             let poll    = osaka::Poll::new();
-            let config  = carrier::config::load()?;
+            let config  = carrier::config::load_from(config_file)?;
             let target = config
                 .resolve_identity(submatches.value_of("target").unwrap().to_string()).expect("resolving identity from cli");
 
-            shell::ui(poll, config, target).run()
+            shell::ui(poll, config, target, submatches.is_present("lan")).run()
         }
         ("push", Some(submatches)) => {
             let poll    = osaka::Poll::new();
-            let config  = carrier::config::load()?;
+            let config  = carrier::config::load_from(config_file)?;
             let target = config
                 .resolve_identity(submatches.value_of("target").unwrap().to_string()).expect("resolving identity from cli");
 
@@ -240,16 +371,32 @@ pub fn main() -> Result<(), Error> {
         }
         ("netsurvey", Some(submatches)) => {
             let poll    = osaka::Poll::new();
-            let config  = carrier::config::load()?;
+            let config  = carrier::config::load_from(config_file)?;
             let target = config
                 .resolve_identity(submatches.value_of("target").unwrap().to_string()).expect("resolving identity from cli");
 
             let mut headers = carrier::headers::Headers::with_path("/v1/netsurvey");
             get(poll, config, target, headers, message_handler::<carrier::proto::NetSurvey>).run()
         }
+        ("stats", Some(submatches)) => {
+            let poll    = osaka::Poll::new();
+            let config  = carrier::config::load_from(config_file)?;
+            let target = config
+                .resolve_identity(submatches.value_of("target").unwrap().to_string()).expect("resolving identity from cli");
+
+            stats(poll, config, target).run()
+        }
+        ("reflector", Some(submatches)) => {
+            let poll    = osaka::Poll::new();
+            let socket  = match submatches.value_of("bind") {
+                Some(bind) => carrier::socket::Socket::bind(&bind.parse().expect("parsing bind address"))?,
+                None => carrier::socket::Socket::bind_any(carrier::reflect::DEFAULT_PORT)?,
+            };
+            carrier::reflect::serve(poll, socket).run()
+        }
         ("rtest", Some(submatches)) => {
             let poll    = osaka::Poll::new();
-            let config  = carrier::config::load()?;
+            let config  = carrier::config::load_from(config_file)?;
             let target = config
                 .resolve_identity(submatches.value_of("target").unwrap().to_string()).expect("resolving identity from cli");
 
@@ -278,9 +425,6 @@ pub fn main() -> Result<(), Error> {
 fn message_handler<T: prost::Message + Default>(_poll: osaka::Poll, mut stream: carrier::endpoint::Stream) {
     use prost::Message;
 
-    let _d = carrier::util::defer(||{
-        std::process::exit(0);
-    });
     let headers = carrier::headers::Headers::decode(&osaka::sync!(stream)).unwrap();
     println!("{:?}", headers);
 
@@ -303,7 +447,6 @@ fn message_handler<T: prost::Message + Default>(_poll: osaka::Poll, mut stream:
 fn print_handler(_poll: osaka::Poll, mut stream: carrier::endpoint::Stream) {
     let _d = carrier::util::defer(||{
         info!("stream ended");
-        std::process::exit(0);
     });
 
     let headers = carrier::headers::Headers::decode(&osaka::sync!(stream)).unwrap();
@@ -338,21 +481,134 @@ fn get<F> (poll: osaka::Poll,
     };
 
     let route  = ep.accept_outgoing(q, move |_h, _s|{None}).unwrap();
+    let closed = Rc::new(Cell::new(false));
+    let closed2 = closed.clone();
     ep.open(
         route,
         headers.clone(),
-        f,
+        move |poll, stream| until_closed(poll, stream, f, closed2),
         );
 
-    loop {
+    let mut r = until_done(ep, closed);
+    osaka::sync!(r)
+}
+
+/// run a stream handler, and note when it is done or the stream went away
+#[osaka]
+pub fn until_closed<F>(poll: osaka::Poll, stream: carrier::endpoint::Stream, f: F, closed: Rc<Cell<bool>>)
+    where F: 'static + FnOnce(osaka::Poll, carrier::endpoint::Stream) -> osaka::Task<()>,
+{
+    let _d = carrier::util::defer(move ||{
+        closed.set(true);
+    });
+    let mut task = f(poll, stream);
+    osaka::sync!(task);
+}
+
+/// drive the endpoint until the stream closed or the peer disconnected, then shut it down
+#[osaka]
+pub fn until_done(mut ep: carrier::endpoint::Endpoint, closed: Rc<Cell<bool>>) -> Result<(), Error> {
+    use osaka::Future;
+
+    while !closed.get() {
+        match ep.poll() {
+            osaka::FutureResult::Done(event) => match event? {
+                carrier::endpoint::Event::Disconnect{identity, ..} => {
+                    warn!("{} disconnected", identity);
+                    closed.set(true);
+                }
+                _ => (),
+            },
+            osaka::FutureResult::Again(again) => {
+                if !closed.get() {
+                    yield again;
+                }
+            }
+        }
+    }
+    let mut shutdown = ep.shutdown(std::time::Duration::from_secs(carrier::endpoint::SHUTDOWN_TIMEOUT));
+    osaka::sync!(shutdown)
+}
+
+
+#[osaka]
+fn stats(poll: osaka::Poll,
+       config: carrier::config::Config,
+       target: carrier::identity::Identity,
+       )
+    -> Result<(), Error>
+{
+    use osaka::Future;
+    use std::time::{Duration, Instant};
+
+    let mut ep = carrier::endpoint::EndpointBuilder::new(&config)?.connect(poll.clone());
+    let mut ep = osaka::sync!(ep)?;
+    ep.connect(target)?;
+
+    let q = loop {
         match osaka::sync!(ep)? {
-            carrier::endpoint::Event::OutgoingConnect(_) => (),
-            carrier::endpoint::Event::Disconnect{identity, ..} => {
+            carrier::endpoint::Event::OutgoingConnect(q) => {
+                break q;
+            },
+            _ => (),
+        }
+    };
+
+    let route  = ep.accept_outgoing(q, move |_h, _s|{None}).unwrap();
+
+    let interval = Duration::from_secs(1);
+    let mut last = Instant::now();
+    loop {
+        if last.elapsed() >= interval {
+            last = Instant::now();
+            // keep rtt samples coming even if nothing else is sent
+            ep.probe(route);
+            if let Some(stats) = ep.stats(route) {
+                print_stats(&stats);
+            }
+        }
+
+        match ep.poll() {
+            osaka::FutureResult::Done(Err(e)) => return Err(e),
+            osaka::FutureResult::Done(Ok(carrier::endpoint::Event::Disconnect{identity, ..})) => {
                 warn!("{} disconnected", identity);
                 return Ok(());
             }
-            carrier::endpoint::Event::IncommingConnect(_) => (),
-        };
+            osaka::FutureResult::Done(Ok(_)) => (),
+            osaka::FutureResult::Again(mut y) => {
+                let remaining = interval.checked_sub(last.elapsed()).unwrap_or(Duration::from_millis(0));
+                y.merge(poll.later(remaining));
+                yield y;
+            }
+        }
+    }
+}
+
+fn print_stats(stats: &carrier::endpoint::Stats) {
+    let c = &stats.channel;
+    println!("{}", stats.identity);
+    match &stats.path {
+        carrier::endpoint::Path::Discovering(candidates) => {
+            println!("  path:           discovering");
+            for (addr, cat) in candidates {
+                println!("                  {} ({:?})", addr, cat);
+            }
+        }
+        carrier::endpoint::Path::Established(addr, Some(cat)) => {
+            println!("  path:           {} ({:?})", addr, cat);
+        }
+        carrier::endpoint::Path::Established(addr, None) => {
+            println!("  path:           {} (unknown)", addr);
+        }
+    }
+    println!("  rtt:            {}ms (var {:.1}ms, min {}ms)", c.smoothed_rtt, c.rttvar, c.min_rtt);
+    println!("  cwnd:           {} bytes, {} in flight, mtu {}", c.congestion_window, c.bytes_in_flight, c.mtu);
+    println!("  packets:        {} sent, {} received", c.packets_sent, c.packets_received);
+    println!("  loss:           {} lost, {} retransmitted, {} replay drops",
+             c.packets_lost, c.packets_retransmitted, c.replay_drops);
+    println!("  protocol:       {:#04x}, key epoch {}", c.version, c.key_epoch);
+    for (stream, s) in &c.streams {
+        println!("  stream {:>8}: {} bytes sent, {} bytes received", stream, s.bytes_sent, s.bytes_received);
     }
 }
 
@@ -422,6 +678,54 @@ fn push(poll: osaka::Poll,
                 return Ok(());
             }
             carrier::endpoint::Event::IncommingConnect(_) => (),
+            carrier::endpoint::Event::Datagram{..} => (),
+            carrier::endpoint::Event::PathChanged{..} => (),
         };
     }
 }
+
+/// for terminals with a dark background, where light modules on a dark background
+/// scan as the usual dark on light
+fn qr(text: &str) -> Result<String, Error> {
+    use qrcode::render::unicode::Dense1x2;
+    let code = qrcode::QrCode::new(text.as_bytes()).map_err(|e| {
+        Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("qr code: {}", e)))
+    })?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+fn import_secret(store: Option<&str>) -> Result<(), Error> {
+    use std::io::Read;
+
+    let mut text = String::new();
+    std::io::stdin().read_to_string(&mut text)?;
+    let text = text.trim();
+    let secret = if text.contains(char::is_whitespace) {
+        carrier::Secret::from_mnemonic(text)?
+    } else {
+        text.parse::<carrier::Secret>()?
+    };
+
+    if let Some(uri) = store {
+        carrier::keystore::open(uri)?.store(&secret)?;
+        eprintln!("stored {} in {}", secret.identity(), uri);
+    } else {
+        println!("{}", secret.to_string()?);
+    }
+    Ok(())
+}
+
+/// --config is global, so it may have been given to any subcommand
+fn config_flag(matches: &clap::ArgMatches) -> Option<String> {
+    if let Some(v) = matches.value_of("config") {
+        return Some(v.to_string());
+    }
+    match matches.subcommand() {
+        (_, Some(submatches)) => config_flag(submatches),
+        _ => None,
+    }
+}
@@ -0,0 +1,113 @@
+//! bytes as a list of words, for backups written down on paper.
+//!
+//! this is the BIP39 encoding with the english word list: the bytes are followed by the first
+//! bits of their sha256, and every 11 bits pick one word. a misspelled word is reported by its
+//! position, swapped or wrong words fail the checksum. since every word is unique in its first
+//! four letters, those are enough when reading a phrase back in.
+
+use error::Error;
+use sha2::{Digest, Sha256};
+
+const WORDLIST: &str = include_str!("bip39-english.txt");
+
+fn words() -> Vec<&'static str> {
+    WORDLIST.lines().collect()
+}
+
+fn invalid<S: Into<String>>(reason: S) -> Error {
+    Error::InvalidMnemonic { reason: reason.into() }
+}
+
+/// 16 to 32 bytes, in steps of 4, as 12 to 24 words
+pub fn encode(entropy: &[u8]) -> Result<String, Error> {
+    if entropy.len() < 16 || entropy.len() > 32 || entropy.len() % 4 != 0 {
+        return Err(invalid(format!("cannot encode {} bytes", entropy.len())));
+    }
+    let checksum = Sha256::digest(entropy);
+    let nwords = (entropy.len() * 8 + entropy.len() / 4) / 11;
+
+    let bit = |i: usize| -> usize {
+        let byte = if i < entropy.len() * 8 {
+            entropy[i / 8]
+        } else {
+            checksum[(i - entropy.len() * 8) / 8]
+        };
+        ((byte >> (7 - i % 8)) & 1) as usize
+    };
+
+    let words = words();
+    let mut r = Vec::with_capacity(nwords);
+    for w in 0..nwords {
+        let mut index = 0;
+        for i in 0..11 {
+            index = (index << 1) | bit(w * 11 + i);
+        }
+        r.push(words[index]);
+    }
+    Ok(r.join(" "))
+}
+
+pub fn decode(phrase: &str) -> Result<Vec<u8>, Error> {
+    let words = words();
+    let mut indices = Vec::new();
+    for (n, word) in phrase.split_whitespace().enumerate() {
+        let word = word.to_lowercase();
+        let index = match words.binary_search(&word.as_str()) {
+            Ok(i) => i,
+            Err(i) if word.len() == 4 && i < words.len() && words[i].starts_with(word.as_str()) => i,
+            Err(_) => return Err(invalid(format!("word {} '{}' is not in the word list", n + 1, word))),
+        };
+        indices.push(index);
+    }
+    if indices.len() < 12 || indices.len() > 24 || indices.len() % 3 != 0 {
+        return Err(invalid(format!("expected 12 to 24 words, got {}", indices.len())));
+    }
+
+    let nbits = indices.len() * 11;
+    let nbytes = (nbits - nbits / 33) / 8;
+    let bit = |i: usize| -> u8 { ((indices[i / 11] >> (10 - i % 11)) & 1) as u8 };
+
+    let mut entropy = vec![0u8; nbytes];
+    for i in 0..nbytes * 8 {
+        entropy[i / 8] |= bit(i) << (7 - i % 8);
+    }
+
+    let checksum = Sha256::digest(&entropy);
+    for i in nbytes * 8..nbits {
+        let j = i - nbytes * 8;
+        if bit(i) != (checksum[j / 8] >> (7 - j % 8)) & 1 {
+            return Err(invalid("checksum mismatch. a word is wrong, missing or in the wrong place"));
+        }
+    }
+    Ok(entropy)
+}
+
+#[test]
+fn test_vectors() {
+    let v: &[(&[u8], &str)] = &[
+        (&[0; 16], "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"),
+        (&[0x7f; 16], "legal winner thank year wave sausage worth useful legal winner thank yellow"),
+        (&[0x80; 24], "letter advice cage absurd amount doctor acoustic avoid letter advice cage absurd amount doctor acoustic avoid letter always"),
+        (&[0xff; 32], "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote"),
+    ];
+    for (entropy, phrase) in v {
+        assert_eq!(encode(entropy).unwrap(), *phrase);
+        assert_eq!(&decode(phrase).unwrap()[..], *entropy);
+    }
+}
+
+#[test]
+fn transcription_errors() {
+    let phrase = "legal winner thank year wave sausage worth useful legal winner thank yellow";
+
+    // case, spacing and four letter abbreviations don't matter
+    assert_eq!(decode("LEGAL  winn than year\nwave sausage worth useful legal winner thank yell").unwrap(), vec![0x7f; 16]);
+
+    match decode(&phrase.replace("sausage", "sausge")) {
+        Err(Error::InvalidMnemonic { reason }) => assert!(reason.starts_with("word 6 ")),
+        _ => panic!("expected the misspelled word to be reported"),
+    }
+    assert!(decode(&phrase.replace("thank year", "year thank")).is_err());
+    assert!(decode(&phrase.replace(" yellow", "")).is_err());
+    assert!(encode(&[0; 15]).is_err());
+}
@@ -2,9 +2,12 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use error::Error;
 use identity::{Address, Identity, Secret, Signature};
 use packet::{self, RoutingDirection, RoutingKey};
+use rand;
+use sha2::{Digest, Sha256};
 use snow::resolvers::{CryptoResolver, FallbackResolver};
 use snow::{self, params::NoiseParams, Builder};
 use std::io::Read;
+use std::mem;
 use std::io::Write;
 
 pub struct Transport {
@@ -12,29 +15,46 @@ pub struct Transport {
     noise: snow::Session,
     route: RoutingKey,
     direction: RoutingDirection,
+    /// negotiated in the handshake
+    version: u8,
+
+    // rekeying. the initiator offers new keys, the responder switches to them when the offer
+    // arrives, and the initiator switches once it sees the responder using them.
+    // the packet counter keeps counting across keys, so anti-replay is unaffected
+    epoch: u32,
+    chain: [u8; 32],
+    /// keys offered to the peer but not used by it yet, with their chain
+    pending: Option<(snow::Session, [u8; 32])>,
+    /// keys of the previous epoch, for packets that were in flight during the switch
+    previous: Option<snow::Session>,
 }
 
 pub struct HandshakeRequester {
     noise: snow::Session,
     timestamp: u64,
     route: Option<RoutingKey>,
+    version: u8,
 }
 
 pub struct HandshakeResponder {
     noise: snow::Session,
     timestamp: u64,
+    version: u8,
 }
 
 enum SendMode<'a> {
     Transport { counter: u64, payload: &'a [u8] },
     InsecureHandshake { identity: Identity, timestamp: u64 },
     Handshake { identity: Identity, timestamp: u64 },
+    /// like Handshake, but also carries the initiator's static key
+    MutualHandshake { identity: Identity, timestamp: u64 },
 }
 
 fn send(
     noise: &mut snow::Session,
     route: RoutingKey,
     direction: RoutingDirection,
+    version: u8,
     payload: SendMode,
 ) -> Result<packet::EncryptedPacket, Error> {
     let counter = if let &SendMode::Transport { counter, .. } = &payload {
@@ -58,6 +78,7 @@ fn send(
             assert_eq!(identity.as_bytes().len(), 32);
             inbuf.write_all(&identity.as_bytes())?;
             inbuf.write_u64::<BigEndian>(timestamp)?;
+            write_versions(&mut inbuf)?;
 
             32 // ephermal
             + 64 // signature
@@ -69,11 +90,26 @@ fn send(
             assert_eq!(identity.as_bytes().len(), 32);
             inbuf.write_all(&identity.as_bytes())?;
             inbuf.write_u64::<BigEndian>(timestamp)?;
+            write_versions(&mut inbuf)?;
 
             16 // tag
             + 32 // ephermal
             + 64 // signature
         }
+        SendMode::MutualHandshake {
+            identity,
+            timestamp,
+        } => {
+            assert_eq!(identity.as_bytes().len(), 32);
+            inbuf.write_all(&identity.as_bytes())?;
+            inbuf.write_u64::<BigEndian>(timestamp)?;
+            write_versions(&mut inbuf)?;
+
+            16 // tag
+            + 32 // ephermal
+            + 32 + 16 // static
+            + 64 // signature
+        }
     };
 
     let padding = 256 - ((inbuf.len() + overhead) % 256);
@@ -97,7 +133,7 @@ fn send(
     }
 
     let pkt = packet::EncryptedPacket {
-        version: 0x08,
+        version,
         route,
         direction,
         counter,
@@ -107,6 +143,15 @@ fn send(
     Ok(pkt)
 }
 
+/// what follows identity and timestamp in a handshake: no certificates,
+/// then the versions we speak. peers from before negotiation read the zero certs and stop
+fn write_versions(w: &mut Vec<u8>) -> Result<(), Error> {
+    w.write_u16::<BigEndian>(0)?;
+    w.write_u8(packet::VERSIONS.len() as u8)?;
+    w.write_all(packet::VERSIONS)?;
+    Ok(())
+}
+
 impl Transport {
     pub fn send(&mut self, payload: &[u8]) -> Result<packet::EncryptedPacket, Error> {
         self.counter += 1;
@@ -114,6 +159,7 @@ impl Transport {
             &mut self.noise,
             self.route,
             self.direction.clone(),
+            self.version,
             SendMode::Transport {
                 counter: self.counter,
                 payload,
@@ -136,34 +182,170 @@ impl Transport {
             return Err(Error::WrongDirection { dir: pkt.direction }.into());
         }
 
-        let mut outbuf = vec![0; pkt.payload.len()];
-        let len = self
-            .noise
-            .read_message_with_nonce(pkt.counter - 1, &pkt.payload, &mut outbuf)?;
-        outbuf.truncate(len);
+        if pkt.version != self.version {
+            return Err(Error::InvalidVersion { version: pkt.version });
+        }
 
-        if len < 2 {
-            return Err(Error::TooSmall { need: 2, got: len }.into());
+        let e = match decrypt(&mut self.noise, &pkt) {
+            Ok(outbuf) => return payload(outbuf),
+            Err(e) => e,
+        };
+
+        // the peer started using the keys we offered
+        let offered = match self.pending {
+            Some((ref mut noise, _)) => decrypt(noise, &pkt).ok(),
+            None => None,
+        };
+        if let Some(outbuf) = offered {
+            let (noise, chain) = self.pending.take().unwrap();
+            self.previous = Some(mem::replace(&mut self.noise, noise));
+            self.chain = chain;
+            self.epoch += 1;
+            debug!("route {} switched to keys of epoch {}", self.route, self.epoch);
+            return payload(outbuf);
         }
-        let len = (&outbuf[..]).read_u16::<BigEndian>()? as usize;
-        let mut payload = outbuf.split_off(2);
-        if len > payload.len() {
-            return Err(Error::DecryptedInvalidPayloadLen.into());
+
+        // sent before the last switch
+        if let Some(ref mut previous) = self.previous {
+            if let Ok(outbuf) = decrypt(previous, &pkt) {
+                return payload(outbuf);
+            }
         }
-        payload.truncate(len);
-        Ok(payload)
+
+        Err(e)
+    }
+
+    /// how many times the keys were replaced since the handshake
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// true while offered keys are not confirmed by the peer
+    pub fn rekey_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// offer new keys to the peer. only the initiator does this.
+    /// the returned epoch and seed go to the responder in a rekey frame.
+    /// we keep sending with the current keys until the responder uses the new ones
+    pub fn rekey_offer(&mut self) -> Result<(u32, [u8; 32]), Error> {
+        assert!(self.is_initiator(), "only the initiator offers new keys");
+        assert!(self.pending.is_none(), "a rekey is already pending");
+
+        let seed: [u8; 32] = rand::random();
+        let (chain, noise) = next_keys(&self.chain, &seed, true)?;
+        self.pending = Some((noise, chain));
+        Ok((self.epoch + 1, seed))
+    }
+
+    /// switch to the keys the initiator offered. returns false for an offer that was
+    /// already accepted, which happens when a rekey frame is retransmitted
+    pub fn rekey_accept(&mut self, epoch: u32, seed: &[u8; 32]) -> Result<bool, Error> {
+        if self.is_initiator() {
+            return Err(Error::SecurityViolation);
+        }
+        if epoch <= self.epoch {
+            return Ok(false);
+        }
+        if epoch != self.epoch + 1 {
+            return Err(Error::SecurityViolation);
+        }
+        let (chain, noise) = next_keys(&self.chain, seed, false)?;
+        self.previous = Some(mem::replace(&mut self.noise, noise));
+        self.chain = chain;
+        self.epoch = epoch;
+        debug!("route {} switched to keys of epoch {}", self.route, self.epoch);
+        Ok(true)
     }
 
     pub fn is_initiator(&self) -> bool {
         self.direction == RoutingDirection::Initiator2Responder
     }
 
+    /// the wire version negotiated with the peer
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// pretend the peer only speaks an older version
+    #[cfg(test)]
+    pub fn downgrade(&mut self, version: u8) {
+        self.version = version;
+    }
+
     pub fn route(&self) -> RoutingKey {
         self.route
     }
 }
 
+fn decrypt(noise: &mut snow::Session, pkt: &packet::EncryptedPacket) -> Result<Vec<u8>, Error> {
+    let mut outbuf = vec![0; pkt.payload.len()];
+    let len = noise.read_message_with_nonce(pkt.counter - 1, &pkt.payload, &mut outbuf)?;
+    outbuf.truncate(len);
+    Ok(outbuf)
+}
+
+fn payload(mut outbuf: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let len = outbuf.len();
+    if len < 2 {
+        return Err(Error::TooSmall { need: 2, got: len }.into());
+    }
+    let len = (&outbuf[..]).read_u16::<BigEndian>()? as usize;
+    let mut payload = outbuf.split_off(2);
+    if len > payload.len() {
+        return Err(Error::DecryptedInvalidPayloadLen.into());
+    }
+    payload.truncate(len);
+    Ok(payload)
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.input(part);
+    }
+    let mut r = [0; 32];
+    r.copy_from_slice(&hasher.result());
+    r
+}
+
+/// the chain and transport keys of the next epoch.
+/// the chain never leaves this process, so knowing the keys of one epoch
+/// and the seed of the next is not enough to compute the next keys
+fn next_keys(chain: &[u8; 32], seed: &[u8; 32], initiator: bool) -> Result<([u8; 32], snow::Session), Error> {
+    let chain = hash(&[&b"carrier rekey chain"[..], &chain[..], &seed[..]]);
+    let i2r = hash(&[&b"carrier rekey i2r"[..], &chain[..]]);
+    let r2i = hash(&[&b"carrier rekey r2i"[..], &chain[..]]);
+
+    // snow only makes transport sessions from a finished handshake,
+    // so finish a throwaway one locally and replace its keys
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let mut i = new_noise_builder(params).build_initiator()?;
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let mut r = new_noise_builder(params).build_responder()?;
+    let mut buf = [0u8; 128];
+    let mut out = [0u8; 128];
+    let len = i.write_message(&[], &mut buf)?;
+    r.read_message(&buf[..len], &mut out)?;
+    let len = r.write_message(&[], &mut buf)?;
+    i.read_message(&buf[..len], &mut out)?;
+
+    let session = if initiator { i } else { r };
+    let mut noise = session.into_stateless_transport_mode()?;
+    noise.rekey(Some(&i2r[..]), Some(&r2i[..]))?;
+    Ok((chain, noise))
+}
+
 impl HandshakeResponder {
+    /// the initiator's static key, if it used a mutual handshake
+    pub fn remote_address(&self) -> Option<Address> {
+        self.noise.get_remote_static().map(|v| {
+            let mut a = [0; 32];
+            a.copy_from_slice(v);
+            Address::from_array(a)
+        })
+    }
+
     pub fn send_response(
         mut self,
         route: RoutingKey,
@@ -173,6 +355,7 @@ impl HandshakeResponder {
             &mut self.noise,
             route,
             RoutingDirection::Responder2Initiator,
+            packet::HANDSHAKE_VERSION,
             SendMode::Handshake {
                 timestamp: self.timestamp,
                 identity: secret.identity(),
@@ -181,28 +364,35 @@ impl HandshakeResponder {
         let signature = secret.sign(
             b"carrier handshake hash 1",
             self.noise.get_handshake_hash()?,
-        );
+        )?;
         pkt.payload.extend_from_slice(&signature.as_bytes());
         assert_eq!(pkt.payload.len() % 256, 0);
         assert_eq!(pkt.payload.len() % 256, 0);
         assert_ne!(route, 0);
 
+        let chain = hash(&[&b"carrier rekey chain"[..], self.noise.get_handshake_hash()?]);
         Ok((
             Transport {
                 counter: 0,
                 noise: self.noise.into_stateless_transport_mode()?,
                 route: route,
                 direction: RoutingDirection::Responder2Initiator,
+                version: self.version,
+                epoch: 0,
+                chain,
+                pending: None,
+                previous: None,
             },
             pkt,
         ))
     }
 }
 
+/// identity, timestamp and the versions the peer speaks
 fn recv_handshake(
     noise: &mut snow::Session,
     pkt: packet::EncryptedPacket,
-) -> Result<(Identity, u64), Error> {
+) -> Result<(Identity, u64, Vec<u8>), Error> {
     if pkt.payload.len() % 256 != 0 {
         return Err(Error::PktMisaligned {
             len: pkt.payload.len(),
@@ -238,23 +428,28 @@ fn recv_handshake(
         chain.push(crt);
     }
 
+    let numversions = reader.read_u8()?;
+    let mut versions = vec![0; numversions as usize];
+    reader.read_exact(&mut versions)?;
+
     identity.verify(
         b"carrier handshake hash 1",
         noise.get_handshake_hash()?,
         &signature,
     )?;
 
-    Ok((identity, timestamp))
+    Ok((identity, timestamp, versions))
 }
 
 impl HandshakeRequester {
     pub fn recv_response(&mut self, pkt: packet::EncryptedPacket) -> Result<Identity, Error> {
         let route = pkt.route;
-        let (identity, timestamp) = recv_handshake(&mut self.noise, pkt)?;
+        let (identity, timestamp, versions) = recv_handshake(&mut self.noise, pkt)?;
 
         if timestamp != self.timestamp {
             return Err(Error::InvalidCookie.into());
         }
+        self.version = packet::negotiate(&versions)?;
 
         self.route = Some(route);
 
@@ -262,6 +457,7 @@ impl HandshakeRequester {
     }
 
     pub fn into_transport(self) -> Result<Transport, Error> {
+        let chain = hash(&[&b"carrier rekey chain"[..], self.noise.get_handshake_hash()?]);
         Ok(Transport {
             counter: 0,
             noise: self.noise.into_stateless_transport_mode()?,
@@ -269,6 +465,11 @@ impl HandshakeRequester {
                 .route
                 .expect("into_transport can only be called after recv_response"),
             direction: RoutingDirection::Initiator2Responder,
+            version: self.version,
+            epoch: 0,
+            chain,
+            pending: None,
+            previous: None,
         })
     }
 }
@@ -278,41 +479,59 @@ pub fn initiate(
     secret: &Secret,
     timestamp: u64,
 ) -> Result<(HandshakeRequester, packet::EncryptedPacket), Error> {
-    let mut noise = if let Some(remote_static) = remote_static {
+    let identity = secret.identity();
+    if let Some(remote_static) = remote_static {
         let params: NoiseParams = "Noise_NK_25519_ChaChaPoly_SHA256".parse().unwrap();
-        new_noise_builder(params)
+        let noise = new_noise_builder(params)
             .remote_public_key(remote_static.as_bytes())
             .prologue("carrier has arrived".as_bytes())
             .build_initiator()
-            .expect("building noise session")
+            .expect("building noise session");
+        send_initiation(noise, secret, timestamp, SendMode::Handshake { identity, timestamp })
     } else {
         let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
-        new_noise_builder(params)
+        let noise = new_noise_builder(params)
             .prologue("carrier has arrived".as_bytes())
             .build_initiator()
-            .expect("building noise session")
-    };
+            .expect("building noise session");
+        send_initiation(noise, secret, timestamp, SendMode::InsecureHandshake { identity, timestamp })
+    }
+}
 
+/// handshake with a peer whose static key we already know.
+/// IK hides our identity from everyone but the peer, and authenticates both sides
+/// with their static keys in addition to the signatures.
+/// the peer must answer with respond_peer
+pub fn initiate_mutual(
+    remote_static: &Address,
+    secret: &Secret,
+    timestamp: u64,
+) -> Result<(HandshakeRequester, packet::EncryptedPacket), Error> {
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let noise = new_static_noise_builder(params, secret)
+        .remote_public_key(remote_static.as_bytes())
+        .prologue("carrier has arrived".as_bytes())
+        .build_initiator()
+        .expect("building noise session");
     let identity = secret.identity();
+    send_initiation(noise, secret, timestamp, SendMode::MutualHandshake { identity, timestamp })
+}
 
+fn send_initiation(
+    mut noise: snow::Session,
+    secret: &Secret,
+    timestamp: u64,
+    mode: SendMode,
+) -> Result<(HandshakeRequester, packet::EncryptedPacket), Error> {
     let mut pkt = send(
         &mut noise,
         0,
         RoutingDirection::Initiator2Responder,
-        if remote_static.is_some() {
-            SendMode::Handshake {
-                identity,
-                timestamp,
-            }
-        } else {
-            SendMode::InsecureHandshake {
-                identity,
-                timestamp,
-            }
-        },
+        packet::HANDSHAKE_VERSION,
+        mode,
     )?;
 
-    let signature = secret.sign(b"carrier handshake hash 1", noise.get_handshake_hash()?);
+    let signature = secret.sign(b"carrier handshake hash 1", noise.get_handshake_hash()?)?;
     pkt.payload.extend_from_slice(&signature.as_bytes());
     assert_eq!(pkt.payload.len() % 256, 0);
 
@@ -320,6 +539,7 @@ pub fn initiate(
         timestamp,
         noise: noise,
         route: None,
+        version: packet::HANDSHAKE_VERSION,
     };
 
     Ok((s, pkt))
@@ -331,8 +551,7 @@ pub fn respond(
 ) -> Result<(HandshakeResponder, Identity, u64), Error> {
     let mut noise = if let Some(xsecret) = xsecret {
         let params: NoiseParams = "Noise_NK_25519_ChaChaPoly_SHA256".parse().unwrap();
-        new_noise_builder(params)
-            .local_private_key(xsecret.as_bytes())
+        new_static_noise_builder(params, xsecret)
             .prologue("carrier has arrived".as_bytes())
             .build_responder()
             .expect("building noise session")
@@ -344,9 +563,38 @@ pub fn respond(
             .expect("building noise session")
     };
 
-    let (identity, timestamp) = recv_handshake(&mut noise, pkt)?;
+    let (identity, timestamp, versions) = recv_handshake(&mut noise, pkt)?;
+    let version = packet::negotiate(&versions)?;
+
+    Ok((HandshakeResponder { noise, timestamp, version }, identity, timestamp))
+}
+
+/// respond to a handshake from a peer, which is either the plain one from initiate(None, ..)
+/// or a mutual one from initiate_mutual to our static key.
+/// there is nothing on the wire telling them apart, so we try both
+pub fn respond_peer(
+    secret: &Secret,
+    pkt: packet::EncryptedPacket,
+) -> Result<(HandshakeResponder, Identity, u64), Error> {
+    let e = match respond(None, pkt.clone()) {
+        Ok(v) => return Ok(v),
+        Err(e) => e,
+    };
+
+    let params: NoiseParams = "Noise_IK_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let mut noise = new_static_noise_builder(params, secret)
+        .prologue("carrier has arrived".as_bytes())
+        .build_responder()
+        .expect("building noise session");
 
-    Ok((HandshakeResponder { noise, timestamp }, identity, timestamp))
+    match recv_handshake(&mut noise, pkt) {
+        Ok((identity, timestamp, versions)) => {
+            let version = packet::negotiate(&versions)?;
+            Ok((HandshakeResponder { noise, timestamp, version }, identity, timestamp))
+        }
+        // a plain handshake that failed is more interesting than the mutual one we guessed
+        Err(_) => Err(e),
+    }
 }
 
 #[derive(Default)]
@@ -400,14 +648,136 @@ impl CryptoResolver for RandResolver {
     }
 }
 
+/// the static key of a secret kept by an external signer.
+/// snow set()s the static key and generate()s ephemeral ones,
+/// so only a dh that was set goes to the signer
+struct ExternalDh {
+    inner:    Box<snow::types::Dh>,
+    secret:   Secret,
+    public:   [u8; 32],
+    external: bool,
+}
+
+impl snow::types::Dh for ExternalDh {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn pub_len(&self) -> usize {
+        self.inner.pub_len()
+    }
+
+    fn priv_len(&self) -> usize {
+        self.inner.priv_len()
+    }
+
+    fn set(&mut self, _: &[u8]) {
+        self.external = true;
+    }
+
+    fn generate(&mut self, rng: &mut snow::types::Random) {
+        self.inner.generate(rng);
+        self.external = false;
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        if self.external {
+            &self.public
+        } else {
+            self.inner.pubkey()
+        }
+    }
+
+    fn privkey(&self) -> &[u8] {
+        if self.external {
+            &[]
+        } else {
+            self.inner.privkey()
+        }
+    }
+
+    fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        if !self.external {
+            return self.inner.dh(pubkey, out);
+        }
+        match self.secret.dh(pubkey) {
+            Ok(shared) => {
+                out[..32].copy_from_slice(&shared);
+                Ok(())
+            }
+            Err(e) => {
+                warn!("external secret: {}", e);
+                Err(())
+            }
+        }
+    }
+}
+
+struct ExternalResolver {
+    secret: Secret,
+}
+
+impl CryptoResolver for ExternalResolver {
+    fn resolve_rng(&self) -> Option<Box<snow::types::Random>> {
+        None
+    }
+
+    fn resolve_dh(
+        &self,
+        choice: &snow::params::DHChoice,
+    ) -> Option<Box<(dyn snow::types::Dh + 'static)>> {
+        let inner = snow::resolvers::HaclStarResolver::default().resolve_dh(choice)?;
+        let mut public = [0u8; 32];
+        public.copy_from_slice(self.secret.address().as_bytes());
+        Some(Box::new(ExternalDh {
+            inner,
+            secret: self.secret.clone(),
+            public,
+            external: false,
+        }))
+    }
+
+    fn resolve_hash(
+        &self,
+        _: &snow::params::HashChoice,
+    ) -> Option<Box<(dyn snow::types::Hash + 'static)>> {
+        None
+    }
+
+    fn resolve_cipher(
+        &self,
+        _: &snow::params::CipherChoice,
+    ) -> Option<Box<(dyn snow::types::Cipher + 'static)>> {
+        None
+    }
+}
+
+fn resolver() -> Box<CryptoResolver> {
+    Box::new(FallbackResolver::new(
+        Box::new(snow::resolvers::HaclStarResolver::default()),
+        Box::new(RandResolver::default()),
+    ))
+}
+
 fn new_noise_builder<'builder>(params: NoiseParams) -> Builder<'builder> {
-    Builder::with_resolver(
-        params,
-        Box::new(FallbackResolver::new(
-            Box::new(snow::resolvers::HaclStarResolver::default()),
-            Box::new(RandResolver::default()),
-        )),
-    )
+    Builder::with_resolver(params, resolver())
+}
+
+/// a builder with our static key.
+/// an external secret stays with its signer, snow does the static dh through ExternalDh
+fn new_static_noise_builder<'builder>(params: NoiseParams, secret: &'builder Secret) -> Builder<'builder> {
+    match secret.as_bytes() {
+        Ok(key) => new_noise_builder(params).local_private_key(key),
+        Err(_) => Builder::with_resolver(
+            params,
+            Box::new(FallbackResolver::new(
+                Box::new(ExternalResolver { secret: secret.clone() }),
+                resolver(),
+            )),
+        )
+        // snow only enables the static key when given one. ExternalDh ignores it
+        .local_private_key(&[0; 32]),
+    }
 }
 
 /*
@@ -521,3 +891,67 @@ fn large_pkt() {
 }
 
 */
+
+#[test]
+fn mutual_handshake() {
+    let (secret_i, secret_r) = (Secret::gen(), Secret::gen());
+
+    let (mut i, pkt) = initiate_mutual(&secret_r.address(), &secret_i, 9).unwrap();
+    let (r, identity, timestamp) = respond_peer(&secret_r, pkt).unwrap();
+    assert_eq!(identity, secret_i.identity());
+    assert_eq!(timestamp, 9);
+    assert_eq!(r.remote_address(), Some(secret_i.address()));
+
+    let (mut r, pkt) = r.send_response(7, &secret_r).unwrap();
+    assert_eq!(i.recv_response(pkt).unwrap(), secret_r.identity());
+    let mut i = i.into_transport().unwrap();
+    assert_eq!(i.version(), *packet::VERSIONS.last().unwrap());
+    assert_eq!(r.version(), i.version());
+
+    let pkt = i.send(b"hello").unwrap();
+    assert_eq!(r.recv(pkt).unwrap(), b"hello");
+
+    // the plain handshake still works with the same responder
+    let (_, pkt) = initiate(None, &secret_i, 10).unwrap();
+    let (r, _, _) = respond_peer(&secret_r, pkt).unwrap();
+    assert!(r.remote_address().is_none());
+
+    // but not if we expected someone else's static key
+    let (_, pkt) = initiate_mutual(&Secret::gen().address(), &secret_i, 11).unwrap();
+    assert!(respond_peer(&secret_r, pkt).is_err());
+}
+
+#[test]
+fn external_secret() {
+    use identity::Signer;
+
+    struct Helper(Secret);
+    impl Signer for Helper {
+        fn sign(&self, text: &[u8]) -> Result<Signature, Error> {
+            self.0.sign(b"", text)
+        }
+        fn dh(&self, public: &[u8; 32]) -> Result<[u8; 32], Error> {
+            self.0.dh(public)
+        }
+    }
+    let external = |s: &Secret| Secret::external(s.identity(), s.address(), Box::new(Helper(s.clone())));
+
+    let (secret_i, secret_r) = (Secret::gen(), Secret::gen());
+    let (external_i, external_r) = (external(&secret_i), external(&secret_r));
+    assert!(external_i.as_bytes().is_err());
+
+    let (mut i, pkt) = initiate_mutual(&secret_r.address(), &external_i, 9).unwrap();
+    let (r, identity, _) = respond_peer(&external_r, pkt).unwrap();
+    assert_eq!(identity, secret_i.identity());
+    assert_eq!(r.remote_address(), Some(secret_i.address()));
+
+    let (mut r, pkt) = r.send_response(7, &external_r).unwrap();
+    assert_eq!(i.recv_response(pkt).unwrap(), secret_r.identity());
+    let mut i = i.into_transport().unwrap();
+    let pkt = i.send(b"hello").unwrap();
+    assert_eq!(r.recv(pkt).unwrap(), b"hello");
+
+    // a signer answering for some other key
+    let wrong = Secret::external(secret_i.identity(), secret_i.address(), Box::new(Helper(Secret::gen())));
+    assert!(initiate(None, &wrong, 10).is_err());
+}
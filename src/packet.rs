@@ -4,12 +4,35 @@ use std::io::{Read, Write};
 
 pub type RoutingKey = u64;
 
+/// the wire versions this build speaks, oldest first.
+/// 0x08 is the original protocol, 0x09 adds datagram, path challenge, path response and rekey frames
+pub const VERSIONS: &[u8] = &[0x08, 0x09];
+
+/// handshakes are always sent with the oldest version, so every peer can read them.
+/// the handshake payload then advertises what else we speak
+pub const HANDSHAKE_VERSION: u8 = 0x08;
+
+/// the newest version both we and the peer speak.
+/// peers that advertise nothing are from before negotiation, and speak 0x08
+pub fn negotiate(theirs: &[u8]) -> Result<u8, Error> {
+    if theirs.is_empty() {
+        return Ok(HANDSHAKE_VERSION);
+    }
+    match VERSIONS.iter().rev().find(|v| theirs.contains(v)) {
+        Some(v) => Ok(*v),
+        None => Err(Error::InvalidVersion {
+            version: theirs.iter().cloned().max().unwrap(),
+        }),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum RoutingDirection {
     Initiator2Responder,
     Responder2Initiator,
 }
 
+#[derive(Clone)]
 pub struct EncryptedPacket {
     pub version: u8,
     pub route: RoutingKey,
@@ -35,7 +58,7 @@ impl EncryptedPacket {
         let route = route.as_ref().read_u64::<BigEndian>()?;
         let counter = inbuf.read_u64::<BigEndian>()?;
 
-        if version != 0x08 || reserved != [0xff, 0xff, 0xff] {
+        if !VERSIONS.contains(&version) || reserved != [0xff, 0xff, 0xff] {
             return Err(Error::InvalidVersion { version }.into());
         }
 
@@ -83,6 +106,27 @@ fn decode_invalid_packets() {
     assert!(EncryptedPacket::decode(&[]).is_err());
     assert!(EncryptedPacket::decode(&[0; 128]).is_err());
     assert!(EncryptedPacket::decode(&[0x08; 128]).is_err());
+
+    let mut pkt = vec![0x42, 0xff, 0xff, 0xff];
+    pkt.extend_from_slice(&[0; 16]);
+    match EncryptedPacket::decode(&pkt) {
+        Err(Error::InvalidVersion { version: 0x42 }) => (),
+        _ => panic!("expected InvalidVersion"),
+    }
+    pkt[0] = 0x09;
+    assert_eq!(EncryptedPacket::decode(&pkt).unwrap().version, 0x09);
+}
+
+#[test]
+fn version_negotiation() {
+    assert_eq!(negotiate(&[]).unwrap(), 0x08);
+    assert_eq!(negotiate(&[0x08]).unwrap(), 0x08);
+    assert_eq!(negotiate(&[0x08, 0x09]).unwrap(), 0x09);
+    assert_eq!(negotiate(&[0x09, 0x08, 0x0c]).unwrap(), 0x09);
+    match negotiate(&[0x0c, 0x0d]) {
+        Err(Error::InvalidVersion { version: 0x0d }) => (),
+        _ => panic!("expected InvalidVersion"),
+    }
 }
 
 #[derive(PartialEq)]
@@ -110,6 +154,20 @@ pub enum Frame {
         timeout: Option<u16>,
         sleeping: bool,
     },
+    Datagram {
+        payload: Vec<u8>,
+    },
+    PathChallenge {
+        data: u64,
+    },
+    PathResponse {
+        data: u64,
+    },
+    /// the initiator offers keys for the next epoch
+    Rekey {
+        epoch: u32,
+        seed: [u8; 32],
+    },
 }
 
 impl std::fmt::Debug for Frame {
@@ -130,6 +188,10 @@ impl std::fmt::Debug for Frame {
             Frame::Config { timeout, sleeping } => {
                 write!(f, "Close[t:{:?},s:{}]", timeout, sleeping)
             }
+            Frame::Datagram { payload } => write!(f, "Datagram[p:{}]", payload.len()),
+            Frame::PathChallenge { data } => write!(f, "PathChallenge[{:x}]", data),
+            Frame::PathResponse { data } => write!(f, "PathResponse[{:x}]", data),
+            Frame::Rekey { epoch, .. } => write!(f, "Rekey[e:{}]", epoch),
         }
     }
 }
@@ -144,6 +206,10 @@ impl Frame {
             Frame::Disconnect => 1,
             Frame::Close { .. } => 1 + 4 + 8,
             Frame::Config { timeout, .. } => 1 + 1 + 2 + if timeout.is_some() { 2 } else { 0 },
+            Frame::Datagram { payload } => 1 + 2 + payload.len(),
+            Frame::PathChallenge { .. } => 1 + 8,
+            Frame::PathResponse { .. } => 1 + 8,
+            Frame::Rekey { .. } => 1 + 4 + 32,
         }
     }
 
@@ -160,6 +226,28 @@ impl Frame {
         }
     }
 
+    /// datagrams are never retransmitted
+    pub fn is_datagram(&self) -> bool {
+        match self {
+            Frame::Datagram { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// a path response answers one specific challenge and is not retransmitted.
+    /// the challenger sends a new challenge instead
+    pub fn is_path_response(&self) -> bool {
+        match self {
+            Frame::PathResponse { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// queued again when the packet carrying it is lost
+    pub fn is_retransmittable(&self) -> bool {
+        !self.is_ack() && !self.is_ping() && !self.is_datagram() && !self.is_path_response()
+    }
+
     pub fn order(&self) -> u64 {
         match self {
             Frame::Header { .. } => 1,
@@ -169,7 +257,9 @@ impl Frame {
         }
     }
 
-    pub fn encode<W: Write>(&self, mut w: W) -> Result<usize, Error> {
+    /// encode for a channel that negotiated version.
+    /// frames the version doesn't have are an error rather than something the peer can't read
+    pub fn encode<W: Write>(&self, version: u8, mut w: W) -> Result<usize, Error> {
         let len = self.len();
         match self {
             Frame::Header { stream, payload } => {
@@ -234,11 +324,42 @@ impl Frame {
                     w.write_u16::<BigEndian>(*timeout)?;
                 }
             }
+            Frame::Datagram { payload } => {
+                assert!(payload.len() + 12 < u16::max_value() as usize);
+                if version < 0x09 {
+                    return Err(Error::InvalidFrameType { typ: 0x08 });
+                }
+                w.write_u8(0x08)?;
+                w.write_u16::<BigEndian>(payload.len() as u16)?;
+                assert_eq!(w.write(payload)?, payload.len());
+            }
+            Frame::PathChallenge { data } => {
+                if version < 0x09 {
+                    return Err(Error::InvalidFrameType { typ: 0x09 });
+                }
+                w.write_u8(0x09)?;
+                w.write_u64::<BigEndian>(*data)?;
+            }
+            Frame::PathResponse { data } => {
+                if version < 0x09 {
+                    return Err(Error::InvalidFrameType { typ: 0x0a });
+                }
+                w.write_u8(0x0a)?;
+                w.write_u64::<BigEndian>(*data)?;
+            }
+            Frame::Rekey { epoch, seed } => {
+                if version < 0x09 {
+                    return Err(Error::InvalidFrameType { typ: 0x0b });
+                }
+                w.write_u8(0x0b)?;
+                w.write_u32::<BigEndian>(*epoch)?;
+                w.write_all(seed)?;
+            }
         }
         Ok(len)
     }
 
-    pub fn decode<R: Read>(mut r: R) -> Result<Vec<Frame>, Error> {
+    pub fn decode<R: Read>(version: u8, mut r: R) -> Result<Vec<Frame>, Error> {
         let mut f = Vec::new();
 
         loop {
@@ -302,6 +423,26 @@ impl Frame {
 
                     f.push(Frame::Config { timeout, sleeping });
                 }
+                Ok(0x08) if version >= 0x09 => {
+                    let len = r.read_u16::<BigEndian>()?;
+                    let mut payload = vec![0; len as usize];
+                    r.read_exact(&mut payload)?;
+                    f.push(Frame::Datagram { payload });
+                }
+                Ok(0x09) if version >= 0x09 => {
+                    let data = r.read_u64::<BigEndian>()?;
+                    f.push(Frame::PathChallenge { data });
+                }
+                Ok(0x0a) if version >= 0x09 => {
+                    let data = r.read_u64::<BigEndian>()?;
+                    f.push(Frame::PathResponse { data });
+                }
+                Ok(0x0b) if version >= 0x09 => {
+                    let epoch = r.read_u32::<BigEndian>()?;
+                    let mut seed = [0; 32];
+                    r.read_exact(&mut seed)?;
+                    f.push(Frame::Rekey { epoch, seed });
+                }
                 Ok(typ) => return Err(Error::InvalidFrameType { typ }.into()),
             };
         }
@@ -315,11 +456,11 @@ fn config_frames() {
         sleeping: false,
     };
     let mut w = Vec::new();
-    let written = frame.encode(&mut w).unwrap();
+    let written = frame.encode(0x09, &mut w).unwrap();
     assert_eq!(written, w.len());
     assert_eq!(w, &[0x07, 0x00, 0x00, 0x00]);
 
-    let frames = Frame::decode(&w[..]).unwrap();
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
     assert_eq!(frames.len(), 1);
     if let Frame::Config {
         timeout: None,
@@ -335,11 +476,11 @@ fn config_frames() {
         sleeping: true,
     };
     let mut w = Vec::new();
-    let written = frame.encode(&mut w).unwrap();
+    let written = frame.encode(0x09, &mut w).unwrap();
     assert_eq!(written, w.len());
     assert_eq!(w, &[0x07, 0b11000000, 0, 2, 5, 12]);
 
-    let frames = Frame::decode(&w[..]).unwrap();
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
     assert_eq!(frames.len(), 1);
     if let Frame::Config {
         timeout: Some(1292),
@@ -351,6 +492,82 @@ fn config_frames() {
     }
 }
 
+#[test]
+fn datagram_frames() {
+    let frame = Frame::Datagram {
+        payload: b"temp=21".to_vec(),
+    };
+    let mut w = Vec::new();
+    let written = frame.encode(0x09, &mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(
+        w,
+        &[0x08, 0x00, 0x07, b't', b'e', b'm', b'p', b'=', b'2', b'1']
+    );
+
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0], frame);
+    assert!(frames[0].is_datagram());
+}
+
+#[test]
+fn path_validation_frames() {
+    let mut w = Vec::new();
+    Frame::PathChallenge { data: 0x0102030405060708 }.encode(0x09, &mut w).unwrap();
+    Frame::PathResponse { data: 42 }.encode(0x09, &mut w).unwrap();
+    assert_eq!(w.len(), 18);
+    assert_eq!(&w[..9], &[0x09, 1, 2, 3, 4, 5, 6, 7, 8]);
+
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
+    assert_eq!(
+        frames,
+        vec![
+            Frame::PathChallenge { data: 0x0102030405060708 },
+            Frame::PathResponse { data: 42 },
+        ]
+    );
+    assert!(!frames[0].is_path_response());
+    assert!(frames[1].is_path_response());
+}
+
+#[test]
+fn rekey_frames() {
+    let frame = Frame::Rekey {
+        epoch: 3,
+        seed: [0x42; 32],
+    };
+    let mut w = Vec::new();
+    let written = frame.encode(0x09, &mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(&w[..5], &[0x0b, 0, 0, 0, 3]);
+
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
+    assert_eq!(frames, vec![frame]);
+
+    // truncated seed
+    assert!(Frame::decode(0x09, &w[..20]).is_err());
+
+    // version 0x08 has no rekey frames
+    assert!(frame.encode(0x08, &mut Vec::new()).is_err());
+    assert!(Frame::decode(0x08, &w[..]).is_err());
+}
+
+#[test]
+fn frames_since_0x09() {
+    let frames = vec![
+        Frame::Datagram { payload: vec![1] },
+        Frame::PathChallenge { data: 1 },
+        Frame::PathResponse { data: 1 },
+    ];
+    for frame in frames {
+        let mut w = Vec::new();
+        frame.encode(0x09, &mut w).unwrap();
+        assert!(frame.encode(0x08, &mut Vec::new()).is_err());
+        assert!(Frame::decode(0x08, &w[..]).is_err());
+    }
+}
+
 #[test]
 fn encode_frame() {
     let frame = Frame::Stream {
@@ -360,7 +577,7 @@ fn encode_frame() {
     };
 
     let mut w = Vec::new();
-    let written = frame.encode(&mut w).unwrap();
+    let written = frame.encode(0x09, &mut w).unwrap();
     assert_eq!(written, w.len());
     assert_eq!(
         w,
@@ -375,7 +592,7 @@ fn encode_frame() {
         acked: vec![0x872],
     };
     let mut w = Vec::new();
-    let written = frame.encode(&mut w).unwrap();
+    let written = frame.encode(0x09, &mut w).unwrap();
     assert_eq!(written, w.len());
     assert_eq!(written, 1 + 2 + 2 + 8);
     assert_eq!(
@@ -393,7 +610,7 @@ fn decode_frame() {
         0x00, 0x00, 0x00,
     ];
 
-    let frames = Frame::decode(&r[..]).unwrap();
+    let frames = Frame::decode(0x09, &r[..]).unwrap();
     assert_eq!(frames.len(), 2);
     if let Frame::Stream {
         order,
@@ -0,0 +1,611 @@
+//! selection of the address a peer is reached on.
+//!
+//! every candidate address gets path challenges. the round trip time of the answer
+//! tells us whether the path works and how fast it is.
+//! candidates are ranked by category first (local, internet, broker), then by rtt,
+//! but a better category only wins if it actually answered.
+//! if nothing answers, the peer is reached through the broker or wherever its packets come from,
+//! and another round is tried later.
+
+use error::Error;
+use proto::path::Category;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// how long to wait for a path response before challenging again, in ms
+pub const PATH_CHALLENGE_TIMEOUT: u64 = 1000;
+
+/// number of unanswered challenges after which a path is considered broken
+pub const MAX_PATH_CHALLENGES: u16 = 3;
+
+/// once one candidate works, how long to wait for the others before settling, in ms
+const DISCOVERY_TIMEOUT: u64 = 1000;
+
+/// a working path in the same category only replaces the current one
+/// if its rtt is below this fraction of the current rtt
+const RTT_IMPROVEMENT: f64 = 0.75;
+
+/// default interval to probe all candidates again while established, in seconds
+const DEFAULT_REEVALUATE: u64 = 60;
+
+/// after a round in which no candidate answered, how long until the next one, in seconds
+const RETRY_INTERVAL: u64 = 10;
+
+/// which paths may be used to reach a peer
+#[derive(Clone, Copy, Debug)]
+pub struct PathPolicy {
+    /// send through the broker if nothing better works
+    pub relay: bool,
+    /// use public internet addresses, not just the local network
+    pub internet: bool,
+    /// seconds between re-evaluating candidates while established. 0 disables
+    pub reevaluate: u64,
+}
+
+impl Default for PathPolicy {
+    fn default() -> Self {
+        Self {
+            relay: true,
+            internet: true,
+            reevaluate: DEFAULT_REEVALUATE,
+        }
+    }
+}
+
+impl PathPolicy {
+    pub fn allows(&self, category: Category) -> bool {
+        match category {
+            Category::Local => true,
+            Category::Internet => self.internet,
+            Category::BrokerOrigin => self.relay,
+            Category::Invalid => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Probe {
+    Idle,
+    Waiting {
+        challenge: u64,
+        sent: Instant,
+        attempts: u16,
+    },
+    Works(Duration),
+    Failed,
+}
+
+struct Candidate {
+    category: Category,
+    probe: Probe,
+}
+
+pub struct Paths {
+    candidates: HashMap<SocketAddr, Candidate>,
+    policy: PathPolicy,
+    /// start of the current probing round, None if not probing
+    round: Option<Instant>,
+    next_round: Instant,
+    /// the last address an authenticated packet came from
+    heard: Option<SocketAddr>,
+    /// the last round ended without any candidate answering
+    unanswered: bool,
+    /// category of a fixed path. None if we can't tell, like for a direct connect to a given address
+    fixed: Option<Category>,
+}
+
+impl Paths {
+    pub fn new(candidates: HashMap<SocketAddr, Category>, policy: PathPolicy, now: Instant) -> Self {
+        let candidates = candidates
+            .into_iter()
+            .filter(|(addr, category)| {
+                let allowed = policy.allows(*category);
+                if !allowed {
+                    debug!("path policy excludes {} ({:?})", addr, category);
+                }
+                allowed
+            })
+            .map(|(addr, category)| {
+                (
+                    addr,
+                    Candidate {
+                        category,
+                        probe: Probe::Idle,
+                    },
+                )
+            })
+            .collect();
+        Self {
+            candidates,
+            policy,
+            round: Some(now),
+            next_round: now,
+            heard: None,
+            unanswered: false,
+            fixed: None,
+        }
+    }
+
+    /// a fixed path, like the broker. never probed
+    pub fn fixed(category: Option<Category>, policy: PathPolicy, now: Instant) -> Self {
+        Self {
+            candidates: HashMap::new(),
+            policy,
+            round: None,
+            next_round: now,
+            heard: None,
+            unanswered: false,
+            fixed: category,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    pub fn category(&self, addr: &SocketAddr) -> Option<Category> {
+        self.candidates.get(addr).map(|c| c.category).or(self.fixed)
+    }
+
+    pub fn rtt(&self, addr: &SocketAddr) -> Option<Duration> {
+        match self.candidates.get(addr).map(|c| &c.probe) {
+            Some(Probe::Works(rtt)) => Some(*rtt),
+            _ => None,
+        }
+    }
+
+    /// candidates that are worth sending to while discovering
+    pub fn usable(&self) -> Vec<(SocketAddr, Category)> {
+        self.candidates
+            .iter()
+            .filter(|(_, c)| c.probe != Probe::Failed)
+            .map(|(addr, c)| (*addr, c.category))
+            .collect()
+    }
+
+    /// a packet arrived from an address we didn't know about.
+    /// it's probably the peer's address behind nat, so consider it an internet path
+    pub fn seen(&mut self, addr: SocketAddr) {
+        if self.candidates.contains_key(&addr) || !self.policy.allows(Category::Internet) {
+            return;
+        }
+        debug!("new candidate path {}", addr);
+        self.candidates.insert(
+            addr,
+            Candidate {
+                category: Category::Internet,
+                probe: Probe::Idle,
+            },
+        );
+    }
+
+    /// an authenticated packet arrived from addr, so the peer can at least send on it
+    pub fn heard(&mut self, addr: SocketAddr) {
+        self.heard = Some(addr);
+    }
+
+    /// where to send when no candidate answers: where the peer's packets come from,
+    /// otherwise the broker relay
+    pub fn fallback(&self) -> Option<SocketAddr> {
+        if let Some(addr) = self.heard {
+            if self.candidates.contains_key(&addr) {
+                return Some(addr);
+            }
+        }
+        self.candidates
+            .iter()
+            .find(|(_, c)| c.category == Category::BrokerOrigin)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// start probing every candidate again. returns false if it's not time yet
+    pub fn reevaluate(&mut self, current: SocketAddr, now: Instant) -> bool {
+        if self.round.is_some() || now < self.next_round {
+            return false;
+        }
+        if self.policy.reevaluate == 0 && !self.unanswered {
+            return false;
+        }
+        self.seen(current);
+        if self.candidates.len() < 2 && !self.unanswered {
+            self.next_round = now + Duration::from_secs(self.policy.reevaluate);
+            return false;
+        }
+        self.restart(now);
+        true
+    }
+
+    /// while still discovering, start another round once the one that found nothing is
+    /// far enough in the past. returns false if it's not time yet
+    pub fn retry(&mut self, now: Instant) -> bool {
+        if self.round.is_some() || now < self.next_round || self.candidates.is_empty() {
+            return false;
+        }
+        self.restart(now);
+        true
+    }
+
+    fn restart(&mut self, now: Instant) {
+        for c in self.candidates.values_mut() {
+            c.probe = Probe::Idle;
+        }
+        self.round = Some(now);
+    }
+
+    /// send outstanding challenges. send creates a challenge packet and sends it to the address.
+    /// returns when the next probe is due
+    pub fn tick<F>(&mut self, now: Instant, mut send: F) -> Result<Option<Duration>, Error>
+    where
+        F: FnMut(&SocketAddr, u64) -> Result<(), Error>,
+    {
+        if self.round.is_none() {
+            if self.candidates.is_empty() || (self.policy.reevaluate == 0 && !self.unanswered) {
+                return Ok(None);
+            }
+            if self.next_round <= now {
+                return Ok(Some(Duration::from_millis(0)));
+            }
+            return Ok(Some(self.next_round - now));
+        }
+        let timeout = Duration::from_millis(PATH_CHALLENGE_TIMEOUT);
+        let mut next: Option<Duration> = None;
+        for (addr, c) in &mut self.candidates {
+            let (challenge, attempts) = match c.probe {
+                Probe::Idle => (::rand::random::<u64>(), 1),
+                Probe::Waiting {
+                    challenge,
+                    sent,
+                    attempts,
+                } => {
+                    let elapsed = now.duration_since(sent);
+                    if elapsed < timeout {
+                        next = Some(next.map_or(timeout - elapsed, |n| n.min(timeout - elapsed)));
+                        continue;
+                    }
+                    if attempts >= MAX_PATH_CHALLENGES {
+                        debug!("path {} ({:?}) does not answer", addr, c.category);
+                        c.probe = Probe::Failed;
+                        continue;
+                    }
+                    (challenge, attempts + 1)
+                }
+                Probe::Works(_) | Probe::Failed => continue,
+            };
+            send(addr, challenge)?;
+            c.probe = Probe::Waiting {
+                challenge,
+                sent: now,
+                attempts,
+            };
+            next = Some(next.map_or(timeout, |n| n.min(timeout)));
+        }
+
+        if let Some(started) = self.round {
+            let wait = Duration::from_millis(DISCOVERY_TIMEOUT);
+            let elapsed = now.duration_since(started);
+            if elapsed < wait && self.best().is_some() {
+                next = Some(next.map_or(wait - elapsed, |n| n.min(wait - elapsed)));
+            }
+        }
+        Ok(next)
+    }
+
+    /// check outstanding challenges against the path responses the channel received
+    pub fn on_recv<F>(&mut self, now: Instant, mut validated: F)
+    where
+        F: FnMut(u64) -> bool,
+    {
+        for (addr, c) in &mut self.candidates {
+            if let Probe::Waiting { challenge, sent, .. } = c.probe {
+                if validated(challenge) {
+                    let rtt = now.duration_since(sent);
+                    debug!("path {} ({:?}) works, rtt {:?}", addr, c.category, rtt);
+                    c.probe = Probe::Works(rtt);
+                }
+            }
+        }
+    }
+
+    /// the best working path: lowest category, then lowest rtt
+    pub fn best(&self) -> Option<SocketAddr> {
+        self.candidates
+            .iter()
+            .filter_map(|(addr, c)| match c.probe {
+                Probe::Works(rtt) => Some((*addr, c.category as i32, rtt)),
+                _ => None,
+            })
+            .min_by_key(|(_, category, rtt)| (*category, *rtt))
+            .map(|(addr, _, _)| addr)
+    }
+
+    /// if the current probing round is complete, end it and return the path to use.
+    /// every candidate answered or failed, or at least one works and the others had long enough.
+    /// if every candidate failed, the round ends with the fallback and a retry is scheduled
+    pub fn settle(&mut self, now: Instant) -> Option<SocketAddr> {
+        let started = self.round?;
+        let complete = self.candidates.values().all(|c| match c.probe {
+            Probe::Works(_) | Probe::Failed => true,
+            _ => false,
+        });
+        let best = match self.best() {
+            Some(best) => best,
+            None if complete => {
+                self.round = None;
+                self.unanswered = true;
+                self.next_round = now + Duration::from_secs(RETRY_INTERVAL);
+                let fallback = self.fallback();
+                debug!(
+                    "no path answered, using {:?}. trying again in {}s",
+                    fallback, RETRY_INTERVAL
+                );
+                return fallback;
+            }
+            None => return None,
+        };
+        if !complete && now.duration_since(started) < Duration::from_millis(DISCOVERY_TIMEOUT) {
+            return None;
+        }
+        self.round = None;
+        self.unanswered = false;
+        self.next_round = now + Duration::from_secs(self.policy.reevaluate);
+        Some(best)
+    }
+
+    /// end discovery without probing, for peers that can't answer path challenges.
+    /// uses where the peer's packets come from, or the relay once nothing arrived in time
+    pub fn settle_unprobed(&mut self, now: Instant) -> Option<SocketAddr> {
+        let started = self.round?;
+        let addr = match self.fallback() {
+            Some(addr) if self.heard == Some(addr) => addr,
+            Some(addr) if now.duration_since(started) >= Duration::from_millis(DISCOVERY_TIMEOUT) => addr,
+            _ => return None,
+        };
+        self.round = None;
+        Some(addr)
+    }
+
+    /// whether the result of a re-evaluation should replace the current path
+    pub fn better(&self, candidate: &SocketAddr, current: &SocketAddr) -> bool {
+        if candidate == current {
+            return false;
+        }
+        let (cand_cat, cand_rtt) = match self.candidates.get(candidate) {
+            Some(Candidate {
+                category,
+                probe: Probe::Works(rtt),
+            }) => (*category as i32, *rtt),
+            _ => return false,
+        };
+        let (cur_cat, cur_rtt) = match self.candidates.get(current) {
+            Some(Candidate {
+                category,
+                probe: Probe::Works(rtt),
+            }) => (*category as i32, *rtt),
+            // the current path didn't answer, anything that works is better
+            _ => return true,
+        };
+        if cand_cat != cur_cat {
+            return cand_cat < cur_cat;
+        }
+        let cur_ms = cur_rtt.as_secs() as f64 * 1000.0 + cur_rtt.subsec_millis() as f64;
+        let cand_ms = cand_rtt.as_secs() as f64 * 1000.0 + cand_rtt.subsec_millis() as f64;
+        cand_ms < cur_ms * RTT_IMPROVEMENT
+    }
+}
+
+#[cfg(test)]
+fn candidates(v: &[(&str, Category)]) -> HashMap<SocketAddr, Category> {
+    v.iter().map(|(a, c)| (a.parse().unwrap(), *c)).collect()
+}
+
+#[test]
+fn working_internet_beats_broken_local() {
+    let now = Instant::now();
+    let mut p = Paths::new(
+        candidates(&[
+            ("192.168.1.2:8443", Category::Local),
+            ("1.2.3.4:8443", Category::Internet),
+            ("5.6.7.8:8443", Category::BrokerOrigin),
+        ]),
+        PathPolicy::default(),
+        now,
+    );
+
+    let mut sent = HashMap::new();
+    p.tick(now, |addr, c| {
+        sent.insert(*addr, c);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(sent.len(), 3);
+
+    // internet and broker answer, local doesn't
+    let internet: SocketAddr = "1.2.3.4:8443".parse().unwrap();
+    let broker: SocketAddr = "5.6.7.8:8443".parse().unwrap();
+    let answered = vec![sent[&internet], sent[&broker]];
+    p.on_recv(now + Duration::from_millis(80), |c| answered.contains(&c));
+    assert_eq!(p.best(), Some(internet));
+
+    // local still gets its chance until the discovery timeout
+    assert_eq!(p.settle(now + Duration::from_millis(100)), None);
+    assert_eq!(p.settle(now + Duration::from_millis(1000)), Some(internet));
+}
+
+#[test]
+fn local_wins_when_it_works() {
+    let now = Instant::now();
+    let mut p = Paths::new(
+        candidates(&[
+            ("192.168.1.2:8443", Category::Local),
+            ("1.2.3.4:8443", Category::Internet),
+        ]),
+        PathPolicy::default(),
+        now,
+    );
+    p.tick(now, |_, _| Ok(())).unwrap();
+
+    // everything answers, settle right away
+    p.on_recv(now + Duration::from_millis(5), |_| true);
+    assert_eq!(p.settle(now + Duration::from_millis(5)), Some("192.168.1.2:8443".parse().unwrap()));
+}
+
+#[test]
+fn policy_never_relay() {
+    let now = Instant::now();
+    let policy = PathPolicy {
+        relay: false,
+        ..PathPolicy::default()
+    };
+    let mut p = Paths::new(
+        candidates(&[("5.6.7.8:8443", Category::BrokerOrigin)]),
+        policy,
+        now,
+    );
+    assert!(p.is_empty());
+    assert_eq!(p.tick(now, |_, _| panic!("must not probe")).unwrap(), None);
+    assert_eq!(p.settle(now + Duration::from_secs(10)), None);
+}
+
+#[test]
+fn broken_paths_fail() {
+    let now = Instant::now();
+    let mut p = Paths::new(candidates(&[("1.2.3.4:8443", Category::Internet)]), PathPolicy::default(), now);
+    let mut count = 0;
+    let mut t = now;
+    for _ in 0..MAX_PATH_CHALLENGES + 1 {
+        p.tick(t, |_, _| {
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+        t += Duration::from_millis(PATH_CHALLENGE_TIMEOUT);
+    }
+    assert_eq!(count, MAX_PATH_CHALLENGES);
+    assert!(p.usable().is_empty());
+}
+
+#[test]
+fn reevaluation_prefers_faster_path() {
+    let now = Instant::now();
+    let a: SocketAddr = "1.2.3.4:8443".parse().unwrap();
+    let b: SocketAddr = "4.3.2.1:8443".parse().unwrap();
+    let mut p = Paths::new(
+        candidates(&[("1.2.3.4:8443", Category::Internet), ("4.3.2.1:8443", Category::Internet)]),
+        PathPolicy::default(),
+        now,
+    );
+    let mut sent = HashMap::new();
+    p.tick(now, |addr, c| {
+        sent.insert(*addr, c);
+        Ok(())
+    })
+    .unwrap();
+    p.on_recv(now + Duration::from_millis(100), |c| c == sent[&a]);
+    p.on_recv(now + Duration::from_millis(90), |c| c == sent[&b]);
+
+    // 90ms is not enough of an improvement over 100ms
+    assert_eq!(p.settle(now + Duration::from_millis(100)), Some(b));
+    assert!(!p.better(&a, &b));
+    assert!(!p.better(&b, &b));
+
+    // not yet time to look again, but wake up when it is
+    assert!(!p.reevaluate(b, now + Duration::from_secs(1)));
+    assert_eq!(
+        p.tick(now + Duration::from_secs(1), |_, _| panic!("must not probe")).unwrap(),
+        Some(Duration::from_millis(59_100))
+    );
+    assert!(p.reevaluate(b, now + Duration::from_secs(61)));
+}
+
+#[test]
+fn all_challenges_fail() {
+    let now = Instant::now();
+    let relay: SocketAddr = "5.6.7.8:8443".parse().unwrap();
+    let mut p = Paths::new(
+        candidates(&[("1.2.3.4:8443", Category::Internet), ("5.6.7.8:8443", Category::BrokerOrigin)]),
+        PathPolicy::default(),
+        now,
+    );
+    let mut t = now;
+    for _ in 0..MAX_PATH_CHALLENGES + 1 {
+        p.tick(t, |_, _| Ok(())).unwrap();
+        t += Duration::from_millis(PATH_CHALLENGE_TIMEOUT);
+    }
+
+    // nothing answered, relay through the broker and try again later
+    assert_eq!(p.settle(t), Some(relay));
+    assert_eq!(
+        p.tick(t, |_, _| panic!("must not probe")).unwrap(),
+        Some(Duration::from_secs(RETRY_INTERVAL))
+    );
+    assert!(!p.reevaluate(relay, t));
+    t += Duration::from_secs(RETRY_INTERVAL);
+    assert!(p.reevaluate(relay, t));
+    let mut count = 0;
+    p.tick(t, |_, _| {
+        count += 1;
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn all_challenges_fail_without_relay() {
+    let now = Instant::now();
+    let direct: SocketAddr = "1.2.3.4:8443".parse().unwrap();
+    let policy = PathPolicy {
+        relay: false,
+        reevaluate: 0,
+        ..PathPolicy::default()
+    };
+    let mut p = Paths::new(candidates(&[("1.2.3.4:8443", Category::Internet)]), policy, now);
+    let mut t = now;
+    for _ in 0..MAX_PATH_CHALLENGES + 1 {
+        p.tick(t, |_, _| Ok(())).unwrap();
+        t += Duration::from_millis(PATH_CHALLENGE_TIMEOUT);
+    }
+
+    // nowhere to go yet, but discovery starts over
+    assert_eq!(p.settle(t), None);
+    assert!(!p.retry(t));
+    assert!(p.retry(t + Duration::from_secs(RETRY_INTERVAL)));
+    assert_eq!(p.usable().len(), 1);
+
+    // the peer's packets arrive from the address, even if its answers don't
+    p.heard(direct);
+    let mut t = t + Duration::from_secs(RETRY_INTERVAL);
+    for _ in 0..MAX_PATH_CHALLENGES + 1 {
+        p.tick(t, |_, _| Ok(())).unwrap();
+        t += Duration::from_millis(PATH_CHALLENGE_TIMEOUT);
+    }
+    assert_eq!(p.settle(t), Some(direct));
+}
+
+#[test]
+fn unprobed() {
+    let now = Instant::now();
+    let direct: SocketAddr = "1.2.3.4:8443".parse().unwrap();
+    let relay: SocketAddr = "5.6.7.8:8443".parse().unwrap();
+    let both = candidates(&[("1.2.3.4:8443", Category::Internet), ("5.6.7.8:8443", Category::BrokerOrigin)]);
+
+    // the relay only once the peer had time to show up directly
+    let mut p = Paths::new(both.clone(), PathPolicy::default(), now);
+    assert_eq!(p.settle_unprobed(now), None);
+    assert_eq!(p.usable().len(), 2);
+    assert_eq!(p.settle_unprobed(now + Duration::from_millis(DISCOVERY_TIMEOUT)), Some(relay));
+
+    let mut p = Paths::new(both, PathPolicy::default(), now);
+    p.heard(direct);
+    assert_eq!(p.settle_unprobed(now), Some(direct));
+}
+
+#[test]
+fn fixed_category() {
+    let now = Instant::now();
+    let addr: SocketAddr = "1.2.3.4:8443".parse().unwrap();
+    let broker = Paths::fixed(Some(Category::BrokerOrigin), PathPolicy::default(), now);
+    assert_eq!(broker.category(&addr), Some(Category::BrokerOrigin));
+    assert_eq!(Paths::fixed(None, PathPolicy::default(), now).category(&addr), None);
+}
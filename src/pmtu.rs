@@ -0,0 +1,289 @@
+//! packetization layer path mtu discovery, loosely following RFC8899 (DPLPMTUD)
+//!
+//! probes are padded ping packets that are not tracked by recovery,
+//! so losing one does not count as congestion.
+//! since noise pads every packet to 256 bytes, probes step up in 256 byte increments.
+//! the ceiling is an ip mtu. packet sizes are what's left after the ip and udp headers.
+
+use std::cmp::{max, min};
+
+/// bytes in front of the encrypted payload
+const HEADER: usize = 20;
+
+/// noise padding granularity
+const STEP: usize = 256;
+
+/// smallest packet that still fits a full size stream frame (1200 bytes payload)
+pub const MIN_PACKET_SIZE: usize = HEADER + 5 * STEP;
+
+/// ipv4 and udp header bytes
+const IPV4_OVERHEAD: usize = 28;
+
+/// ipv6 and udp header bytes
+const IPV6_OVERHEAD: usize = 48;
+
+/// number of unacknowledged probes of the same size before that size is considered too big
+const MAX_PROBES: u16 = 3;
+
+/// how long to wait for a probe ack if there is no rtt measurement yet
+const DEFAULT_PROBE_TIMEOUT: u64 = 1000;
+
+/// how long to wait before searching for a bigger mtu again, after the search completed
+const RAISE_TIMER: u64 = 600_000;
+
+/// size on the wire of a packet with payload_len bytes of frames
+pub fn packet_size(payload_len: usize) -> usize {
+    // see noise::send: 2 bytes length, 16 bytes tag, then padded up to the next 256 bytes
+    HEADER + ((payload_len + 2 + 16) / STEP + 1) * STEP
+}
+
+/// number of frame bytes that make a packet of exactly size on the wire
+pub fn probe_payload_len(size: usize) -> usize {
+    size - HEADER - 2 - 16 - 1
+}
+
+/// round down to a size that noise padding can actually produce
+fn align(size: usize) -> usize {
+    max(MIN_PACKET_SIZE, HEADER + (size.saturating_sub(HEADER) / STEP) * STEP)
+}
+
+/// largest packet size that fits an ip mtu
+fn ceiling(floor: usize, mtu: usize, ipv6: bool) -> usize {
+    let overhead = if ipv6 { IPV6_OVERHEAD } else { IPV4_OVERHEAD };
+    max(floor, align(mtu.saturating_sub(overhead)))
+}
+
+struct Probe {
+    size: usize,
+    seq: u64,
+    sent: u64,
+    attempts: u16,
+}
+
+pub struct Pmtu {
+    floor: usize,
+    ceiling: usize,
+
+    /// configured ip mtu
+    mtu: usize,
+    ipv6: bool,
+
+    /// largest confirmed packet size
+    current: usize,
+
+    /// smallest size known not to work
+    too_big: usize,
+
+    probe: Option<Probe>,
+
+    /// earliest time to send the next probe
+    next_probe: u64,
+}
+
+impl Pmtu {
+    /// floor is a packet size, mtu is the ip mtu of the path.
+    /// assumes ipv6 until told otherwise, since its headers are bigger
+    pub fn new(floor: usize, mtu: usize) -> Self {
+        let floor = align(floor);
+        let ceiling = ceiling(floor, mtu, true);
+        Self {
+            floor,
+            ceiling,
+            mtu,
+            ipv6: true,
+            current: floor,
+            too_big: ceiling + STEP,
+            probe: None,
+            next_probe: 0,
+        }
+    }
+
+    /// the path changed address family. searches again
+    pub fn set_ipv6(&mut self, ipv6: bool) {
+        if self.ipv6 == ipv6 {
+            return;
+        }
+        self.ipv6 = ipv6;
+        self.ceiling = ceiling(self.floor, self.mtu, ipv6);
+        self.current = min(self.current, self.ceiling);
+        self.too_big = self.ceiling + STEP;
+        self.next_probe = 0;
+        if let Some(size) = self.probe.as_ref().map(|p| p.size) {
+            if size > self.ceiling {
+                self.probe = None;
+            }
+        }
+    }
+
+    /// largest packet size that may be sent on this path
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// returns the size of a probe that should be sent now, if any.
+    /// also expires outstanding probes
+    pub fn poll(&mut self, now: u64, smoothed_rtt: u64) -> Option<usize> {
+        let timeout = if smoothed_rtt == 0 {
+            DEFAULT_PROBE_TIMEOUT
+        } else {
+            max(3 * smoothed_rtt, 100)
+        };
+
+        if let Some(ref mut probe) = self.probe {
+            if now < probe.sent + timeout {
+                return None;
+            }
+            if probe.attempts < MAX_PROBES {
+                return Some(probe.size);
+            }
+            trace!("pmtu: probe size {} failed {} times", probe.size, probe.attempts);
+            self.too_big = probe.size;
+        }
+        self.probe = None;
+
+        if now < self.next_probe {
+            return None;
+        }
+
+        let size = self.current + STEP;
+        if size > self.ceiling || size >= self.too_big {
+            debug!("pmtu: search done, path mtu is {}", self.current);
+            self.next_probe = now + RAISE_TIMER;
+            self.too_big = self.ceiling + STEP;
+            return None;
+        }
+        Some(size)
+    }
+
+    pub fn on_probe_sent(&mut self, size: usize, seq: u64, now: u64) {
+        let attempts = match self.probe {
+            Some(ref probe) if probe.size == size => probe.attempts + 1,
+            _ => 1,
+        };
+        self.probe = Some(Probe {
+            size,
+            seq,
+            sent: now,
+            attempts,
+        });
+    }
+
+    pub fn on_ack(&mut self, acked: &[u64]) {
+        let confirmed = match self.probe {
+            Some(ref probe) if acked.contains(&probe.seq) => probe.size,
+            _ => return,
+        };
+        debug!("pmtu: confirmed packet size {}", confirmed);
+        self.current = min(self.ceiling, max(self.current, confirmed));
+        self.probe = None;
+    }
+
+    /// packets of the current size are persistently lost.
+    /// go back to the floor and search again later
+    pub fn on_black_hole(&mut self, now: u64) {
+        if self.current == self.floor {
+            return;
+        }
+        warn!("pmtu: black hole detected at {}, falling back to {}", self.current, self.floor);
+        self.too_big = self.current;
+        self.current = self.floor;
+        self.probe = None;
+        self.next_probe = now + RAISE_TIMER;
+    }
+
+    /// time of the next probe related event
+    pub fn deadline(&self, smoothed_rtt: u64) -> Option<u64> {
+        match self.probe {
+            Some(ref probe) => Some(
+                probe.sent
+                    + if smoothed_rtt == 0 {
+                        DEFAULT_PROBE_TIMEOUT
+                    } else {
+                        max(3 * smoothed_rtt, 100)
+                    },
+            ),
+            None if self.current + STEP <= self.ceiling => Some(self.next_probe),
+            None => None,
+        }
+    }
+}
+
+#[test]
+fn sizes() {
+    assert_eq!(MIN_PACKET_SIZE, 1300);
+    assert_eq!(packet_size(1215), 1300);
+    assert_eq!(packet_size(probe_payload_len(1556)), 1556);
+    assert_eq!(align(1500), 1300);
+    assert_eq!(align(9000), 8980);
+    assert_eq!(ceiling(1300, 1500, false), 1300);
+    assert_eq!(ceiling(1300, 9000, false), 8980 - 256);
+    assert_eq!(ceiling(1300, 1600, false), 1556);
+    assert_eq!(ceiling(1300, 1600, true), 1300);
+}
+
+#[test]
+fn search() {
+    // jumbo frame path that actually supports 4000 bytes
+    let mut p = Pmtu::new(1300, 9000);
+    p.set_ipv6(false);
+    let mut now = 0;
+    let mut seq = 0;
+    while let Some(size) = p.poll(now, 10) {
+        seq += 1;
+        p.on_probe_sent(size, seq, now);
+        if size <= 4000 {
+            p.on_ack(&[seq]);
+        } else {
+            now += 100;
+        }
+    }
+    assert_eq!(p.current(), 3860);
+
+    // nothing to do until the raise timer expires
+    assert_eq!(p.poll(now + 1, 10), None);
+}
+
+#[test]
+fn black_hole() {
+    let mut p = Pmtu::new(1300, 1840);
+    p.set_ipv6(false);
+    let size = p.poll(0, 10).unwrap();
+    assert_eq!(size, 1556);
+    p.on_probe_sent(size, 1, 0);
+    p.on_ack(&[1]);
+    assert_eq!(p.current(), 1556);
+
+    p.on_black_hole(10);
+    assert_eq!(p.current(), 1300);
+    assert_eq!(p.poll(20, 10), None);
+}
+
+#[test]
+fn floor_is_ceiling() {
+    // a plain ethernet path has no room above the floor
+    let mut p = Pmtu::new(1300, 1500);
+    p.set_ipv6(false);
+    assert_eq!(p.current(), 1300);
+    assert_eq!(p.poll(0, 10), None);
+    assert_eq!(p.deadline(10), None);
+}
+
+#[test]
+fn address_family() {
+    // the default ceiling probes
+    let mut p = Pmtu::new(MIN_PACKET_SIZE, 9000);
+    assert_eq!(p.poll(0, 10), Some(1556));
+
+    // 1600 fits 1556 over ipv4 but not over ipv6
+    let mut p = Pmtu::new(1300, 1600);
+    assert_eq!(p.poll(0, 10), None);
+    p.set_ipv6(false);
+    let size = p.poll(0, 10).unwrap();
+    assert_eq!(size, 1556);
+    p.on_probe_sent(size, 1, 0);
+    p.on_ack(&[1]);
+    assert_eq!(p.current(), 1556);
+
+    p.set_ipv6(true);
+    assert_eq!(p.current(), 1300);
+}
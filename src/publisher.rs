@@ -4,17 +4,22 @@ use osaka::{osaka, Poll};
 use endpoint;
 use headers;
 use identity;
-use certificate;
+use nix::sys::signal::Signal;
+use packet::RoutingKey;
+use std::cell::RefCell;
 use std::process::Command;
+use std::rc::Rc;
 use axon::CommandExt;
 use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::collections::HashMap;
+use std::time::Duration;
 use osaka::Future;
 
 pub mod sft;
 pub mod shell;
 pub mod openwrt;
+pub mod signal;
 
 pub type RouteHandler = Box<Fn(
     Poll,
@@ -23,6 +28,10 @@ pub type RouteHandler = Box<Fn(
     endpoint::Stream) -> Option<osaka::Task<()>>>;
 
 
+/// streams being served, with who opened them for which resource.
+/// closed streams are only noticed when pruning
+type OpenStreams = HashMap<(RoutingKey, u32), (identity::Identity, String)>;
+
 pub struct PublisherBuilder {
     config:     Config,
     routes:     HashMap<String, RouteHandler>,
@@ -37,41 +46,115 @@ pub fn new(config: Config) -> PublisherBuilder{
     }
 }
 
+fn resource(headers: &headers::Headers) -> String {
+    headers.path().as_ref().map(|v|String::from_utf8_lossy(v).to_string()).unwrap_or(String::from(""))
+}
+
 fn newstreamhandler(
     poll:       Poll,
     headers:    headers::Headers,
     mut stream: endpoint::Stream,
     identity:   &identity::Identity,
-    auth:       &certificate::Authenticator,
+    publish:    &config::PublisherConfig,
     routes:     &HashMap<String, RouteHandler>,
     with_axons: bool,
 ) -> Option<osaka::Task<()>> {
 
-    let resource = headers.path().as_ref().map(|v|String::from_utf8_lossy(v).to_string()).unwrap_or(String::from(""));
+    let resource = resource(&headers);
 
-    if let Err(e) = auth.check(identity, &resource, &Vec::new()) {
+    if let Err(e) = publish.auth.check(identity, &resource, &Vec::new()) {
         stream.send(headers::Headers::with_error(403, format!("{}",e)).encode());
         return None;
     }
 
+    if !publish.disabled.contains(&resource) {
+        if let Some(ref v) = routes.get(&resource) {
+            return (*v)(poll, headers, &identity, stream);
+        }
+        if let Some(path) = axon(&resource, with_axons) {
+            return Some(axon_exe(poll, headers, stream, path));
+        }
+    }
 
-    if let Some(ref v) = routes.get(&resource) {
-        return (*v)(poll, headers, &identity, stream);
+    stream.send(headers::Headers::with_error(404, "not found").encode());
+    None
+}
+
+/// the axon executable serving resource
+fn axon(resource: &str, with_axons: bool) -> Option<PathBuf> {
+    if !with_axons {
+        return None;
     }
+    let exe = resource.split("/v0/").nth(1)?;
+    if !exe.chars().all(|c|c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    which::which(format!("carrier-axon-v0-{}", exe)).ok()
+}
 
-    if with_axons {
-        if let Some(exe) = resource.split("/v0/").nth(1) {
-            if exe.chars().all(|c|c.is_ascii_alphanumeric()) {
-                let exe = format!("carrier-axon-v0-{}", exe);
-                if let Ok(path) = which::which(exe) {
-                    return Some(axon_exe(poll, headers, stream, path));
-                }
-            }
+/// swap in authorization and disabled routes from the config on disk.
+/// the secret and shadow are bound to the endpoint, changing those takes a restart
+fn reload(
+    ep:         &mut endpoint::Endpoint,
+    config:     &Config,
+    publish:    &RefCell<config::PublisherConfig>,
+    open:       &RefCell<OpenStreams>,
+) {
+    let new = match config.reload() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("not reloading: {}", e);
+            return;
+        }
+    };
+    let new_publish = match new.publish {
+        Some(v) => v,
+        None => {
+            error!("not reloading: the config has no publish section anymore");
+            return;
         }
+    };
+    let revoked = swap(new_publish, publish, open, |route, stream| ep.is_open(route, stream));
+    for (route, stream) in revoked {
+        ep.close(route, stream);
     }
+}
 
-    stream.send(headers::Headers::with_error(404, "not found").encode());
-    None
+/// returns the open streams that lost access and are to be closed
+fn swap<F: Fn(RoutingKey, u32) -> bool>(
+    new_publish: config::PublisherConfig,
+    publish:     &RefCell<config::PublisherConfig>,
+    open:        &RefCell<OpenStreams>,
+    is_open:     F,
+) -> Vec<(RoutingKey, u32)> {
+    if new_publish.shadow != publish.borrow().shadow {
+        error!("not reloading: the shadow changed, which needs a restart");
+        return Vec::new();
+    }
+    let close_revoked = new_publish.close_revoked;
+    *publish.borrow_mut() = new_publish;
+    info!("reloaded authorization from the config");
+
+    open.borrow_mut().retain(|&(route, stream), _| is_open(route, stream));
+    if !close_revoked {
+        return Vec::new();
+    }
+    let revoked: Vec<(RoutingKey, u32)> = {
+        let publish = publish.borrow();
+        open.borrow()
+            .iter()
+            .filter(|(_, (identity, resource))| {
+                publish.disabled.contains(resource) || publish.auth.check(identity, resource, &Vec::new()).is_err()
+            })
+            .map(|(k, _)| *k)
+            .collect()
+    };
+    for key in &revoked {
+        if let Some((identity, resource)) = open.borrow_mut().remove(key) {
+            warn!("closing {} of {}, which lost access", resource, identity);
+        }
+    }
+    revoked
 }
 
 impl PublisherBuilder {
@@ -94,32 +177,95 @@ impl PublisherBuilder {
     #[osaka]
     pub fn publish(self, poll: Poll) -> Result<(), Error> {
         let mut ep = endpoint::EndpointBuilder::new(&self.config)?.connect(poll.clone());
-        let mut ep = osaka::sync!(ep)?;
+        let mut ep = match osaka::sync!(ep) {
+            Ok(ep) => ep,
+            Err(e) => {
+                if !self.config.lan {
+                    return Err(e);
+                }
+                // still reachable for peers on the local network
+                warn!("cannot reach a broker: {}. publishing on the lan only", e);
+                endpoint::EndpointBuilder::new(&self.config)?.direct(poll.clone())?
+            }
+        };
+
+        // without any broker left we'd be unreachable, so better exit and get restarted
+        let had_broker = !ep.brokers().is_empty();
 
         let with_axons = self.with_axons;
         let routes  :&'static HashMap<String, RouteHandler> = Box::leak(Box::new(self.routes));
-        let publish_config  = self.config.publish.expect("missing publish section in config");
+        let mut config = self.config;
+        let publish_config  = config.publish.take().expect("missing publish section in config");
         ep.publish(publish_config.shadow.clone());
-        let publish_config : &'static config::PublisherConfig = Box::leak(Box::new(publish_config));
+        // swapped on SIGHUP, while stream handlers keep using it
+        let publish_config = Rc::new(RefCell::new(publish_config));
+        let open = Rc::new(RefCell::new(OpenStreams::new()));
+        let mut prune_at = 64;
+
+        // leave the shadow right away when stopped, instead of after the broker's idle timeout
+        let signals = signal::Signals::new()
+            .map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))?;
+        let signal_token = poll
+            .register(&signals, mio::Ready::readable(), mio::PollOpt::level())
+            .unwrap();
 
         loop {
-            match osaka::sync!(ep)? {
-                endpoint::Event::Disconnect{..} => (),
+            if let Some(signal) = signals.pending() {
+                info!("received {:?}", signal);
+                if signal == Signal::SIGHUP {
+                    reload(&mut ep, &config, &publish_config, &open);
+                    continue;
+                }
+                let mut shutdown = ep.shutdown(Duration::from_secs(endpoint::SHUTDOWN_TIMEOUT));
+                return osaka::sync!(shutdown);
+            }
+            let event = match ep.poll() {
+                osaka::FutureResult::Done(v) => v?,
+                osaka::FutureResult::Again(mut again) => {
+                    again.merge(poll.again(signal_token.clone(), None));
+                    yield again;
+                    continue;
+                }
+            };
+            // streams end without telling us, so forget them once in a while
+            if open.borrow().len() > prune_at {
+                open.borrow_mut().retain(|&(route, stream), _| ep.is_open(route, stream));
+                prune_at = (open.borrow().len() * 2).max(64);
+            }
+            match event {
+                endpoint::Event::Disconnect{route, ..} => {
+                    open.borrow_mut().retain(|&(r, _), _| r != route);
+                    if had_broker && ep.brokers().is_empty() {
+                        return Err(Error::NoBroker);
+                    }
+                }
                 endpoint::Event::OutgoingConnect(_) => (),
+                endpoint::Event::Datagram{..} => (),
+                endpoint::Event::PathChanged{..} => (),
                 endpoint::Event::IncommingConnect(q) => {
                     info!("incomming {}", q.identity);
                     let poll = poll.clone();
                     let identity = q.identity.clone();
-                    match publish_config.auth.reject_early(&q.identity, &Vec::new()) {
+                    let route = q.cr.route;
+                    let publish_config = publish_config.clone();
+                    let open = open.clone();
+                    let early = publish_config.borrow().auth.reject_early(&q.identity, &Vec::new());
+                    match early {
                         Ok(()) => ep.accept_incomming(q, move |h, s|{
-                            newstreamhandler(
+                            let key = (route, s.id());
+                            let resource = resource(&h);
+                            let task = newstreamhandler(
                                 poll.clone(),
                                 h, s,
                                 &identity,
-                                &publish_config.auth,
+                                &publish_config.borrow(),
                                 &routes,
                                 with_axons,
-                                )
+                                );
+                            if task.is_some() {
+                                open.borrow_mut().insert(key, (identity.clone(), resource));
+                            }
+                            task
                         }),
                         Err(e) => {
                             warn!("rejecting incomming {}: {}", q.identity, e);
@@ -192,3 +338,49 @@ pub fn axon_exe(poll: osaka::Poll, headers: headers::Headers, mut stream: endpoi
 
 }
 
+#[test]
+fn reload_closes_revoked() {
+    let (door, shadow) = (identity::Secret::gen().identity(), identity::Secret::gen().address());
+    let (ops, dev) = (identity::Secret::gen().identity(), identity::Secret::gen().identity());
+    let publish = |dev_resource: &str, disabled: &[&str], close_revoked| {
+        let mut auth = ::certificate::Authenticator::new(door.clone(), shadow.clone());
+        auth.allow(ops.clone(), vec!["/v0/shell".into(), "/v0/sft".into()]);
+        auth.allow(dev.clone(), vec![dev_resource.into()]);
+        config::PublisherConfig {
+            shadow: shadow.clone(),
+            auth,
+            disabled: disabled.iter().map(|s| s.to_string()).collect(),
+            close_revoked,
+        }
+    };
+    let current = RefCell::new(publish("/v0/shell", &[], true));
+    let open = RefCell::new(OpenStreams::new());
+    let fill = || {
+        let mut open = open.borrow_mut();
+        open.clear();
+        open.insert((1, 1), (ops.clone(), "/v0/shell".into()));
+        open.insert((1, 3), (ops.clone(), "/v0/sft".into()));
+        open.insert((2, 1), (dev.clone(), "/v0/shell".into()));
+        open.insert((3, 1), (dev.clone(), "/v0/shell".into()));
+    };
+
+    // dev lost the shell, and sft is disabled for everyone. route 3 already went away
+    fill();
+    let mut closed = swap(publish("/v0/sft", &["/v0/sft"], true), &current, &open, |route, _| route != 3);
+    closed.sort();
+    assert_eq!(closed, vec![(1, 3), (2, 1)]);
+    assert_eq!(open.borrow().keys().cloned().collect::<Vec<_>>(), vec![(1, 1)]);
+    assert!(current.borrow().disabled.contains("/v0/sft"));
+
+    // without close_revoked open streams keep going
+    fill();
+    let closed = swap(publish("/v0/sft", &["/v0/sft"], false), &current, &open, |_, _| true);
+    assert!(closed.is_empty());
+    assert_eq!(open.borrow().len(), 4);
+
+    // a different shadow is not swapped in
+    let mut other = publish("/v0/sft", &[], true);
+    other.shadow = identity::Secret::gen().address();
+    assert!(swap(other, &current, &open, |_, _| true).is_empty());
+    assert!(!current.borrow().close_revoked);
+}
@@ -0,0 +1,101 @@
+//! SIGTERM, SIGINT and SIGHUP as something the poll loop can wait for.
+//!
+//! the handler only writes the signal number into a pipe,
+//! the read end of which is registered with the poll.
+
+use libc;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{close, pipe, read};
+use osaka::mio;
+use osaka::mio::unix::EventedFd;
+use osaka::mio::Evented;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let fd = WRITE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let b = signal as u8;
+        unsafe {
+            libc::write(fd, &b as *const u8 as *const libc::c_void, 1);
+        }
+    }
+}
+
+pub struct Signals {
+    read:  RawFd,
+    write: RawFd,
+}
+
+impl Signals {
+    /// catch SIGTERM, SIGINT and SIGHUP from now on. there should only be one of these
+    pub fn new() -> Result<Self, nix::Error> {
+        let (read, write) = pipe()?;
+        for fd in &[read, write] {
+            fcntl(*fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+            fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        }
+        WRITE_FD.store(write, Ordering::SeqCst);
+
+        let action = SigAction::new(SigHandler::Handler(on_signal), SaFlags::SA_RESTART, SigSet::empty());
+        unsafe {
+            sigaction(Signal::SIGTERM, &action)?;
+            sigaction(Signal::SIGINT, &action)?;
+            sigaction(Signal::SIGHUP, &action)?;
+        }
+        Ok(Self { read, write })
+    }
+
+    /// a signal that arrived since the last call
+    pub fn pending(&self) -> Option<Signal> {
+        let mut b = [0u8; 1];
+        match read(self.read, &mut b) {
+            Ok(1) => Signal::from_c_int(b[0] as libc::c_int).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+        unsafe {
+            sigaction(Signal::SIGTERM, &action).ok();
+            sigaction(Signal::SIGINT, &action).ok();
+            sigaction(Signal::SIGHUP, &action).ok();
+        }
+        WRITE_FD.store(-1, Ordering::SeqCst);
+        close(self.read).ok();
+        close(self.write).ok();
+    }
+}
+
+impl Evented for Signals {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.read).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.read).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.read).deregister(poll)
+    }
+}
@@ -1,5 +1,6 @@
 //! an implementation of draft-ietf-quic-recovery-13
 
+use congestion::{self, CongestionController};
 use packet::Frame;
 use std::cmp::{max, min};
 use std::collections::HashMap;
@@ -27,20 +28,6 @@ const MIN_TLP_TIMEOUT: u64 = 10;
 /// Minimum time in the future an RTO alarm may be set for.
 const MIN_RTO_TIMEOUT: u64 = 200;
 
-// 4.8.1.  Congestion Control Settings
-
-/// The max packet size is used for calculating initial and minimum congestion windows.
-const INITIAL_MSS: u64 = 1460;
-
-/// Limit on the initial amount of outstanding data in bytes.
-const INITIAL_WINDOW: u64 = 10 * INITIAL_MSS;
-
-/// Minimum congestion window in bytes.
-const MINIMUM_WINDOW: u64 = 2 * INITIAL_MSS;
-
-/// Reduction in congestion window when a new loss event is detected.
-const LOSS_REDUCTION_FACTOR: f64 = 0.5;
-
 #[derive(Default)]
 pub struct Pkt {
     seq: u64,
@@ -80,10 +67,6 @@ pub struct QuicRecovery {
     /// The packet number of the most recently sent packet.
     largest_sent_packet: u64,
 
-    /// The packet number of the most recently sent packet.
-    /// NOT PART OF SPEC
-    largest_sent_retransmittable_packet: u64,
-
     /// The largest packet number acknowledged in an ACK frame.
     largest_acked_packet: u64,
 
@@ -127,21 +110,28 @@ pub struct QuicRecovery {
     /// congestion feedback.
     bytes_in_flight: usize,
 
-    /// Maximum number of bytes-in-flight that may be sent.
-    congestion_window: u64,
-
     /// The largest packet number sent when QUIC detects a loss.
     /// When a larger packet is acknowledged, QUIC exits recovery.
     end_of_recovery: u64,
 
-    /// Slow start threshold in bytes.  When the congestion window
-    /// is below ssthresh, the mode is slow start and the window grows by
-    /// the number of bytes acknowledged.
-    ssthresh: u64,
+    /// decides the size of the congestion window
+    cc: Box<CongestionController>,
+
+    // Statistics
+    /// number of packets declared lost by loss detection
+    packets_lost: u64,
+
+    /// number of packets whose frames were queued again,
+    /// either because they were lost or as part of a TLP or RTO probe
+    packets_retransmitted: u64,
 }
 
 impl QuicRecovery {
     pub fn new() -> Self {
+        Self::with_congestion(congestion::Algorithm::default())
+    }
+
+    pub fn with_congestion(algorithm: congestion::Algorithm) -> Self {
         Self {
             loss_detection_alarm: None,
             tlp_count: 0,
@@ -149,7 +139,6 @@ impl QuicRecovery {
             largest_sent_before_rto: 0,
             time_of_last_sent_retransmittable_packet: 0,
             largest_sent_packet: 0,
-            largest_sent_retransmittable_packet: 0,
             largest_acked_packet: 0,
             loss_time: 0,
             sent_packets: HashMap::default(),
@@ -159,36 +148,50 @@ impl QuicRecovery {
             min_rtt: <u64>::max_value(),
             max_ack_delay: 0,
             bytes_in_flight: 0,
-            congestion_window: INITIAL_WINDOW,
             end_of_recovery: 0,
-            ssthresh: <u64>::max_value(),
+            cc: algorithm.controller(),
+            packets_lost: 0,
+            packets_retransmitted: 0,
         }
     }
 
     /// current free space in sending window
     pub fn window(&self) -> usize {
-        if self.largest_acked_packet + 20 < self.largest_sent_retransmittable_packet {
-            // TODO: not part of the spec.
-            // But if we dont do this, loss_detection_alarm keeps getting reset on send even we get no ack
-            // https://github.com/quicwg/base-drafts/issues/1718
-            // this should be removed when we have a test suite that captures this case
-            // because the new idle timer should actually fix this properly
-            trace!("no send window because we're loosing too many packets");
-            return 0;
-        }
-
-        if self.bytes_in_flight > self.congestion_window as usize {
+        let congestion_window = self.cc.window();
+        if self.bytes_in_flight > congestion_window as usize {
             0
         } else {
-            self.congestion_window as usize - self.bytes_in_flight
+            congestion_window as usize - self.bytes_in_flight
         }
     }
 
+    /// current congestion window in bytes
+    pub fn congestion_window(&self) -> u64 {
+        self.cc.window()
+    }
+
     /// current free space in sending window
     pub fn bytes_in_flight(&self) -> usize {
         self.bytes_in_flight
     }
 
+    /// minimum rtt seen so far, 0 if there is no sample yet
+    pub fn min_rtt(&self) -> u64 {
+        if self.min_rtt == <u64>::max_value() {
+            0
+        } else {
+            self.min_rtt
+        }
+    }
+
+    pub fn packets_lost(&self) -> u64 {
+        self.packets_lost
+    }
+
+    pub fn packets_retransmitted(&self) -> u64 {
+        self.packets_retransmitted
+    }
+
     pub fn loss_detection_alarm(&self) -> Option<u64> {
         self.loss_detection_alarm
     }
@@ -212,15 +215,11 @@ impl QuicRecovery {
         };
 
         assert!(
-            self.largest_sent_packet < seq
+            self.largest_sent_packet < seq,
             "cannot send packet older than last one"
         );
         self.largest_sent_packet = seq;
 
-        if !ackonly {
-            self.largest_sent_retransmittable_packet = seq;
-        }
-
         let old = self.sent_packets.insert(seq, pkt);
         assert!(
             old.is_none(),
@@ -229,7 +228,7 @@ impl QuicRecovery {
 
         if !ackonly {
             self.time_of_last_sent_retransmittable_packet = now;
-            self.on_packet_sent_cc(bytes);
+            self.on_packet_sent_cc(bytes, now);
             self.set_loss_detection_alarm();
         }
     }
@@ -250,7 +249,7 @@ impl QuicRecovery {
 
         // Find all newly acked packets.
         for acked in acked {
-            self.on_packet_acked(acked);
+            self.on_packet_acked(acked, now);
         }
 
         let loss = self.detect_lost_packets(now);
@@ -302,11 +301,11 @@ impl QuicRecovery {
     }
 
     /// 3.5.6.  On Packet Acknowledgment
-    fn on_packet_acked(&mut self, acked_packet: u64) {
+    fn on_packet_acked(&mut self, acked_packet: u64, now: u64) {
         match self.sent_packets.remove(&acked_packet) {
             Some(v) => {
                 if !v.ackonly {
-                    self.on_packet_acked_cc(v)
+                    self.on_packet_acked_cc(v, now)
                 }
             }
             None => {
@@ -376,11 +375,15 @@ impl QuicRecovery {
             // Remove lost packets from bytes_in_flight.
             self.bytes_in_flight -= pkt.bytes;
             largest_lost_packet = max(largest_lost_packet, pkt.seq);
+            self.packets_lost += 1;
+            if pkt.frames.iter().any(|f| f.is_retransmittable()) {
+                self.packets_retransmitted += 1;
+            }
 
             lost_frames.append(&mut pkt.frames);
         }
         if lost_frames.len() > 0 {
-            self.congestion_event(largest_lost_packet);
+            self.congestion_event(largest_lost_packet, now);
             LossDetection::Lost(lost_frames)
         } else {
             LossDetection::None
@@ -442,6 +445,9 @@ impl QuicRecovery {
             }
             self.bytes_in_flight -= pkt.bytes;
             pkt.bytes = 0;
+            if pkt.frames.iter().any(|f| f.is_retransmittable()) {
+                self.packets_retransmitted += 1;
+            }
             r.append(&mut pkt.frames);
             n -= 1;
             if n < 1 {
@@ -483,8 +489,9 @@ impl QuicRecovery {
     }
 
     /// 4.8.4.  Congestion Control
-    fn on_packet_sent_cc(&mut self, bytes: usize) {
+    fn on_packet_sent_cc(&mut self, bytes: usize, now: u64) {
         self.bytes_in_flight += bytes;
+        self.cc.on_packet_sent(bytes, now);
     }
 
     /// 4.8.5. Congestion Control
@@ -493,39 +500,29 @@ impl QuicRecovery {
     }
 
     /// 4.8.5. Congestion Control
-    fn on_packet_acked_cc(&mut self, acked_packet: Pkt) {
+    fn on_packet_acked_cc(&mut self, acked_packet: Pkt, now: u64) {
         self.bytes_in_flight -= acked_packet.bytes;
 
-        if self.in_recovery(acked_packet.seq) {
-            // Do not increase congestion window in recovery period.
-            return;
-        }
-
-        if self.congestion_window < self.ssthresh {
-            // Slow start.
-            self.congestion_window += acked_packet.bytes as u64;
-        } else {
-            // Congestion avoidance.
-            self.congestion_window +=
-                INITIAL_MSS * acked_packet.bytes as u64 / self.congestion_window;
-        }
+        let in_recovery = self.in_recovery(acked_packet.seq);
+        let rtt = congestion::Rtt {
+            latest: self.latest_rtt,
+            smoothed: self.smoothed_rtt,
+            min: self.min_rtt(),
+        };
+        self.cc.on_packet_acked(acked_packet.bytes, in_recovery, rtt, now);
     }
 
     /// 4.8.6 On New Congestion Event
-    fn congestion_event(&mut self, packet_number: u64) {
+    fn congestion_event(&mut self, packet_number: u64, now: u64) {
         if !self.in_recovery(packet_number) {
             self.end_of_recovery = self.largest_sent_packet;
-            self.congestion_window = (self.congestion_window as f64 * LOSS_REDUCTION_FACTOR) as u64;
-            self.congestion_window = max(self.congestion_window, MINIMUM_WINDOW);
-            self.ssthresh = self.congestion_window
+            self.cc.on_congestion_event(now);
         }
     }
 
     // 4.8.9.  On Retransmission Timeout Verified
-    // QUIC decreases the congestion window to the minimum value once the
-    // retransmission timeout has been verified.
     fn on_retransmission_timeout_verified(&mut self) {
-        self.congestion_window = MINIMUM_WINDOW;
+        self.cc.on_retransmission_timeout_verified();
     }
 }
 
@@ -679,6 +676,9 @@ fn high_latency() {
     */
 }
 
+#[cfg(test)]
+use congestion::INITIAL_WINDOW;
+
 #[test]
 fn low_latency_high_loss() {
     let mut qr = QuicRecovery::new();
@@ -702,7 +702,7 @@ fn low_latency_high_loss() {
     assert_eq!(qr.largest_sent_packet, 10);
     assert_eq!(qr.latest_rtt, 0, "RTT cannot be calculated yet");
     assert_eq!(qr.smoothed_rtt, 0, "RTT cannot be calculated yet");
-    assert_eq!(qr.congestion_window, INITIAL_WINDOW);
+    assert_eq!(qr.congestion_window(), INITIAL_WINDOW);
     assert_eq!(
         qr.loss_detection_alarm,
         Some(20),
@@ -715,7 +715,7 @@ fn low_latency_high_loss() {
     // no loss
     let loss = qr.on_ack_received(1, vec![2], clock);
     assert_eq!(
-        qr.congestion_window,
+        qr.congestion_window(),
         INITIAL_WINDOW + 1015,
         "congestion windows should increase by ack'd frame"
     );
@@ -732,7 +732,7 @@ fn low_latency_high_loss() {
     // no loss
     let loss = qr.on_ack_received(1, vec![4], clock);
     assert_eq!(
-        qr.congestion_window,
+        qr.congestion_window(),
         INITIAL_WINDOW + 2030,
         "congestion windows should increase by ack'd frame"
     );
@@ -755,10 +755,12 @@ fn low_latency_high_loss() {
         panic!("expected lost frames");
     };
     assert_eq!(
-        qr.congestion_window,
+        qr.congestion_window(),
         (INITIAL_WINDOW + 3045) / 2,
         "congestion windows should be halfed on loss"
     );
+    assert_eq!(qr.packets_lost(), 1);
+    assert_eq!(qr.packets_retransmitted(), 1);
     //retransmit packet 1
     seq += 1;
     qr.on_packet_sent(seq, frames, clock);
@@ -769,7 +771,7 @@ fn low_latency_high_loss() {
     let loss = qr.on_ack_received(1, vec![5, 4, 3, 2], clock);
     assert_eq!(loss, LossDetection::None);
     assert_eq!(
-        qr.congestion_window, 8822,
+        qr.congestion_window(), 8822,
         "congestion windows should recover"
     );
 }
@@ -820,3 +822,157 @@ fn tlp_vs_ping() {
         assert_eq!(qr.loss_detection_alarm, Some(clock + 100 + 50));
     }
 }
+
+/// a single bottleneck link with fixed propagation delay and random loss.
+/// returns the number of payload bytes that arrived at the receiver in order to be consumed,
+/// counting each stream frame only once
+#[cfg(test)]
+fn simulate(algorithm: congestion::Algorithm, loss_per_mille: u64, rtt: u64, duration: u64) -> u64 {
+    use std::collections::{HashSet, VecDeque};
+
+    // xorshift, so the simulation is deterministic
+    let mut rng: u64 = 0x2545F4914F6CDD1D;
+    let mut random = move || {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        rng
+    };
+
+    // 1 packet every 2ms, roughly 4Mbit
+    let serialize = 2;
+    let max_queue = 50;
+
+    let mut qr = QuicRecovery::with_congestion(algorithm);
+    let mut seq = 0;
+    let mut order = 0;
+    let mut retransmit: VecDeque<Frame> = VecDeque::new();
+    let mut link_free = 0;
+    let mut on_link: VecDeque<(u64, u64, Vec<u64>)> = VecDeque::new();
+    let mut acks: VecDeque<(u64, u64)> = VecDeque::new();
+    let mut received = HashSet::new();
+
+    for now in 0..duration {
+        // packets arriving at receiver, acked immediately
+        while on_link.front().map(|v| v.0 <= now).unwrap_or(false) {
+            let (_, seq, orders) = on_link.pop_front().unwrap();
+            for order in orders {
+                received.insert(order);
+            }
+            acks.push_back((now + rtt / 2, seq));
+        }
+
+        let mut loss = LossDetection::None;
+        while acks.front().map(|v| v.0 <= now).unwrap_or(false) {
+            let (_, seq) = acks.pop_front().unwrap();
+            if let LossDetection::Lost(frames) = qr.on_ack_received(0, vec![seq], now) {
+                retransmit.extend(frames);
+            }
+        }
+
+        if let Some(alarm) = qr.loss_detection_alarm() {
+            if now >= alarm && qr.bytes_in_flight() > 0 {
+                loss = qr.on_loss_detection_alarm(now);
+            }
+        }
+        match loss {
+            LossDetection::None => (),
+            LossDetection::Lost(frames)
+            | LossDetection::TailLossProbe(frames)
+            | LossDetection::RetransmissionTimeout(frames) => retransmit.extend(frames),
+            LossDetection::Unrecoverable => break,
+        }
+
+        while qr.window() > 1100 {
+            let frame = match retransmit.pop_front() {
+                Some(frame) => frame,
+                None => {
+                    order += 1;
+                    Frame::Stream {
+                        stream: 1,
+                        order,
+                        payload: vec![0; 1000],
+                    }
+                }
+            };
+            seq += 1;
+            let orders = vec![frame.order()];
+            qr.on_packet_sent(seq, vec![frame], now);
+
+            // tail drop when the bottleneck queue is full
+            let start = max(link_free, now);
+            if (start - now) / serialize > max_queue {
+                continue;
+            }
+            link_free = start + serialize;
+            if random() % 1000 < loss_per_mille {
+                continue;
+            }
+            on_link.push_back((link_free + rtt / 2, seq, orders));
+        }
+    }
+
+    received.len() as u64 * 1000
+}
+
+#[test]
+fn simulate_lossless() {
+    for algorithm in &[
+        congestion::Algorithm::NewReno,
+        congestion::Algorithm::Cubic,
+        congestion::Algorithm::Bbr,
+    ] {
+        let delivered = simulate(*algorithm, 0, 100, 30_000);
+        // link capacity is 15MB in 30 seconds
+        assert!(
+            delivered > 10_000_000,
+            "{:?} only delivered {} bytes on a clean link",
+            algorithm,
+            delivered
+        );
+    }
+}
+
+#[test]
+fn simulate_lossy_cellular() {
+    let newreno = simulate(congestion::Algorithm::NewReno, 20, 300, 60_000);
+    let cubic = simulate(congestion::Algorithm::Cubic, 20, 300, 60_000);
+    let bbr = simulate(congestion::Algorithm::Bbr, 20, 300, 60_000);
+
+    assert!(newreno > 0);
+    assert!(
+        cubic > newreno,
+        "cubic ({}) should outperform newreno ({}) on a lossy link",
+        cubic,
+        newreno
+    );
+    assert!(
+        bbr > cubic,
+        "bbr ({}) should outperform cubic ({}) on a lossy link",
+        bbr,
+        cubic
+    );
+}
+
+#[test]
+fn lost_datagrams_are_not_retransmitted() {
+    let mut qr = QuicRecovery::new();
+    qr.on_packet_sent(1, vec![Frame::Datagram { payload: vec![0; 100] }], 1);
+    for seq in 2..6 {
+        let frame = Frame::Stream {
+            order: seq,
+            payload: vec![0; 100],
+            stream: 1,
+        };
+        qr.on_packet_sent(seq, vec![frame], 1);
+    }
+    qr.on_packet_sent(6, vec![Frame::Ping], 1);
+
+    // packet 1 and 2 are more than REORDERING_THRESHOLD behind the ack
+    match qr.on_ack_received(0, vec![6], 2) {
+        LossDetection::Lost(frames) => assert_eq!(frames.len(), 2),
+        loss => panic!("expected lost frames, got {:?}", loss),
+    }
+    assert_eq!(qr.packets_lost(), 2);
+    assert_eq!(qr.packets_retransmitted(), 1);
+}
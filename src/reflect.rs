@@ -0,0 +1,167 @@
+//! reflexive address discovery, similar to a STUN binding request.
+//!
+//! a reflector answers with the source address it saw the request coming from,
+//! which is our address on the outside of any nat between us and the reflector.
+//! requests are sent from the endpoint socket itself, so the nat mapping is the one peers will see.
+//! the reflexive address is advertised to peers as an Internet path.
+//! since both sides of a connect start probing their candidates as soon as the broker
+//! relayed the handshake, those probes double as hole punching packets.
+//!
+//! messages are not encrypted. they start with a magic that can't be confused with an
+//! EncryptedPacket, and a response is never bigger than the request,
+//! so a reflector can't be used for amplification.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use error::Error;
+use osaka::mio;
+use osaka::osaka;
+use socket::Socket;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const MAGIC: [u8; 4] = [b'R', b'F', b'X', 0x01];
+
+const REQUEST: u8 = 0x01;
+const RESPONSE: u8 = 0x02;
+
+/// requests are padded to this size, which fits the largest possible response
+const REQUEST_SIZE: usize = 32;
+
+/// default port of a reflector
+pub const DEFAULT_PORT: u16 = 8444;
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Request { txid: u64 },
+    Response { txid: u64, addr: SocketAddr },
+}
+
+/// true if this datagram is a reflector message rather than a carrier packet
+pub fn is_reflect(buf: &[u8]) -> bool {
+    buf.len() >= MAGIC.len() && buf[..MAGIC.len()] == MAGIC
+}
+
+pub fn request(txid: u64) -> Vec<u8> {
+    let mut w = Vec::with_capacity(REQUEST_SIZE);
+    w.extend_from_slice(&MAGIC);
+    w.push(REQUEST);
+    w.write_u64::<BigEndian>(txid).unwrap();
+    w.resize(REQUEST_SIZE, 0);
+    w
+}
+
+pub fn response(txid: u64, addr: &SocketAddr) -> Vec<u8> {
+    let mut w = Vec::with_capacity(REQUEST_SIZE);
+    w.extend_from_slice(&MAGIC);
+    w.push(RESPONSE);
+    w.write_u64::<BigEndian>(txid).unwrap();
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            w.push(4);
+            w.write_u16::<BigEndian>(addr.port()).unwrap();
+            w.write_all(&ip.octets()).unwrap();
+        }
+        IpAddr::V6(ip) => {
+            w.push(6);
+            w.write_u16::<BigEndian>(addr.port()).unwrap();
+            w.write_all(&ip.octets()).unwrap();
+        }
+    }
+    assert!(w.len() <= REQUEST_SIZE);
+    w
+}
+
+pub fn decode(buf: &[u8]) -> Option<Message> {
+    if !is_reflect(buf) {
+        return None;
+    }
+    let mut r = &buf[MAGIC.len()..];
+    match r.read_u8().ok()? {
+        REQUEST => {
+            if buf.len() < REQUEST_SIZE {
+                return None;
+            }
+            let txid = r.read_u64::<BigEndian>().ok()?;
+            Some(Message::Request { txid })
+        }
+        RESPONSE => {
+            let txid = r.read_u64::<BigEndian>().ok()?;
+            let family = r.read_u8().ok()?;
+            let port = r.read_u16::<BigEndian>().ok()?;
+            let ip = match family {
+                4 => {
+                    let mut b = [0; 4];
+                    r.read_exact(&mut b).ok()?;
+                    IpAddr::V4(Ipv4Addr::from(b))
+                }
+                6 => {
+                    let mut b = [0; 16];
+                    r.read_exact(&mut b).ok()?;
+                    IpAddr::V6(Ipv6Addr::from(b))
+                }
+                _ => return None,
+            };
+            Some(Message::Response {
+                txid,
+                addr: SocketAddr::new(ip, port),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// a standalone reflector, for networks that can't rely on the broker
+#[osaka]
+pub fn serve(poll: osaka::Poll, socket: Socket) -> Result<(), Error> {
+    let token = poll
+        .register(&socket, mio::Ready::readable(), mio::PollOpt::level())
+        .unwrap();
+    info!("reflector listening on {}", socket.local_addr()?);
+
+    loop {
+        let mut buf = [0; 64];
+        match socket.recv_from(&mut buf) {
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::WouldBlock {
+                    return Err(Error::Io(e));
+                }
+                yield poll.again(token.clone(), None);
+            }
+            Ok((len, addr)) => match decode(&buf[..len]) {
+                Some(Message::Request { txid }) => {
+                    trace!("reflecting {}", addr);
+                    if let Err(e) = socket.send_to(&response(txid, &addr), &addr) {
+                        debug!("reflect to {}: {}", addr, e);
+                    }
+                }
+                _ => trace!("ignoring {} bytes from {}", len, addr),
+            },
+        }
+    }
+}
+
+#[test]
+fn roundtrip() {
+    let req = request(0x1122334455667788);
+    assert_eq!(req.len(), REQUEST_SIZE);
+    assert!(is_reflect(&req));
+    assert_eq!(decode(&req), Some(Message::Request { txid: 0x1122334455667788 }));
+
+    // truncated requests are not answered, so the response can't be bigger
+    assert_eq!(decode(&req[..13]), None);
+
+    for addr in &["203.0.113.7:41641", "[2001:db8::1]:8443"] {
+        let addr: SocketAddr = addr.parse().unwrap();
+        let res = response(7, &addr);
+        assert!(res.len() <= req.len());
+        assert_eq!(decode(&res), Some(Message::Response { txid: 7, addr }));
+    }
+}
+
+#[test]
+fn not_a_carrier_packet() {
+    // EncryptedPacket starts with its version
+    assert!(!is_reflect(&[0x08, 0xff, 0xff, 0xff, 0, 0, 0, 0]));
+    assert!(!is_reflect(b"RF"));
+    assert_eq!(decode(&[b'R', b'F', b'X', 0x01, 0x09]), None);
+}
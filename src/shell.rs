@@ -9,6 +9,9 @@ use std::io::{Read, Write};
 use osaka::mio;
 use osaka::Future;
 use nix::fcntl;
+use std::cell::Cell;
+use std::rc::Rc;
+use super::{until_closed, until_done};
 
 
 static mut ORIGINAL_TERMINAL_MODE:  Option<libc::termios> = None;
@@ -89,10 +92,6 @@ fn message_handler(poll: osaka::Poll, mut stream: carrier::endpoint::Stream) {
     into_raw_mode().expect("into raw mode");
     unsafe { libc::atexit(atexit); }
 
-    let _d = carrier::util::defer(||{
-        std::process::exit(0);
-    });
-
     let headers = carrier::headers::Headers::decode(&osaka::sync!(stream)).unwrap();
     println!("{:?}", headers);
 
@@ -127,12 +126,22 @@ fn message_handler(poll: osaka::Poll, mut stream: carrier::endpoint::Stream) {
 pub fn ui(poll: osaka::Poll,
        config: carrier::config::Config,
        target: carrier::identity::Identity,
+       lan:    bool,
        )
     -> Result<(), Error>
 {
-    let mut ep = carrier::endpoint::EndpointBuilder::new(&config)?.connect(poll.clone());
-    let mut ep = osaka::sync!(ep)?;
-    ep.connect(target)?;
+    let mut ep = if lan {
+        let mut addr = carrier::lan::discover(poll.clone(), target.clone(), std::time::Duration::from_secs(10));
+        let addr = osaka::sync!(addr)?;
+        let mut ep = carrier::endpoint::EndpointBuilder::new(&config)?.direct(poll.clone())?;
+        ep.connect_direct(addr, target)?;
+        ep
+    } else {
+        let mut ep = carrier::endpoint::EndpointBuilder::new(&config)?.connect(poll.clone());
+        let mut ep = osaka::sync!(ep)?;
+        ep.connect(target)?;
+        ep
+    };
 
     let q = loop {
         match osaka::sync!(ep)? {
@@ -145,20 +154,14 @@ pub fn ui(poll: osaka::Poll,
 
     let headers = carrier::headers::Headers::with_path("/v0/shell");
     let route  = ep.accept_outgoing(q, move |_h, _s|{None}).unwrap();
+    let closed = Rc::new(Cell::new(false));
+    let closed2 = closed.clone();
     ep.open(
         route,
         headers,
-        message_handler,
+        move |poll, stream| until_closed(poll, stream, message_handler, closed2),
         );
 
-    loop {
-        match osaka::sync!(ep)? {
-            carrier::endpoint::Event::OutgoingConnect(_) => (),
-            carrier::endpoint::Event::Disconnect{identity, ..} => {
-                warn!("{} disconnected", identity);
-                return Ok(());
-            }
-            carrier::endpoint::Event::IncommingConnect(_) => (),
-        };
-    }
+    let mut r = until_done(ep, closed);
+    osaka::sync!(r)
 }
@@ -0,0 +1,236 @@
+//! udp socket that reaches both ipv4 and ipv6 peers.
+//!
+//! where the os allows it, this is a single dual stack ipv6 socket.
+//! ipv4 peers then show up as v4-mapped ipv6 addresses, which are translated here,
+//! so the rest of carrier only ever sees plain ipv4 and ipv6 addresses.
+//! hosts without ipv6 get a plain ipv4 socket.
+
+use osaka::mio;
+use osaka::mio::net::UdpSocket;
+use osaka::mio::Evented;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+pub struct Socket {
+    inner: UdpSocket,
+    v6: bool,
+}
+
+impl Socket {
+    /// bind to port on all addresses of both families, or just ipv4 if ipv6 is not available
+    pub fn bind_any(port: u16) -> io::Result<Self> {
+        match dual_stack(port) {
+            Ok(sock) => Ok(Self {
+                inner: UdpSocket::from_socket(sock)?,
+                v6: true,
+            }),
+            Err(e) => {
+                debug!("no dual stack socket ({}), falling back to ipv4 only", e);
+                let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+                Ok(Self {
+                    inner: UdpSocket::bind(&addr)?,
+                    v6: false,
+                })
+            }
+        }
+    }
+
+    /// bind to exactly this address
+    pub fn bind(addr: &SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            inner: UdpSocket::bind(addr)?,
+            v6: addr.is_ipv6(),
+        })
+    }
+
+    /// receive datagrams sent to an ipv4 multicast group on port.
+    /// several listeners on the same host can share the port
+    pub fn multicast(group: Ipv4Addr, port: u16) -> io::Result<Self> {
+        let sock = reusable_v4(port)?;
+        sock.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        Ok(Self {
+            inner: UdpSocket::from_socket(sock)?,
+            v6: false,
+        })
+    }
+
+    /// whether packets to addr can be sent from this socket
+    pub fn supports(&self, addr: &SocketAddr) -> bool {
+        match addr {
+            SocketAddr::V4(_) => true,
+            SocketAddr::V6(_) => self.v6,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        match (self.v6, addr) {
+            (true, SocketAddr::V4(v4)) => {
+                let mapped = SocketAddr::V6(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0));
+                self.inner.send_to(buf, &mapped)
+            }
+            _ => self.inner.send_to(buf, addr),
+        }
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, addr) = self.inner.recv_from(buf)?;
+        Ok((len, unmap(addr)))
+    }
+}
+
+impl Evented for Socket {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        self.inner.deregister(poll)
+    }
+}
+
+/// turn a v4-mapped ipv6 address (::ffff:a.b.c.d) back into ipv4
+pub fn unmap(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(v6) = addr {
+        if let Some(ip) = mapped_v4(v6.ip()) {
+            return SocketAddr::new(IpAddr::V4(ip), v6.port());
+        }
+    }
+    addr
+}
+
+fn mapped_v4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, hi, lo] => Some(Ipv4Addr::new(
+            (hi >> 8) as u8,
+            hi as u8,
+            (lo >> 8) as u8,
+            lo as u8,
+        )),
+        _ => None,
+    }
+}
+
+/// an ipv6 socket that also accepts ipv4, on [::]:port
+#[cfg(unix)]
+fn dual_stack(port: u16) -> io::Result<::std::net::UdpSocket> {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    unsafe {
+        let fd = libc::socket(libc::AF_INET6, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // owns fd from here, so it's closed on every error path
+        let sock = ::std::net::UdpSocket::from_raw_fd(fd);
+
+        let off: libc::c_int = 0;
+        if libc::setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &off as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut addr: libc::sockaddr_in6 = mem::zeroed();
+        addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        addr.sin6_port = port.to_be();
+        if libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sock)
+    }
+}
+
+/// an ipv4 socket on 0.0.0.0:port with SO_REUSEADDR
+#[cfg(unix)]
+fn reusable_v4(port: u16) -> io::Result<::std::net::UdpSocket> {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = ::std::net::UdpSocket::from_raw_fd(fd);
+
+        let on: libc::c_int = 1;
+        if libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut addr: libc::sockaddr_in = mem::zeroed();
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = port.to_be();
+        if libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sock)
+    }
+}
+
+#[cfg(not(unix))]
+fn reusable_v4(port: u16) -> io::Result<::std::net::UdpSocket> {
+    ::std::net::UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))
+}
+
+#[cfg(not(unix))]
+fn dual_stack(_port: u16) -> io::Result<::std::net::UdpSocket> {
+    Err(io::Error::new(io::ErrorKind::Other, "not supported on this platform"))
+}
+
+#[test]
+fn unmap_v4_mapped() {
+    let mapped: SocketAddr = "[::ffff:192.0.2.1]:8443".parse().unwrap();
+    assert_eq!(unmap(mapped), "192.0.2.1:8443".parse().unwrap());
+
+    let v6: SocketAddr = "[2001:db8::1]:8443".parse().unwrap();
+    assert_eq!(unmap(v6), v6);
+
+    let v4: SocketAddr = "192.0.2.1:8443".parse().unwrap();
+    assert_eq!(unmap(v4), v4);
+}
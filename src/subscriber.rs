@@ -44,15 +44,21 @@ impl SubscriberBuilder {
 
             match v.m {
                 Some(proto::subscribe_change::M::Publish(proto::Publish{identity, xaddr})) => {
+                    let v = match identity::Identity::from_bytes(&identity) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!("SubscribeChange::Publish: {}", e);
+                            continue;
+                        }
+                    };
+                    // a retired identity publishing means its old secret is still in use somewhere
+                    if this.borrow().config.successions.is_retired(&v) {
+                        warn!("ignoring {}, which was succeeded by {}",
+                              v, this.borrow().config.successions.current(&v));
+                        continue;
+                    }
                     if let Some(h) = &mut this.borrow_mut().on_publish {
-                        match identity::Identity::from_bytes(&identity) {
-                            Ok(v) => {
-                                h(v);
-                            }
-                            Err(e) => {
-                                warn!("SubscribeChange::Publish: {}", e);
-                            }
-                        };
+                        h(v);
                     }
                 },
                 Some(proto::subscribe_change::M::Unpublish(proto::Unpublish{identity})) => {
@@ -115,6 +121,8 @@ impl SubscriberBuilder {
             match osaka::sync!(ep)? {
                 endpoint::Event::Disconnect{..} => (),
                 endpoint::Event::OutgoingConnect(_) => (),
+                endpoint::Event::Datagram{..} => (),
+                endpoint::Event::PathChanged{..} => (),
                 endpoint::Event::IncommingConnect(q) => {
                     info!("ignoring incomming connect {}", q.identity);
                 }
@@ -0,0 +1,284 @@
+//! the newest handshake timestamp seen from each identity.
+//!
+//! a handshake is only accepted when its timestamp is newer than the last one from the same
+//! identity, and close enough to our own clock. the table is bounded: when it is full, the
+//! identity with the oldest handshake is forgotten, and its timestamp becomes a floor that
+//! handshakes from identities we don't remember have to be newer than.
+//!
+//! on disk, accepted handshakes are appended to a log. when the log is twice as long as the
+//! table, it is compacted back into one line per identity.
+
+use dirs;
+use error::Error;
+use identity::Identity;
+use rand;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, rename, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// identities to remember
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// how far off our clock a handshake may be, in seconds
+pub const DEFAULT_SKEW: u64 = 300;
+
+pub struct Timestamps {
+    seen:     HashMap<Identity, u64>,
+    floor:    u64,
+    capacity: usize,
+    /// in ms. 0 only checks that handshakes are newer than the last one
+    skew:     u64,
+    path:     Option<PathBuf>,
+    /// open for appending, reopened after compaction
+    log:      Option<File>,
+    /// lines in the log
+    lines:    usize,
+}
+
+fn path() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or(PathBuf::from("/"))
+        .join(".devguard/handshakes")
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + d.subsec_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+impl Timestamps {
+    /// a table that only lives in memory. skew is in seconds
+    pub fn new(capacity: usize, skew: u64) -> Self {
+        Self {
+            seen: HashMap::new(),
+            floor: 0,
+            capacity: capacity.max(1),
+            skew: skew * 1000,
+            path: None,
+            log: None,
+            lines: 0,
+        }
+    }
+
+    /// the table in ~/.devguard/handshakes, which every accepted handshake is appended to
+    pub fn load(skew: u64) -> Self {
+        Self::open(path(), DEFAULT_CAPACITY, skew)
+    }
+
+    fn open(path: PathBuf, capacity: usize, skew: u64) -> Self {
+        let mut t = Self::new(capacity, skew);
+        let mut b = String::new();
+        if File::open(&path).and_then(|mut f| f.read_to_string(&mut b)).is_ok() {
+            for line in b.lines() {
+                t.lines += 1;
+                let mut parts = line.split_whitespace();
+                match (parts.next(), parts.next().and_then(|v| v.parse::<u64>().ok())) {
+                    (Some("floor"), Some(ts)) => t.floor = t.floor.max(ts),
+                    (Some(identity), Some(ts)) => match identity.parse::<Identity>() {
+                        Ok(identity) => {
+                            if t.seen.get(&identity).map_or(true, |last| *last < ts) {
+                                t.insert(identity, ts);
+                            }
+                        }
+                        Err(e) => warn!("{:?}: {}", path, e),
+                    },
+                    _ => (),
+                }
+            }
+        }
+        t.path = Some(path);
+        t
+    }
+
+    /// in seconds
+    pub fn set_skew(&mut self, skew: u64) {
+        self.skew = skew * 1000;
+    }
+
+    /// accept a handshake from identity, unless it is a replay or too far off our clock
+    pub fn check(&mut self, identity: &Identity, timestamp: u64) -> Result<(), Error> {
+        self.check_at(identity, timestamp, now())
+    }
+
+    fn check_at(&mut self, identity: &Identity, timestamp: u64, now: u64) -> Result<(), Error> {
+        if self.skew > 0
+            && (timestamp.saturating_add(self.skew) < now || timestamp > now.saturating_add(self.skew))
+        {
+            let skew = if timestamp >= now {
+                (timestamp - now).min(i64::max_value() as u64) as i64
+            } else {
+                -((now - timestamp).min(i64::max_value() as u64) as i64)
+            };
+            return Err(Error::ClockSkew {
+                identity: identity.clone(),
+                skew,
+            });
+        }
+
+        let last = self.seen.get(identity).cloned().unwrap_or(self.floor);
+        if timestamp <= last {
+            return Err(Error::HandshakeReplay {
+                identity: identity.clone(),
+                timestamp,
+                last,
+            });
+        }
+
+        let floor = self.floor;
+        self.insert(identity.clone(), timestamp);
+
+        let r = if self.path.is_none() {
+            Ok(())
+        } else if self.lines >= self.capacity * 2 {
+            self.store()
+        } else if self.floor != floor {
+            self.append(&format!("{} {}\nfloor {}\n", identity, timestamp, self.floor))
+        } else {
+            self.append(&format!("{} {}\n", identity, timestamp))
+        };
+        if let Err(e) = r {
+            error!("cannot write handshake timestamps to {:?}: {}", self.path.as_ref().unwrap(), e);
+        }
+        Ok(())
+    }
+
+    /// remember timestamp, forgetting the oldest identity if the table is full
+    fn insert(&mut self, identity: Identity, timestamp: u64) {
+        self.seen.insert(identity, timestamp);
+        if self.seen.len() > self.capacity {
+            let (oldest, ts) = self
+                .seen
+                .iter()
+                .min_by_key(|(_, ts)| **ts)
+                .map(|(k, v)| (k.clone(), *v))
+                .unwrap();
+            self.seen.remove(&oldest);
+            self.floor = self.floor.max(ts);
+        }
+    }
+
+    fn append(&mut self, lines: &str) -> Result<(), String> {
+        if self.log.is_none() {
+            let path = self.path.as_ref().unwrap();
+            create_dir_all(path.parent().unwrap()).map_err(|e| e.to_string())?;
+            let f = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| e.to_string())?;
+            self.log = Some(f);
+        }
+        let f = self.log.as_mut().unwrap();
+        f.write_all(lines.as_bytes()).map_err(|e| e.to_string())?;
+        self.lines += lines.lines().count();
+        Ok(())
+    }
+
+    /// rewrite the log as the current table
+    fn store(&mut self) -> Result<(), String> {
+        let path = self.path.clone().unwrap();
+        let dir = path.parent().unwrap();
+        create_dir_all(&dir).map_err(|e| e.to_string())?;
+
+        let r: u64 = rand::random();
+        let path2 = dir.join(format!("handshakes{}", r));
+        let mut f = File::create(&path2).map_err(|e| e.to_string())?;
+        writeln!(f, "floor {}", self.floor).map_err(|e| e.to_string())?;
+        for (identity, ts) in &self.seen {
+            writeln!(f, "{} {}", identity, ts).map_err(|e| e.to_string())?;
+        }
+        f.sync_all().map_err(|e| e.to_string())?;
+        rename(&path2, &path).map_err(|e| e.to_string())?;
+        self.log = None;
+        self.lines = self.seen.len() + 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn replays_are_rejected() {
+    use identity::Secret;
+
+    let now = 1_000_000_000;
+    let a = Secret::gen().identity();
+    let b = Secret::gen().identity();
+    let mut t = Timestamps::new(2, 60);
+
+    assert!(t.check_at(&a, now, now).is_ok());
+    assert!(t.check_at(&a, now, now + 10).is_err());
+    assert!(t.check_at(&a, now - 1, now + 10).is_err());
+    assert!(t.check_at(&a, now + 1, now + 10).is_ok());
+
+    // every identity has its own clock
+    assert!(t.check_at(&b, now - 5000, now).is_ok());
+
+    // a third identity pushes out b, the oldest, whose timestamp becomes the floor
+    let c = Secret::gen().identity();
+    assert!(t.check_at(&c, now + 2, now).is_ok());
+    assert!(t.check_at(&b, now - 6000, now).is_err());
+    assert!(t.check_at(&b, now - 4000, now).is_ok());
+    assert!(t.check_at(&a, now + 1, now).is_err());
+}
+
+#[test]
+fn clock_skew() {
+    use identity::Secret;
+
+    let now = 1_000_000_000;
+    let a = Secret::gen().identity();
+    let mut t = Timestamps::new(16, 60);
+
+    // last week
+    match t.check_at(&a, now - 7 * 24 * 3600 * 1000, now) {
+        Err(Error::ClockSkew { skew, .. }) => assert!(skew < 0),
+        _ => panic!("expected ClockSkew"),
+    }
+    assert!(t.check_at(&a, now + 61_000, now).is_err());
+    assert!(t.check_at(&a, now - 59_000, now).is_ok());
+
+    // far in the future doesn't overflow
+    match t.check_at(&a, u64::max_value(), now) {
+        Err(Error::ClockSkew { skew, .. }) => assert!(skew > 0),
+        _ => panic!("expected ClockSkew"),
+    }
+    assert!(t.check_at(&a, now, u64::max_value()).is_err());
+
+    // without a skew only the order counts
+    let mut t = Timestamps::new(16, 0);
+    assert!(t.check_at(&a, 1, now).is_ok());
+    assert!(t.check_at(&a, 1, now).is_err());
+}
+
+#[test]
+fn log() {
+    use identity::Secret;
+
+    let path = ::std::env::temp_dir().join(format!("carrier-handshakes-{}", rand::random::<u64>()));
+    let ids: Vec<Identity> = (0..3).map(|_| Secret::gen().identity()).collect();
+
+    let mut t = Timestamps::open(path.clone(), 2, 0);
+    for n in 1..4 {
+        for id in &ids {
+            t.check(id, n).unwrap();
+        }
+    }
+
+    // compacted along the way, never much longer than twice the table
+    let lines = |p: &PathBuf| {
+        let mut b = String::new();
+        File::open(p).unwrap().read_to_string(&mut b).unwrap();
+        b.lines().count()
+    };
+    assert!(lines(&path) < 2 * 2 + 3);
+
+    let mut t = Timestamps::open(path.clone(), 2, 0);
+    assert_eq!(t.seen.len(), 2);
+    assert!(t.check(&ids[2], 3).is_err());
+    assert!(t.check(&ids[0], 3).is_err());
+    assert!(t.check(&ids[0], 4).is_ok());
+    ::std::fs::remove_file(&path).unwrap();
+}